@@ -1,313 +1,4979 @@
-// Reverse-mode automatic differentiation.
+// Reverse-mode automatic differentiation, plus forward mode
+// for directional derivatives and Hessian-vector products.
 //
 // Components of the system:
-//  - Tensor: a shaped vector of floats.
+//  - Tensor: a shaped vector of floats (f32 by default, or
+//    any other Float such as f64). Elementwise ops broadcast
+//    their operands like numpy does.
+//  - TensorView: a strided view of a Tensor, which can be
+//    transposed and broadcast without copying.
 //  - Gradient: a mapping of variable names to grads.
-//  - Res: an abstract differentiable value.
-//  - Variable: a Res that returns its upstream gradient.
-//  - Fork: a Res that allows you to use a Box<Res> more
-//    than once, since operations on Box<Res> normally
-//    consume the operands. The Fork also accumulates
-//    upstream gradients to avoid double-backprop.
+//  - Res: an abstract differentiable value, which knows
+//    its inputs and how to back-propagate into them.
+//  - Node: a reference-counted handle to a Res. Nodes can
+//    be used any number of times, and the operators are
+//    overloaded on Node and &Node.
+//  - ShapeError: returned by the checked ops (try_add(),
+//    try_matmul(), etc.) when their operands have the wrong
+//    shapes. The operators panic with the same message.
+//  - Tape: the nodes of a graph in topological order. It
+//    runs the backward pass, summing the gradients from
+//    every use of a node before visiting it. Gradients are
+//    summed in place in GradBuffers, which can be kept
+//    between passes to avoid reallocating them. It can also
+//    push tangents forward (jvp), and through the backward
+//    pass itself (hvp, i.e. forward-over-reverse). It can
+//    also build the backward pass as a graph of Nodes, so
+//    that gradients can be differentiated again. Tapes can
+//    be exported to DOT or JSON, and loaded from JSON.
+//  - no_grad(): an inference mode that builds no graph, so
+//    intermediate results are freed right away.
+//  - Variable: a Res whose gradient ends up in Gradient.
 //  - Constant: a hacky Res with a constant value.
+//  - MatMulRes: a matrix product, created with matmul(),
+//    and TransposeRes, created with transpose().
+//  - Unary Res types (ExpRes, TanhRes, PowRes, etc.) which
+//    are created with methods like exp() and tanh().
+//  - Reductions (SumRes, MeanRes, MaxRes) which turn a
+//    Res into a smaller one, e.g. a scalar loss.
+//  - ConvRes, AvgPoolRes, MaxPoolRes: 1D and 2D convolution
+//    and pooling over sliding Windows, created with conv1d(),
+//    conv2d(), avg_pool() and max_pool().
+//  - SumToRes, VjpRes, JvpRes, ConvGradRes: the extra Res
+//    types that backward graphs are made of.
+//  - Params: named tensors that persist across steps. They
+//    can be saved as JSON or in a binary format, and loaded
+//    back to resume training.
+//  - Optimizer: updates Params from a Gradient (SGD,
+//    Momentum, and Adam are implemented).
+//  - Module: a layer whose parameters are registered in
+//    Params (Linear, and MLP which stacks Linear layers).
+//    Models are trained with mse_loss() for regression or
+//    cross_entropy_loss() for classification, which uses a
+//    SoftmaxCrossEntropyRes.
 //
-// In general, all nodes in the graph are supposed to have
-// a unique identifier (otherwise, Fork wouldn't be able
-// to uniquely identify its quasi-variable).
-// In other languages, this wouldn't be necessary, since a
-// reference to a Variable (e.g. a pointer) could uniquely
-// identify it. It might be possible to do this by using
-// generics instead of trait objects, although that would
-// result in a TON of types being created.
-
-use std::collections::HashMap;
-use std::ops::{Add, Mul, Div, Sub};
+// Nodes are identified by the address of their Res, so
+// names only matter for Variables. If two Variables share
+// a name, their gradients are summed.
+
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Debug, Display};
+use std::ops::{Add, AddAssign, Mul, MulAssign, Div, Neg, Sub, SubAssign};
+use std::rc::Rc;
+use std::time::Instant;
+
+// The element type of a Tensor.
+trait Float: Copy + PartialOrd + Debug + Display + 'static +
+        Add<Output=Self> + Sub<Output=Self> + Mul<Output=Self> + Div<Output=Self> +
+        Neg<Output=Self> + AddAssign + SubAssign + MulAssign {
+    fn zero() -> Self;
+    fn one() -> Self;
+    fn from_f64(x: f64) -> Self;
+    fn to_f64(self) -> f64;
+    fn exp(self) -> Self;
+    fn ln(self) -> Self;
+    fn tanh(self) -> Self;
+    fn sqrt(self) -> Self;
+    fn abs(self) -> Self;
+    fn powf(self, power: Self) -> Self;
+    fn powi(self, power: i32) -> Self;
+    fn max(self, other: Self) -> Self;
+}
+
+macro_rules! impl_float {
+    ($type:ty) => {
+        impl Float for $type {
+            fn zero() -> $type { 0 as $type }
+            fn one() -> $type { 1 as $type }
+            fn from_f64(x: f64) -> $type { x as $type }
+            fn to_f64(self) -> f64 { self as f64 }
+            fn exp(self) -> $type { <$type>::exp(self) }
+            fn ln(self) -> $type { <$type>::ln(self) }
+            fn tanh(self) -> $type { <$type>::tanh(self) }
+            fn sqrt(self) -> $type { <$type>::sqrt(self) }
+            fn abs(self) -> $type { <$type>::abs(self) }
+            fn powf(self, power: $type) -> $type { <$type>::powf(self, power) }
+            fn powi(self, power: i32) -> $type { <$type>::powi(self, power) }
+            fn max(self, other: $type) -> $type { <$type>::max(self, other) }
+        }
+    }
+}
+
+impl_float!(f32);
+impl_float!(f64);
 
 // An N-dimensional array of floating-point values.
 #[derive(Clone)]
-struct Tensor {
-    data: Vec<f32>,
+struct Tensor<T: Float = f32> {
+    data: Vec<T>,
     shape: Vec<usize>
 }
 
-impl Tensor {
-    fn new(shape: Vec<usize>) -> Tensor {
+impl<T: Float> Tensor<T> {
+    fn new(shape: Vec<usize>) -> Tensor<T> {
         let mut size: usize = 1;
         for x in shape.clone() {
             size *= x;
         }
-        let mut res = Tensor{data: Vec::<f32>::new(), shape: shape};
-        for _ in 0..size {
-            res.data.push(0f32);
+        Tensor{data: vec![T::zero(); size], shape: shape}
+    }
+
+    // Get the stride of each dimension of a broadcast shape
+    // in this tensor's data. Broadcast dimensions get a stride
+    // of 0, so they repeat the same values.
+    fn broadcast_strides(&self, shape: &[usize]) -> Vec<usize> {
+        let mut strides = Vec::<usize>::new();
+        for _ in 0..shape.len() {
+            strides.push(0);
+        }
+        let offset = shape.len() - self.shape.len();
+        let mut stride = 1;
+        for i in (0..self.shape.len()).rev() {
+            if self.shape[i] != 1 {
+                strides[i + offset] = stride;
+            }
+            stride *= self.shape[i];
+        }
+        strides
+    }
+
+    // Expand the tensor to a larger shape, repeating values
+    // along broadcast dimensions.
+    fn broadcast_to(&self, shape: &[usize]) -> Tensor<T> {
+        self.view().broadcast_to(shape).to_tensor()
+    }
+
+    // Sum out the dimensions that broadcast_to() would have
+    // expanded, producing a tensor of the given shape.
+    // This is used to turn the gradient of a broadcast result
+    // into the gradient of the original operand.
+    fn sum_to(&self, shape: &[usize]) -> Tensor<T> {
+        if self.shape == shape {
+            return self.clone();
+        }
+        let target = Tensor::new(shape.to_vec());
+        if broadcast_shape(shape, &self.shape) != self.shape {
+            panic!("cannot sum {:?} to {:?}", self.shape, shape);
+        }
+        let strides = target.broadcast_strides(&self.shape);
+        let mut res = target;
+        for i in 0..self.data.len() {
+            res.data[strided_index(i, &self.shape, &strides)] += self.data[i];
         }
         res
     }
-}
 
-macro_rules! define_tensor_op {
-    ($trait:tt, $fn:tt) => {
-        impl<'a, 'b> $trait<&'b Tensor> for &'a Tensor {
-            type Output = Tensor;
+    // Apply a function to every element.
+    fn map<F: Fn(T) -> T>(&self, f: F) -> Tensor<T> {
+        let mut res = Tensor{data: Vec::<T>::new(), shape: self.shape.clone()};
+        for x in &self.data {
+            res.data.push(f(*x));
+        }
+        res
+    }
+
+    // Split the shape around an axis into the number of
+    // outer rows, the size of the axis, and the number of
+    // inner columns, so that element (o, k, i) lives at
+    // (o*size + k)*inner + i.
+    fn axis_layout(&self, axis: usize) -> (usize, usize, usize) {
+        if axis >= self.shape.len() {
+            panic!("axis {} out of range for shape {:?}", axis, self.shape);
+        }
+        let outer = self.shape[..axis].iter().product();
+        let inner = self.shape[axis + 1..].iter().product();
+        (outer, self.shape[axis], inner)
+    }
 
-            fn $fn(self, rhs: &'b Tensor) -> Tensor {
-                let mut result = Tensor{data: Vec::<f32>::new(), shape: self.shape.clone()};
-                if self.shape != rhs.shape || self.data.len() != rhs.data.len() {
-                    panic!("shape mismatch");
+    // Get the shape with an axis removed.
+    fn reduced_shape(&self, axis: usize) -> Vec<usize> {
+        let mut shape = self.shape.clone();
+        shape.remove(axis);
+        shape
+    }
+
+    // Sum the elements along an axis, removing it.
+    fn sum_axis(&self, axis: usize) -> Tensor<T> {
+        let (outer, size, inner) = self.axis_layout(axis);
+        let mut res = Tensor::new(self.reduced_shape(axis));
+        for o in 0..outer {
+            for k in 0..size {
+                for i in 0..inner {
+                    res.data[o * inner + i] += self.data[(o * size + k) * inner + i];
                 }
-                for i in 0..self.data.len() {
-                    result.data.push($trait::$fn(self.data[i], rhs.data[i]));
+            }
+        }
+        res
+    }
+
+    // Find the index of the largest element along an axis.
+    // Ties are broken by picking the first index.
+    fn argmax(&self, axis: usize) -> Vec<usize> {
+        let (outer, size, inner) = self.axis_layout(axis);
+        let mut res = Vec::<usize>::new();
+        for o in 0..outer {
+            for i in 0..inner {
+                let mut best = 0;
+                for k in 1..size {
+                    if self.data[(o * size + k) * inner + i] >
+                            self.data[(o * size + best) * inner + i] {
+                        best = k;
+                    }
                 }
-                result
+                res.push(best);
             }
         }
+        res
+    }
 
-        impl<'a> $trait<f32> for &'a Tensor {
-            type Output = Tensor;
+    // Take the largest element along an axis, removing it.
+    fn max_axis(&self, axis: usize) -> Tensor<T> {
+        let (_, size, inner) = self.axis_layout(axis);
+        let mut res = Tensor::new(self.reduced_shape(axis));
+        for (j, k) in self.argmax(axis).into_iter().enumerate() {
+            res.data[j] = self.data[((j / inner) * size + k) * inner + j % inner];
+        }
+        res
+    }
 
-            fn $fn(self, rhs: f32) -> Tensor {
-                let mut result = Tensor{data: Vec::<f32>::new(), shape: self.shape.clone()};
-                for i in 0..self.data.len() {
-                    result.data.push($trait::$fn(self.data[i], rhs));
+    // Insert a new axis of the given size, repeating the
+    // values along it. This inverts sum_axis() for the
+    // purpose of propagating gradients.
+    fn expand_axis(&self, axis: usize, size: usize) -> Tensor<T> {
+        let mut shape = self.shape.clone();
+        shape.insert(axis, size);
+        let mut res = Tensor::new(shape);
+        let (outer, _, inner) = res.axis_layout(axis);
+        for o in 0..outer {
+            for k in 0..size {
+                for i in 0..inner {
+                    res.data[(o * size + k) * inner + i] = self.data[o * inner + i];
                 }
-                result
             }
         }
+        res
+    }
+
+    // Swap the two dimensions of a matrix.
+    fn transpose(&self) -> Tensor<T> {
+        if self.shape.len() != 2 {
+            panic!("cannot transpose shape {:?}", self.shape);
+        }
+        self.view().transpose(0, 1).to_tensor()
+    }
+
+    // Change the shape without copying the data.
+    fn reshape(self, shape: Vec<usize>) -> Tensor<T> {
+        if shape.iter().product::<usize>() != self.data.len() {
+            panic!("cannot reshape {:?} to {:?}", self.shape, shape);
+        }
+        Tensor{data: self.data, shape: shape}
+    }
 
-        impl<'a> $trait<&'a Tensor> for f32 {
-            type Output = Tensor;
+    fn view(&self) -> TensorView<'_, T> {
+        TensorView{
+            data: &self.data,
+            strides: contiguous_strides(&self.shape),
+            shape: self.shape.clone()
+        }
+    }
 
-            fn $fn(self, rhs: &'a Tensor) -> Tensor {
-                let mut result = Tensor{data: Vec::<f32>::new(), shape: rhs.shape.clone()};
-                for i in 0..rhs.data.len() {
-                    result.data.push($trait::$fn(self, rhs.data[i]));
+    // Multiply two matrices.
+    fn matmul(&self, rhs: &Tensor<T>) -> Tensor<T> {
+        if self.shape.len() != 2 || rhs.shape.len() != 2 || self.shape[1] != rhs.shape[0] {
+            panic!("shape mismatch: {:?} and {:?}", self.shape, rhs.shape);
+        }
+        let (rows, inner, cols) = (self.shape[0], self.shape[1], rhs.shape[1]);
+        let mut res = Tensor::new(vec![rows, cols]);
+        for i in 0..rows {
+            for k in 0..inner {
+                let x = self.data[i * inner + k];
+                for j in 0..cols {
+                    res.data[i * cols + j] += x * rhs.data[k * cols + j];
                 }
-                result
             }
         }
+        res
     }
 }
 
-define_tensor_op!(Add, add);
-define_tensor_op!(Mul, mul);
-define_tensor_op!(Div, div);
-define_tensor_op!(Sub, sub);
+// A strided view of the data in a Tensor.
+//
+// Element (i, j, ...) of the view is stored at
+// i*strides[0] + j*strides[1] + ... in the data. Transposing
+// and broadcasting only change the shape and strides, so no
+// data is copied until to_tensor() is called.
+#[derive(Clone)]
+struct TensorView<'a, T: Float + 'a = f32> {
+    data: &'a [T],
+    shape: Vec<usize>,
+    strides: Vec<usize>
+}
 
-struct Gradient(HashMap<String, Tensor>);
+impl<'a, T: Float> TensorView<'a, T> {
+    fn len(&self) -> usize {
+        self.shape.iter().product()
+    }
 
-impl Gradient {
-    fn new(name: String, value: Tensor) -> Gradient {
-        let mut res = Gradient(HashMap::<String, Tensor>::new());
-        res.0.insert(name, value);
+    // Swap two axes.
+    fn transpose(&self, a: usize, b: usize) -> TensorView<'a, T> {
+        let mut res = self.clone();
+        res.shape.swap(a, b);
+        res.strides.swap(a, b);
         res
     }
 
-    fn combine(mut self, other: Gradient) -> Gradient {
-        for (k, v) in other.0.into_iter() {
-            self.0.insert(k, v);
+    // Expand to a larger shape by giving the broadcast
+    // dimensions a stride of zero.
+    fn broadcast_to(&self, shape: &[usize]) -> TensorView<'a, T> {
+        if broadcast_shape(&self.shape, shape) != shape {
+            panic!("cannot broadcast {:?} to {:?}", self.shape, shape);
         }
-        self
+        let mut strides = Vec::<usize>::new();
+        let offset = shape.len() - self.shape.len();
+        for i in 0..shape.len() {
+            strides.push(if i < offset || self.shape[i - offset] == 1 {
+                0
+            } else {
+                self.strides[i - offset]
+            });
+        }
+        TensorView{data: self.data, shape: shape.to_vec(), strides: strides}
+    }
+
+    // Copy the viewed elements into a new contiguous Tensor.
+    fn to_tensor(&self) -> Tensor<T> {
+        let mut res = Tensor{data: Vec::<T>::with_capacity(self.len()), shape: self.shape.clone()};
+        for i in 0..self.len() {
+            res.data.push(self.data[strided_index(i, &self.shape, &self.strides)]);
+        }
+        res
     }
 }
 
-// A tensor that can be back-propagated through.
-trait Res {
-    fn value(&self) -> &Tensor;
+// Get the strides of a contiguous row-major shape.
+fn contiguous_strides(shape: &[usize]) -> Vec<usize> {
+    let mut strides = vec![0; shape.len()];
+    let mut stride = 1;
+    for i in (0..shape.len()).rev() {
+        strides[i] = stride;
+        stride *= shape[i];
+    }
+    strides
+}
 
-    fn name(&self) -> String;
+// The number of elements in a shape, or None if that
+// doesn't fit in a usize, e.g. for a shape read from a file.
+fn checked_size(shape: &[usize]) -> Option<usize> {
+    shape.iter().fold(Some(1usize), |n, &x| n.and_then(|n| n.checked_mul(x)))
+}
 
-    fn backward(&mut self, out_grad: &Tensor) -> Gradient;
+// Compute the shape produced by broadcasting two shapes
+// together, following the numpy rules: shapes are aligned
+// at their last dimension, and a dimension of size 1 is
+// stretched to match the other shape.
+fn broadcast_shape(a: &[usize], b: &[usize]) -> Vec<usize> {
+    try_broadcast_shape("broadcast", a, b).unwrap_or_else(|e| panic!("{}", e))
 }
 
-macro_rules! define_op_res {
-    ($name:expr, $trait:tt, $fn:tt, $res_name:tt, $bwd:item) => {
-        struct $res_name {
-            a: Box<Res>,
-            b: Box<Res>,
-            out: Tensor
+fn try_broadcast_shape(op: &'static str, a: &[usize], b: &[usize])
+    -> Result<Vec<usize>, ShapeError>
+{
+    let n = if a.len() > b.len() { a.len() } else { b.len() };
+    let mut res = Vec::<usize>::new();
+    for i in 0..n {
+        let x = if i + a.len() >= n { a[i + a.len() - n] } else { 1 };
+        let y = if i + b.len() >= n { b[i + b.len() - n] } else { 1 };
+        if x == y || y == 1 {
+            res.push(x);
+        } else if x == 1 {
+            res.push(y);
+        } else {
+            return Err(ShapeError::new(op, &[a, b], "shapes can't be broadcast together"));
         }
+    }
+    Ok(res)
+}
 
-        impl Res for $res_name {
-            fn value(&self) -> &Tensor {
-                &self.out
+// The error returned when a graph is built from operands
+// whose shapes don't fit the op, e.g. a MatMul of [2, 3]
+// and [4, 5]. The checked methods (try_add(), try_matmul(),
+// etc.) return it; the operators and unchecked methods
+// panic with its message instead.
+#[derive(Clone, Debug, PartialEq)]
+struct ShapeError {
+    op: &'static str,
+    shapes: Vec<Vec<usize>>,
+    reason: String
+}
+
+impl ShapeError {
+    fn new<S: Into<String>>(op: &'static str, shapes: &[&[usize]], reason: S) -> ShapeError {
+        ShapeError{
+            op: op,
+            shapes: shapes.iter().map(|x| x.to_vec()).collect(),
+            reason: reason.into()
+        }
+    }
+}
+
+impl Display for ShapeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let shapes: Vec<String> = self.shapes.iter().map(|x| format!("{:?}", x)).collect();
+        write!(f, "{} of {}: {}", self.op, shapes.join(" and "), self.reason)
+    }
+}
+
+impl std::error::Error for ShapeError {}
+
+// Let shape errors propagate with ? in functions that
+// report errors as strings, such as Tape::from_json().
+impl From<ShapeError> for String {
+    fn from(err: ShapeError) -> String {
+        err.to_string()
+    }
+}
+
+// Convert a flat index into a row-major shape into an
+// offset using a (possibly broadcast) set of strides.
+fn strided_index(mut idx: usize, shape: &[usize], strides: &[usize]) -> usize {
+    let mut res = 0;
+    for i in (0..shape.len()).rev() {
+        res += (idx % shape[i]) * strides[i];
+        idx /= shape[i];
+    }
+    res
+}
+
+macro_rules! define_tensor_op {
+    ($trait:tt, $fn:tt) => {
+        impl<'a, 'b, T: Float> $trait<&'b Tensor<T>> for &'a Tensor<T> {
+            type Output = Tensor<T>;
+
+            fn $fn(self, rhs: &'b Tensor<T>) -> Tensor<T> {
+                if self.shape == rhs.shape && self.data.len() == rhs.data.len() {
+                    let mut result = Tensor{data: Vec::<T>::new(), shape: self.shape.clone()};
+                    for i in 0..self.data.len() {
+                        result.data.push($trait::$fn(self.data[i], rhs.data[i]));
+                    }
+                    return result;
+                }
+                let shape = broadcast_shape(&self.shape, &rhs.shape);
+                let lhs_strides = self.broadcast_strides(&shape);
+                let rhs_strides = rhs.broadcast_strides(&shape);
+                let mut result = Tensor::new(shape);
+                for i in 0..result.data.len() {
+                    let x = self.data[strided_index(i, &result.shape, &lhs_strides)];
+                    let y = rhs.data[strided_index(i, &result.shape, &rhs_strides)];
+                    result.data[i] = $trait::$fn(x, y);
+                }
+                result
             }
+        }
 
-            fn name(&self) -> String {
-                format!("{}<{}, {}>", $name, self.a.name(), self.b.name())
+        impl<'a, T: Float> $trait<T> for &'a Tensor<T> {
+            type Output = Tensor<T>;
+
+            fn $fn(self, rhs: T) -> Tensor<T> {
+                self.map(|x| $trait::$fn(x, rhs))
             }
+        }
+
+        // Scalars on the left can't be generic because of the
+        // orphan rules, so each element type is listed.
+        impl<'a> $trait<&'a Tensor<f32>> for f32 {
+            type Output = Tensor<f32>;
 
-            fn backward(&mut self, out_grad: &Tensor) -> Gradient {
-                $bwd;
-                // TODO: why doesn't *self.a or self.a.deref_mut() work?
-                // TODO: why the static in Box<Res + 'static>?
-                bwd(self.a.as_mut(), self.b.as_mut(), out_grad)
+            fn $fn(self, rhs: &'a Tensor<f32>) -> Tensor<f32> {
+                rhs.map(|x| $trait::$fn(self, x))
             }
         }
 
-        impl $trait for Box<Res> {
-            type Output = Box<Res>;
+        impl<'a> $trait<&'a Tensor<f64>> for f64 {
+            type Output = Tensor<f64>;
 
-            fn $fn(self, rhs: Box<Res>) -> Box<Res> {
-                let out = $trait::$fn(self.value(), rhs.value());
-                Box::new($res_name{a: self, b: rhs, out: out})
+            fn $fn(self, rhs: &'a Tensor<f64>) -> Tensor<f64> {
+                rhs.map(|x| $trait::$fn(self, x))
             }
         }
     }
 }
 
-define_op_res!("Add", Add, add, AddRes,
-    fn bwd(a: &mut Res, b: &mut Res, out_grad: &Tensor) -> Gradient {
-        a.backward(out_grad).combine(b.backward(out_grad))
-    });
+define_tensor_op!(Add, add);
+define_tensor_op!(Mul, mul);
+define_tensor_op!(Div, div);
+define_tensor_op!(Sub, sub);
 
-define_op_res!("Mul", Mul, mul, MulRes,
-    fn bwd(a: &mut Res, b: &mut Res, out_grad: &Tensor) -> Gradient {
-        a.backward(&(out_grad * b.value())).combine(b.backward(&(out_grad * a.value())))
-    });
+// Add in place when the shapes match, which is always the
+// case when summing gradients.
+impl<'a, T: Float> AddAssign<&'a Tensor<T>> for Tensor<T> {
+    fn add_assign(&mut self, rhs: &'a Tensor<T>) {
+        if self.shape == rhs.shape && self.data.len() == rhs.data.len() {
+            for (x, &y) in self.data.iter_mut().zip(&rhs.data) {
+                *x += y;
+            }
+        } else {
+            *self = &*self + rhs;
+        }
+    }
+}
 
-define_op_res!("Div", Div, div, DivRes,
-    fn bwd(a: &mut Res, b: &mut Res, out_grad: &Tensor) -> Gradient{
-        // TODO: figure out why we have to bind b_grad to a
-        // variable and then pass it in.
-        // I think it's because b.backward() grabs a mutable
-        // reference to b before b.value() can run.
-        let b_grad = &(-1f32 * &(a.value() * out_grad)) / &(b.value() * b.value());
-        a.backward(&(out_grad / b.value())).combine(b.backward(&b_grad))
-    });
+struct Gradient<T: Float = f32>(HashMap<String, Tensor<T>>);
 
-define_op_res!("Sub", Sub, sub, SubRes,
-    fn bwd(a: &mut Res, b: &mut Res, out_grad: &Tensor) -> Gradient {
-        a.backward(out_grad).combine(b.backward(&(out_grad * -1f32)))
-    });
+impl<T: Float> Gradient<T> {
+    fn new(name: String, value: Tensor<T>) -> Gradient<T> {
+        let mut res = Gradient(HashMap::<String, Tensor<T>>::new());
+        res.0.insert(name, value);
+        res
+    }
 
-struct Variable {
-    data: Tensor,
-    name: String
+    fn empty() -> Gradient<T> {
+        Gradient(HashMap::<String, Tensor<T>>::new())
+    }
+
+    // Add a gradient for a variable, summing it with any
+    // gradient that is already present for the same name.
+    fn accumulate(&mut self, name: String, value: Tensor<T>) {
+        match self.0.get_mut(&name) {
+            Some(existing) => {
+                *existing += &value;
+                return;
+            },
+            None => {}
+        }
+        self.0.insert(name, value);
+    }
 }
 
-impl Variable {
-    fn new(name: String, value: Tensor) -> Variable {
-        Variable{
-            data: value,
-            name: name
+// A tensor that can be back-propagated through.
+//
+// A Res only knows how to compute the gradients of its
+// direct inputs; walking the rest of the graph is done by
+// a Tape, which makes sure every node is visited once.
+trait Res<T: Float = f32> {
+    fn value(&self) -> &Tensor<T>;
+
+    fn name(&self) -> String;
+
+    // Get the type of operation, e.g. "Add" or "Variable".
+    // Together with attrs(), this is enough to rebuild the
+    // Res from its inputs when loading an exported graph.
+    fn op(&self) -> &'static str;
+
+    // Get the non-Node arguments of the operation, such as
+    // the axis of a reduction.
+    fn attrs(&self) -> Vec<(&'static str, f64)> {
+        Vec::new()
+    }
+
+    fn inputs(&self) -> Vec<Node<T>>;
+
+    // Compute the gradient of each input, in the same order
+    // as inputs(), given the gradient of the output.
+    fn backward(&self, out_grad: &Tensor<T>) -> Vec<Tensor<T>>;
+
+    // Add the gradient of each input into grads[inputs[i]],
+    // where inputs holds the tape positions of inputs(). The
+    // default goes through backward(); elementwise ops
+    // override it to skip the temporary tensors.
+    fn backward_into(&self, out_grad: &Tensor<T>, grads: &mut [Tensor<T>], inputs: &[usize]) {
+        add_grads(grads, inputs, self.backward(out_grad));
+    }
+
+    // Like backward(), but build each input gradient as a
+    // Node from a Node out_grad, so that the gradients can be
+    // differentiated again. output is the Node holding this
+    // Res, for gradients that are cheapest to write in terms
+    // of it.
+    fn backward_graph(&self, output: &Node<T>, out_grad: &Node<T>) -> Vec<Node<T>>;
+
+    // Give up the output value, so that no_grad() can drop
+    // the rest of the Res.
+    fn into_value(self) -> Tensor<T> where Self: Sized {
+        self.value().clone()
+    }
+
+    // Compute the directional derivative (tangent) of the
+    // output given the tangents of the inputs.
+    fn jvp(&self, in_tangents: &[&Tensor<T>]) -> Tensor<T>;
+
+    // Differentiate backward() in the direction given by the
+    // input tangents and the tangent of out_grad, returning
+    // the tangent of each input gradient.
+    // This is what makes forward-over-reverse possible.
+    fn backward_jvp(&self, in_tangents: &[&Tensor<T>], out_grad: &Tensor<T>,
+                    out_grad_tangent: &Tensor<T>) -> Vec<Tensor<T>>;
+
+    // If this is a Variable, get its name so that the Tape
+    // can put its gradient into the final Gradient.
+    fn variable_name(&self) -> Option<String> {
+        None
+    }
+
+    // Whether this is a Variable or Constant, which has no
+    // inputs, so no_grad() keeps it as it is.
+    fn is_leaf(&self) -> bool {
+        false
+    }
+}
+
+thread_local! {
+    // Whether new nodes keep their inputs, which no_grad()
+    // turns off.
+    static RECORDING: Cell<bool> = Cell::new(true);
+}
+
+// Evaluate f in inference mode. Ops only compute their
+// values, and become unnamed Constants that don't keep their
+// inputs alive, so each intermediate result is freed as soon
+// as it isn't used any more. Nothing computed inside f can
+// be differentiated.
+fn no_grad<R, F: FnOnce() -> R>(f: F) -> R {
+    // Restore the old mode even if f panics.
+    struct Restore(bool);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let recording = self.0;
+            RECORDING.with(|x| x.set(recording));
         }
     }
+
+    let _restore = Restore(RECORDING.with(|x| x.replace(false)));
+    f()
 }
 
-impl<'a> Res for Variable {
-    fn value(&self) -> &Tensor {
-        &self.data
+// A shared handle to a Res in a graph.
+//
+// Cloning a Node is cheap, and the same Node can be used
+// as an input to any number of other nodes.
+#[derive(Clone)]
+struct Node<T: Float = f32>(Rc<Res<T>>);
+
+impl<T: Float> Node<T> {
+    fn new<R: Res<T> + 'static>(res: R) -> Node<T> {
+        if RECORDING.with(|x| x.get()) || res.is_leaf() {
+            return Node(Rc::new(res));
+        }
+        Node(Rc::new(Constant(res.into_value(), String::new())))
+    }
+
+    fn value(&self) -> &Tensor<T> {
+        self.0.value()
     }
 
     fn name(&self) -> String {
-        self.name.clone()
+        self.0.name()
+    }
+
+    // Get a key that uniquely identifies the underlying Res
+    // for as long as the Node is alive.
+    fn id(&self) -> usize {
+        &*self.0 as *const Res<T> as *const u8 as usize
+    }
+
+    fn backward(&self, out_grad: &Tensor<T>) -> Gradient<T> {
+        Tape::new(self).backward(out_grad)
+    }
+
+    // Back-propagate from a scalar, such as a loss.
+    fn backward_scalar(&self) -> Gradient<T> {
+        self.backward(&self.scalar_grad("backward_scalar"))
+    }
+
+    // Compute the directional derivative of the output in
+    // the direction given by a tangent for each Variable.
+    // Variables that are not in the direction are fixed.
+    fn jvp(&self, direction: &Gradient<T>) -> Tensor<T> {
+        Tape::new(self).jvp(direction)
+    }
+
+    // Compute the product of the Hessian of a scalar with a
+    // direction vector, using forward-over-reverse.
+    fn hvp(&self, direction: &Gradient<T>) -> Gradient<T> {
+        Tape::new(self).hvp(&self.scalar_grad("hvp"), direction).1
+    }
+
+    // Get the gradient of a scalar as a graph (see
+    // Tape::backward_graph), so that it can be differentiated
+    // again.
+    fn grad(&self) -> HashMap<String, Node<T>> {
+        let out_grad = Node::new(Constant(self.scalar_grad("grad"), "1".to_string()));
+        Tape::new(self).backward_graph(&out_grad)
     }
 
-    fn backward(&mut self, out_grad: &Tensor) -> Gradient {
-        Gradient::new(self.name(), out_grad.clone())
+    fn scalar_grad(&self, caller: &str) -> Tensor<T> {
+        if self.value().data.len() != 1 {
+            panic!("{}() on non-scalar shape {:?}", caller, self.value().shape);
+        }
+        let mut out_grad = self.value().clone();
+        out_grad.data[0] = T::one();
+        out_grad
     }
 }
 
-struct Fork {
-    input: Box<Res>,
-    output: Box<Res>,
-    names: Vec<String>
+// The nodes of a graph in topological order, such that
+// every node comes after all of its inputs.
+struct Tape<T: Float = f32>(Vec<Node<T>>);
+
+// Storage for the gradient of each node on a tape, indexed
+// by position. Keeping it between backward passes lets a
+// training loop reuse the same tensors on every step, as
+// long as the graph keeps the same shapes.
+struct GradBuffers<T: Float = f32> {
+    grads: Vec<Tensor<T>>,
+    reached: Vec<bool>,
+    keep: bool
 }
 
-impl Fork {
-    fn new<F>(input: Box<Res>, n: usize, op: F) -> Fork
-        where F: FnOnce(Vec<Box<Res>>) -> Box<Res>
-    {
-        let mut names = Vec::<String>::new();
-        let mut vars = Vec::<Box<Res>>::new();
-        for i in 0..n {
-            let name = format!("Fork<{}>[{}]", input.name(), i);
-            vars.push(Box::new(Variable::new(name.clone(), input.value().clone())));
-            names.push(name);
+impl<T: Float> GradBuffers<T> {
+    fn new() -> GradBuffers<T> {
+        GradBuffers{grads: Vec::new(), reached: Vec::new(), keep: true}
+    }
+
+    // Buffers for a single pass, which free each gradient
+    // once its node has been visited.
+    fn transient() -> GradBuffers<T> {
+        GradBuffers{keep: false, ..GradBuffers::new()}
+    }
+
+    // Prepare for a pass over a tape of the given length.
+    fn start(&mut self, len: usize) {
+        while self.grads.len() < len {
+            self.grads.push(Tensor{data: Vec::new(), shape: Vec::new()});
         }
-        let output = op(vars);
-        Fork{input: input, output: output, names: names}
+        self.reached.clear();
+        self.reached.resize(len, false);
     }
 
-    fn fork<F>(input: Box<Res>, n: usize, op: F) -> Box<Res>
-        where F: FnOnce(Vec<Box<Res>>) -> Box<Res>
-    {
-        Box::new(Fork::new(input, n, op))
+    // Zero the gradient at a position when it's first
+    // reached, reusing the old buffer if its shape fits.
+    fn reset(&mut self, pos: usize, shape: &[usize]) {
+        let grad = &mut self.grads[pos];
+        if grad.shape[..] == shape[..] && grad.data.len() == shape.iter().product() {
+            for x in grad.data.iter_mut() {
+                *x = T::zero();
+            }
+        } else {
+            *grad = Tensor::new(shape.to_vec());
+        }
+        self.reached[pos] = true;
     }
 }
 
-impl Res for Fork {
-    fn value(&self) -> &Tensor {
-        self.output.value()
+impl<T: Float> Tape<T> {
+    fn new(output: &Node<T>) -> Tape<T> {
+        let mut order = Vec::<Node<T>>::new();
+        let mut visited = HashSet::<usize>::new();
+
+        // Use an explicit stack rather than recursion so that
+        // very deep graphs don't overflow the call stack.
+        // The flag says whether the inputs were pushed yet.
+        // A node can be pushed more than once, so it's only
+        // marked as visited when it's expanded; otherwise a
+        // node that is reached again from a later input could
+        // end up after that input on the tape.
+        let mut stack = vec![(output.clone(), false)];
+        while let Some((node, expanded)) = stack.pop() {
+            if expanded {
+                order.push(node);
+                continue;
+            }
+            if !visited.insert(node.id()) {
+                continue;
+            }
+            let inputs = node.0.inputs();
+            stack.push((node, true));
+            for input in inputs {
+                if !visited.contains(&input.id()) {
+                    stack.push((input, false));
+                }
+            }
+        }
+        Tape(order)
+    }
+
+    // Propagate a gradient from the last node on the tape
+    // back to every Variable.
+    fn backward(&self, out_grad: &Tensor<T>) -> Gradient<T> {
+        self.backward_with(out_grad, &mut GradBuffers::transient())
     }
 
-    fn name(&self) -> String {
-        format!("Forked<{}>", self.output.name())
-    }
-
-    fn backward(&mut self, out_grad: &Tensor) -> Gradient {
-        let mut grad = self.output.backward(out_grad);
-        let mut summed: Option<Tensor> = None;
-        for name in &self.names {
-            match grad.0.remove(name) {
-                Some(tensor) => {
-                    summed = Some(match summed {
-                        Some(tensor1) => &tensor + &tensor1,
-                        None => tensor
-                    });
-                },
-                None => ()
+    // Run the backward pass using buffers that were kept from
+    // an earlier pass, e.g. on the previous training step.
+    fn backward_with(&self, out_grad: &Tensor<T>, buffers: &mut GradBuffers<T>) -> Gradient<T> {
+        let mut result = Gradient::empty();
+        self.propagate_with(out_grad, buffers, |node, grad| {
+            if let Some(name) = node.0.variable_name() {
+                result.accumulate(name, grad.clone());
             }
+        });
+        result
+    }
+
+    // Propagate a gradient from the last node on the tape
+    // back through the graph, calling f with the gradient of
+    // each node once the gradients from all of its uses have
+    // been summed. Nodes that don't affect the output are
+    // never visited.
+    fn propagate<F: FnMut(&Node<T>, &Tensor<T>)>(&self, out_grad: &Tensor<T>, f: F) {
+        self.propagate_with(out_grad, &mut GradBuffers::transient(), f)
+    }
+
+    fn propagate_with<F>(&self, out_grad: &Tensor<T>, buffers: &mut GradBuffers<T>, mut f: F)
+        where F: FnMut(&Node<T>, &Tensor<T>)
+    {
+        if self.0.is_empty() {
+            return;
         }
-        match summed {
-            Some(downstream) => grad.combine(self.input.backward(&downstream)),
-            None => grad
+        let positions = self.indices();
+        buffers.start(self.0.len());
+        let last = self.0.len() - 1;
+        buffers.reset(last, &out_grad.shape);
+        buffers.grads[last] += out_grad;
+
+        let mut inputs = Vec::<usize>::new();
+        for (pos, node) in self.0.iter().enumerate().rev() {
+            if !buffers.reached[pos] {
+                continue;
+            }
+            inputs.clear();
+            for input in node.0.inputs() {
+                let i = positions[&input.id()];
+                if !buffers.reached[i] {
+                    buffers.reset(i, &input.value().shape);
+                }
+                inputs.push(i);
+            }
+            if !inputs.is_empty() {
+                // Move the gradient out while the inputs' gradients
+                // are borrowed. An empty Tensor doesn't allocate.
+                let empty = Tensor{data: Vec::new(), shape: Vec::new()};
+                let grad = std::mem::replace(&mut buffers.grads[pos], empty);
+                node.0.backward_into(&grad, &mut buffers.grads, &inputs);
+                buffers.grads[pos] = grad;
+            }
+            f(node, &buffers.grads[pos]);
+            if !buffers.keep {
+                buffers.grads[pos] = Tensor{data: Vec::new(), shape: Vec::new()};
+            }
         }
     }
-}
 
-struct Constant(Tensor, String);
+    // Run forward mode over the tape, computing the tangent
+    // of every node given the tangents of the Variables.
+    // Variables missing from the direction get zero tangents.
+    fn tangents(&self, direction: &Gradient<T>) -> HashMap<usize, Tensor<T>> {
+        let mut tangents = HashMap::<usize, Tensor<T>>::new();
+        for node in &self.0 {
+            let tangent = match node.0.variable_name() {
+                Some(name) => match direction.0.get(&name) {
+                    Some(t) => t.clone(),
+                    None => Tensor::new(node.value().shape.clone())
+                },
+                None => {
+                    let inputs = node.0.inputs();
+                    let in_tangents: Vec<&Tensor<T>> = inputs.iter()
+                        .map(|x| &tangents[&x.id()])
+                        .collect();
+                    node.0.jvp(&in_tangents)
+                }
+            };
+            tangents.insert(node.id(), tangent);
+        }
+        tangents
+    }
 
-impl Constant {
-    fn new(shape: Vec<usize>, value: f32) -> Constant {
-        let mut res = Constant(Tensor::new(shape), format!("{}", value));
-        for i in 0..res.0.data.len() {
-            res.0.data[i] = value;
+    // Compute the tangent of the last node on the tape.
+    fn jvp(&self, direction: &Gradient<T>) -> Tensor<T> {
+        match self.0.last() {
+            Some(output) => self.tangents(direction).remove(&output.id())
+                .expect("missing tangent"),
+            None => panic!("jvp() on empty tape")
         }
-        res
     }
-}
 
-impl Res for Constant {
-    fn value(&self) -> &Tensor {
-        &self.0
+    // Run the backward pass while tracking the tangent of
+    // every gradient in the given direction.
+    //
+    // Returns the gradient and its tangent, which is the
+    // Hessian-vector product of the dot product between
+    // out_grad and the output.
+    fn hvp(&self, out_grad: &Tensor<T>, direction: &Gradient<T>) -> (Gradient<T>, Gradient<T>) {
+        let tangents = self.tangents(direction);
+        let mut result = (Gradient::empty(), Gradient::empty());
+        let mut grads = HashMap::<usize, (Tensor<T>, Tensor<T>)>::new();
+        match self.0.last() {
+            Some(output) => grads.insert(output.id(),
+                (out_grad.clone(), Tensor::new(out_grad.shape.clone()))),
+            None => return result
+        };
+        for node in self.0.iter().rev() {
+            let (grad, grad_tangent) = match grads.remove(&node.id()) {
+                Some(pair) => pair,
+                None => continue
+            };
+            if let Some(name) = node.0.variable_name() {
+                result.0.accumulate(name.clone(), grad);
+                result.1.accumulate(name, grad_tangent);
+                continue;
+            }
+            let inputs = node.0.inputs();
+            let in_tangents: Vec<&Tensor<T>> = inputs.iter().map(|x| &tangents[&x.id()]).collect();
+            let in_grads = node.0.backward(&grad);
+            let in_grad_tangents = node.0.backward_jvp(&in_tangents, &grad, &grad_tangent);
+            for ((input, g), t) in inputs.iter().zip(in_grads).zip(in_grad_tangents) {
+                let sum = match grads.remove(&input.id()) {
+                    Some((g1, t1)) => (&g1 + &g, &t1 + &t),
+                    None => (g, t)
+                };
+                grads.insert(input.id(), sum);
+            }
+        }
+        result
     }
 
-    fn name(&self) -> String {
-        self.1.clone()
+    // Run the backward pass with Nodes instead of Tensors,
+    // building a graph for the gradient of each Variable
+    // that can itself be differentiated, e.g. for second
+    // derivatives or gradient penalties.
+    fn backward_graph(&self, out_grad: &Node<T>) -> HashMap<String, Node<T>> {
+        let mut result = HashMap::<String, Node<T>>::new();
+        let mut grads = HashMap::<usize, Node<T>>::new();
+        match self.0.last() {
+            Some(output) => grads.insert(output.id(), out_grad.clone()),
+            None => return result
+        };
+        for node in self.0.iter().rev() {
+            let grad = match grads.remove(&node.id()) {
+                Some(grad) => grad,
+                None => continue
+            };
+            if let Some(name) = node.0.variable_name() {
+                let sum = match result.remove(&name) {
+                    Some(g) => &g + &grad,
+                    None => grad
+                };
+                result.insert(name, sum);
+                continue;
+            }
+            for (input, g) in node.0.inputs().iter().zip(node.0.backward_graph(node, &grad)) {
+                let sum = match grads.remove(&input.id()) {
+                    Some(g1) => &g1 + &g,
+                    None => g
+                };
+                grads.insert(input.id(), sum);
+            }
+        }
+        result
     }
+}
 
-    fn backward(&mut self, _: &Tensor) -> Gradient {
-        Gradient(HashMap::<String, Tensor>::new())
+fn add_grads<T: Float>(grads: &mut [Tensor<T>], inputs: &[usize], in_grads: Vec<Tensor<T>>) {
+    for (&i, in_grad) in inputs.iter().zip(in_grads) {
+        grads[i] += &in_grad;
     }
 }
 
-fn main() {
-    // Approximate sin(x) for x=0, x=0.2, x=0.4.
-    let x = Variable::new("x".to_string(),
-        Tensor{shape: vec![3], data: vec![0f32, 0.2f32, 0.4f32]});
-    let shape = x.value().shape.clone();
-    let mut sin = Fork::fork(Box::new(x), 5, |mut xs| {
-        let x2 = xs.pop().expect("no field") * xs.pop().expect("no field");
-        Fork::fork(x2, 3, |mut x2s| {
-            let x1 = xs.pop().expect("no field");
-            let x3 = xs.pop().expect("no field") * x2s.pop().expect("no field");
-            let x5 = xs.pop().expect("no field") * x2s.pop().expect("no field") *
-                x2s.pop().expect("no field");
-            x1 - (x3 / Box::new(Constant::new(shape.clone(), 6f32))) +
-                (x5 / Box::new(Constant::new(shape, 120f32)))
-        })
-    });
-    println!("sin(0, 0.2, 0.4): {:?}", sin.value().data);
-    let out_grad = Tensor{shape: vec![3], data: vec![1f32, 1f32, 1f32]};
-    println!("cos(0, 0.2, 0.4): {:?}", sin.backward(&out_grad).0["x"].data)
+// Define a Res for a binary operator.
+//
+// The bwd function computes the input gradients, jvp the
+// output tangent, and bwd_jvp the tangents of the input
+// gradients (see Res::backward_jvp). bwd_graph is bwd
+// written with Nodes (see Res::backward_graph). When the
+// operands have the same shape, da and db give the input
+// gradients elementwise from (a, b, out_grad), which lets
+// backward_into() skip the temporaries.
+macro_rules! define_op_res {
+    ($name:expr, $trait:tt, $fn:tt, $try_fn:tt, $res_name:tt, $da:expr, $db:expr,
+     $bwd:item, $jvp:item, $bwd_jvp:item, $bwd_graph:item) => {
+        struct $res_name<T: Float> {
+            a: Node<T>,
+            b: Node<T>,
+            out: Tensor<T>
+        }
+
+        impl<T: Float> Res<T> for $res_name<T> {
+            fn value(&self) -> &Tensor<T> {
+                &self.out
+            }
+
+            fn into_value(self) -> Tensor<T> {
+                self.out
+            }
+
+            fn name(&self) -> String {
+                format!("{}<{}, {}>", $name, self.a.name(), self.b.name())
+            }
+
+            fn op(&self) -> &'static str {
+                $name
+            }
+
+            fn inputs(&self) -> Vec<Node<T>> {
+                vec![self.a.clone(), self.b.clone()]
+            }
+
+            fn backward(&self, out_grad: &Tensor<T>) -> Vec<Tensor<T>> {
+                $bwd
+                let (a_grad, b_grad) = bwd(self.a.value(), self.b.value(), out_grad);
+                vec![a_grad, b_grad]
+            }
+
+            fn backward_into(&self, out_grad: &Tensor<T>, grads: &mut [Tensor<T>],
+                             inputs: &[usize]) {
+                let (a, b) = (self.a.value(), self.b.value());
+                if a.shape != b.shape {
+                    return add_grads(grads, inputs, self.backward(out_grad));
+                }
+                let (da, db): (fn(T, T, T) -> T, fn(T, T, T) -> T) = ($da, $db);
+                for i in 0..out_grad.data.len() {
+                    let (x, y, g) = (a.data[i], b.data[i], out_grad.data[i]);
+                    grads[inputs[0]].data[i] += da(x, y, g);
+                    grads[inputs[1]].data[i] += db(x, y, g);
+                }
+            }
+
+            fn backward_graph(&self, _: &Node<T>, out_grad: &Node<T>) -> Vec<Node<T>> {
+                $bwd_graph
+                let (a_grad, b_grad) = bwd_graph(&self.a, &self.b, out_grad);
+                vec![a_grad.sum_to(&self.a.value().shape), b_grad.sum_to(&self.b.value().shape)]
+            }
+
+            fn jvp(&self, in_tangents: &[&Tensor<T>]) -> Tensor<T> {
+                $jvp
+                jvp(self.a.value(), self.b.value(), in_tangents[0], in_tangents[1])
+            }
+
+            fn backward_jvp(&self, in_tangents: &[&Tensor<T>], out_grad: &Tensor<T>,
+                            out_grad_tangent: &Tensor<T>) -> Vec<Tensor<T>> {
+                $bwd_jvp
+                let (a, b) = (self.a.value(), self.b.value());
+                let (a_tangent, b_tangent) = bwd_jvp(a, b, in_tangents[0], in_tangents[1],
+                    out_grad, out_grad_tangent);
+                vec![a_tangent.sum_to(&a.shape), b_tangent.sum_to(&b.shape)]
+            }
+        }
+
+        impl<T: Float> Node<T> {
+            fn $try_fn(&self, rhs: &Node<T>) -> Result<Node<T>, ShapeError> {
+                try_broadcast_shape($name, &self.value().shape, &rhs.value().shape)?;
+                let out = $trait::$fn(self.value(), rhs.value());
+                Ok(Node::new($res_name{a: self.clone(), b: rhs.clone(), out: out}))
+            }
+        }
+
+        impl<'a, 'b, T: Float> $trait<&'b Node<T>> for &'a Node<T> {
+            type Output = Node<T>;
+
+            fn $fn(self, rhs: &'b Node<T>) -> Node<T> {
+                self.$try_fn(rhs).unwrap_or_else(|e| panic!("{}", e))
+            }
+        }
+
+        impl<T: Float> $trait for Node<T> {
+            type Output = Node<T>;
+
+            fn $fn(self, rhs: Node<T>) -> Node<T> {
+                $trait::$fn(&self, &rhs)
+            }
+        }
+
+        impl<'a, T: Float> $trait<T> for &'a Node<T> {
+            type Output = Node<T>;
+
+            fn $fn(self, rhs: T) -> Node<T> {
+                let rhs: Node<T> = Node::new(Constant::new(vec![], rhs));
+                $trait::$fn(self, &rhs)
+            }
+        }
+
+        impl<T: Float> $trait<T> for Node<T> {
+            type Output = Node<T>;
+
+            fn $fn(self, rhs: T) -> Node<T> {
+                $trait::$fn(&self, rhs)
+            }
+        }
+    }
+}
+
+// Each backward function reduces its gradients with sum_to(),
+// since the operands may have been broadcast to a larger shape.
+
+define_op_res!("Add", Add, add, try_add, AddRes, |_, _, g| g, |_, _, g| g,
+    fn bwd<T: Float>(a: &Tensor<T>, b: &Tensor<T>, out_grad: &Tensor<T>) -> (Tensor<T>, Tensor<T>) {
+        (out_grad.sum_to(&a.shape), out_grad.sum_to(&b.shape))
+    },
+    fn jvp<T: Float>(_: &Tensor<T>, _: &Tensor<T>, da: &Tensor<T>, db: &Tensor<T>) -> Tensor<T> {
+        da + db
+    },
+    fn bwd_jvp<T: Float>(_: &Tensor<T>, _: &Tensor<T>, _: &Tensor<T>, _: &Tensor<T>, _: &Tensor<T>,
+               dg: &Tensor<T>) -> (Tensor<T>, Tensor<T>) {
+        (dg.clone(), dg.clone())
+    },
+    fn bwd_graph<T: Float>(_: &Node<T>, _: &Node<T>, g: &Node<T>) -> (Node<T>, Node<T>) {
+        (g.clone(), g.clone())
+    });
+
+define_op_res!("Mul", Mul, mul, try_mul, MulRes, |_, b, g| g * b, |a, _, g| g * a,
+    fn bwd<T: Float>(a: &Tensor<T>, b: &Tensor<T>, out_grad: &Tensor<T>) -> (Tensor<T>, Tensor<T>) {
+        ((out_grad * b).sum_to(&a.shape), (out_grad * a).sum_to(&b.shape))
+    },
+    fn jvp<T: Float>(a: &Tensor<T>, b: &Tensor<T>, da: &Tensor<T>, db: &Tensor<T>) -> Tensor<T> {
+        &(da * b) + &(a * db)
+    },
+    fn bwd_jvp<T: Float>(a: &Tensor<T>, b: &Tensor<T>, da: &Tensor<T>, db: &Tensor<T>,
+               g: &Tensor<T>, dg: &Tensor<T>) -> (Tensor<T>, Tensor<T>) {
+        (&(dg * b) + &(g * db), &(dg * a) + &(g * da))
+    },
+    fn bwd_graph<T: Float>(a: &Node<T>, b: &Node<T>, g: &Node<T>) -> (Node<T>, Node<T>) {
+        (g * b, g * a)
+    });
+
+define_op_res!("Div", Div, div, try_div, DivRes, |_, b, g| g / b, |a, b, g| -g * a / (b * b),
+    fn bwd<T: Float>(a: &Tensor<T>, b: &Tensor<T>, out_grad: &Tensor<T>) -> (Tensor<T>, Tensor<T>) {
+        let b_grad = &(&(a * out_grad) * -T::one()) / &(b * b);
+        ((out_grad / b).sum_to(&a.shape), b_grad.sum_to(&b.shape))
+    },
+    fn jvp<T: Float>(a: &Tensor<T>, b: &Tensor<T>, da: &Tensor<T>, db: &Tensor<T>) -> Tensor<T> {
+        &(da / b) - &(&(a * db) / &(b * b))
+    },
+    fn bwd_jvp<T: Float>(a: &Tensor<T>, b: &Tensor<T>, da: &Tensor<T>, db: &Tensor<T>,
+               g: &Tensor<T>, dg: &Tensor<T>) -> (Tensor<T>, Tensor<T>) {
+        let b2 = b * b;
+        let a_tangent = &(dg / b) - &(&(g * db) / &b2);
+        let b_tangent = &(&(&(&(a * g) * db) * T::from_f64(2.0)) / &(&b2 * b)) -
+            &(&(&(da * g) + &(a * dg)) / &b2);
+        (a_tangent, b_tangent)
+    },
+    fn bwd_graph<T: Float>(a: &Node<T>, b: &Node<T>, g: &Node<T>) -> (Node<T>, Node<T>) {
+        (g / b, (&(a * g) / &(b * b)).scale(-T::one()))
+    });
+
+define_op_res!("Sub", Sub, sub, try_sub, SubRes, |_, _, g| g, |_, _, g| -g,
+    fn bwd<T: Float>(a: &Tensor<T>, b: &Tensor<T>, out_grad: &Tensor<T>) -> (Tensor<T>, Tensor<T>) {
+        (out_grad.sum_to(&a.shape), (out_grad * -T::one()).sum_to(&b.shape))
+    },
+    fn jvp<T: Float>(_: &Tensor<T>, _: &Tensor<T>, da: &Tensor<T>, db: &Tensor<T>) -> Tensor<T> {
+        da - db
+    },
+    fn bwd_jvp<T: Float>(_: &Tensor<T>, _: &Tensor<T>, _: &Tensor<T>, _: &Tensor<T>, _: &Tensor<T>,
+               dg: &Tensor<T>) -> (Tensor<T>, Tensor<T>) {
+        (dg.clone(), dg * -T::one())
+    },
+    fn bwd_graph<T: Float>(_: &Node<T>, _: &Node<T>, g: &Node<T>) -> (Node<T>, Node<T>) {
+        (g.clone(), g.scale(-T::one()))
+    });
+
+// A matrix product of two Res matrices.
+struct MatMulRes<T: Float> {
+    a: Node<T>,
+    b: Node<T>,
+    out: Tensor<T>
+}
+
+impl<T: Float> Res<T> for MatMulRes<T> {
+    fn value(&self) -> &Tensor<T> {
+        &self.out
+    }
+
+    fn into_value(self) -> Tensor<T> {
+        self.out
+    }
+
+    fn name(&self) -> String {
+        format!("MatMul<{}, {}>", self.a.name(), self.b.name())
+    }
+
+    fn op(&self) -> &'static str {
+        "MatMul"
+    }
+
+    fn inputs(&self) -> Vec<Node<T>> {
+        vec![self.a.clone(), self.b.clone()]
+    }
+
+    fn backward(&self, out_grad: &Tensor<T>) -> Vec<Tensor<T>> {
+        vec![
+            out_grad.matmul(&self.b.value().transpose()),
+            self.a.value().transpose().matmul(out_grad)
+        ]
+    }
+
+    fn backward_graph(&self, _: &Node<T>, out_grad: &Node<T>) -> Vec<Node<T>> {
+        vec![out_grad.matmul(&self.b.transpose()), self.a.transpose().matmul(out_grad)]
+    }
+
+    fn jvp(&self, in_tangents: &[&Tensor<T>]) -> Tensor<T> {
+        &in_tangents[0].matmul(self.b.value()) + &self.a.value().matmul(in_tangents[1])
+    }
+
+    fn backward_jvp(&self, in_tangents: &[&Tensor<T>], out_grad: &Tensor<T>,
+                    out_grad_tangent: &Tensor<T>) -> Vec<Tensor<T>> {
+        let (da, db) = (in_tangents[0], in_tangents[1]);
+        vec![
+            &out_grad_tangent.matmul(&self.b.value().transpose()) +
+                &out_grad.matmul(&db.transpose()),
+            &self.a.value().transpose().matmul(out_grad_tangent) +
+                &da.transpose().matmul(out_grad)
+        ]
+    }
+}
+
+impl<T: Float> Node<T> {
+    fn matmul(&self, rhs: &Node<T>) -> Node<T> {
+        self.try_matmul(rhs).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn try_matmul(&self, rhs: &Node<T>) -> Result<Node<T>, ShapeError> {
+        let (a, b) = (&self.value().shape, &rhs.value().shape);
+        if a.len() != 2 || b.len() != 2 {
+            return Err(ShapeError::new("MatMul", &[a, b], "both operands must be matrices"));
+        }
+        if a[1] != b[0] {
+            return Err(ShapeError::new("MatMul", &[a, b], "inner dimensions differ"));
+        }
+        let out = self.value().matmul(rhs.value());
+        Ok(Node::new(MatMulRes{a: self.clone(), b: rhs.clone(), out: out}))
+    }
+}
+
+// Swap the rows and columns of a Res matrix.
+struct TransposeRes<T: Float> {
+    input: Node<T>,
+    out: Tensor<T>
+}
+
+impl<T: Float> Res<T> for TransposeRes<T> {
+    fn value(&self) -> &Tensor<T> {
+        &self.out
+    }
+
+    fn into_value(self) -> Tensor<T> {
+        self.out
+    }
+
+    fn name(&self) -> String {
+        format!("Transpose<{}>", self.input.name())
+    }
+
+    fn op(&self) -> &'static str {
+        "Transpose"
+    }
+
+    fn inputs(&self) -> Vec<Node<T>> {
+        vec![self.input.clone()]
+    }
+
+    fn backward(&self, out_grad: &Tensor<T>) -> Vec<Tensor<T>> {
+        vec![out_grad.transpose()]
+    }
+
+    fn backward_graph(&self, _: &Node<T>, out_grad: &Node<T>) -> Vec<Node<T>> {
+        vec![out_grad.transpose()]
+    }
+
+    fn jvp(&self, in_tangents: &[&Tensor<T>]) -> Tensor<T> {
+        in_tangents[0].transpose()
+    }
+
+    fn backward_jvp(&self, _: &[&Tensor<T>], _: &Tensor<T>,
+                    out_grad_tangent: &Tensor<T>) -> Vec<Tensor<T>> {
+        self.backward(out_grad_tangent)
+    }
+}
+
+impl<T: Float> Node<T> {
+    fn transpose(&self) -> Node<T> {
+        self.try_transpose().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn try_transpose(&self) -> Result<Node<T>, ShapeError> {
+        if self.value().shape.len() != 2 {
+            let reason = "operand must be a matrix";
+            return Err(ShapeError::new("Transpose", &[&self.value().shape], reason));
+        }
+        let out = self.value().transpose();
+        Ok(Node::new(TransposeRes{input: self.clone(), out: out}))
+    }
+}
+
+// Define a Res that applies a scalar function elementwise.
+// The first and second derivatives are given in terms of
+// both the input x and the output y, since some derivatives
+// (e.g. for exp or tanh) are cheapest to compute from the
+// output. deriv_node is the first derivative again, built
+// from the x and y Nodes.
+macro_rules! define_unary_res {
+    ($name:expr, $res_name:tt, $fn:tt, $f:expr, $deriv:expr, $deriv2:expr, $deriv_node:expr) => {
+        struct $res_name<T: Float> {
+            input: Node<T>,
+            out: Tensor<T>
+        }
+
+        impl<T: Float> Res<T> for $res_name<T> {
+            fn value(&self) -> &Tensor<T> {
+                &self.out
+            }
+
+            fn into_value(self) -> Tensor<T> {
+                self.out
+            }
+
+            fn name(&self) -> String {
+                format!("{}<{}>", $name, self.input.name())
+            }
+
+            fn op(&self) -> &'static str {
+                $name
+            }
+
+            fn inputs(&self) -> Vec<Node<T>> {
+                vec![self.input.clone()]
+            }
+
+            fn backward(&self, out_grad: &Tensor<T>) -> Vec<Tensor<T>> {
+                let deriv: fn(T, T) -> T = $deriv;
+                let mut in_grad = out_grad.clone();
+                for i in 0..in_grad.data.len() {
+                    in_grad.data[i] *= deriv(self.input.value().data[i], self.out.data[i]);
+                }
+                vec![in_grad]
+            }
+
+            fn backward_into(&self, out_grad: &Tensor<T>, grads: &mut [Tensor<T>],
+                             inputs: &[usize]) {
+                let deriv: fn(T, T) -> T = $deriv;
+                let in_grad = &mut grads[inputs[0]];
+                for i in 0..in_grad.data.len() {
+                    let (x, y) = (self.input.value().data[i], self.out.data[i]);
+                    in_grad.data[i] += out_grad.data[i] * deriv(x, y);
+                }
+            }
+
+            fn backward_graph(&self, output: &Node<T>, out_grad: &Node<T>) -> Vec<Node<T>> {
+                let deriv: fn(&Node<T>, &Node<T>) -> Node<T> = $deriv_node;
+                vec![out_grad * &deriv(&self.input, output)]
+            }
+
+            fn jvp(&self, in_tangents: &[&Tensor<T>]) -> Tensor<T> {
+                let deriv: fn(T, T) -> T = $deriv;
+                let mut out = in_tangents[0].clone();
+                for i in 0..out.data.len() {
+                    out.data[i] *= deriv(self.input.value().data[i], self.out.data[i]);
+                }
+                out
+            }
+
+            fn backward_jvp(&self, in_tangents: &[&Tensor<T>], out_grad: &Tensor<T>,
+                            out_grad_tangent: &Tensor<T>) -> Vec<Tensor<T>> {
+                let deriv: fn(T, T) -> T = $deriv;
+                let deriv2: fn(T, T) -> T = $deriv2;
+                let mut res = Tensor::new(self.out.shape.clone());
+                for i in 0..res.data.len() {
+                    let (x, y) = (self.input.value().data[i], self.out.data[i]);
+                    res.data[i] = out_grad_tangent.data[i] * deriv(x, y) +
+                        out_grad.data[i] * deriv2(x, y) * in_tangents[0].data[i];
+                }
+                vec![res]
+            }
+        }
+
+        impl<T: Float> Node<T> {
+            fn $fn(&self) -> Node<T> {
+                let f: fn(T) -> T = $f;
+                let out = self.value().map(f);
+                Node::new($res_name{input: self.clone(), out: out})
+            }
+        }
+    }
+}
+
+define_unary_res!("Exp", ExpRes, exp, |x| x.exp(), |_, y| y, |_, y| y, |_, y| y.clone());
+define_unary_res!("Log", LogRes, log, |x| x.ln(), |x, _| T::one() / x, |x, _| -T::one() / (x * x),
+    |x, _| x.pow(-T::one()));
+define_unary_res!("Tanh", TanhRes, tanh, |x| x.tanh(), |_, y| T::one() - y * y,
+    |_, y| T::from_f64(-2.0) * y * (T::one() - y * y), |_, y| &Node::scalar(T::one()) - &(y * y));
+define_unary_res!("ReLU", ReLURes, relu, |x| if x > T::zero() { x } else { T::zero() },
+    |x, _| if x > T::zero() { T::one() } else { T::zero() }, |_, _| T::zero(),
+    |x, _| Node::new(Constant(x.value().map(|x| if x > T::zero() { T::one() } else { T::zero() }),
+        "step".to_string())));
+define_unary_res!("Sigmoid", SigmoidRes, sigmoid, |x| T::one() / (T::one() + (-x).exp()),
+    |_, y| y * (T::one() - y), |_, y| y * (T::one() - y) * (T::one() - T::from_f64(2.0) * y),
+    |_, y| y * &(&Node::scalar(T::one()) - y));
+
+// Raise every element to a constant power.
+struct PowRes<T: Float> {
+    input: Node<T>,
+    power: T,
+    out: Tensor<T>
+}
+
+impl<T: Float> Res<T> for PowRes<T> {
+    fn value(&self) -> &Tensor<T> {
+        &self.out
+    }
+
+    fn into_value(self) -> Tensor<T> {
+        self.out
+    }
+
+    fn name(&self) -> String {
+        format!("Pow<{}, {}>", self.input.name(), self.power)
+    }
+
+    fn op(&self) -> &'static str {
+        "Pow"
+    }
+
+    fn attrs(&self) -> Vec<(&'static str, f64)> {
+        vec![("power", self.power.to_f64())]
+    }
+
+    fn inputs(&self) -> Vec<Node<T>> {
+        vec![self.input.clone()]
+    }
+
+    fn backward(&self, out_grad: &Tensor<T>) -> Vec<Tensor<T>> {
+        let power = self.power;
+        let deriv = self.input.value().map(|x| power * x.powf(power - T::one()));
+        vec![out_grad * &deriv]
+    }
+
+    fn backward_graph(&self, _: &Node<T>, out_grad: &Node<T>) -> Vec<Node<T>> {
+        vec![(out_grad * &self.input.pow(self.power - T::one())).scale(self.power)]
+    }
+
+    fn jvp(&self, in_tangents: &[&Tensor<T>]) -> Tensor<T> {
+        let power = self.power;
+        in_tangents[0] * &self.input.value().map(|x| power * x.powf(power - T::one()))
+    }
+
+    fn backward_jvp(&self, in_tangents: &[&Tensor<T>], out_grad: &Tensor<T>,
+                    out_grad_tangent: &Tensor<T>) -> Vec<Tensor<T>> {
+        let power = self.power;
+        let deriv = self.input.value().map(|x| power * x.powf(power - T::one()));
+        let deriv2 = self.input.value()
+            .map(|x| power * (power - T::one()) * x.powf(power - T::from_f64(2.0)));
+        vec![&(out_grad_tangent * &deriv) + &(&(out_grad * &deriv2) * in_tangents[0])]
+    }
+}
+
+impl<T: Float> Node<T> {
+    fn pow(&self, power: T) -> Node<T> {
+        let out = self.value().map(|x| x.powf(power));
+        Node::new(PowRes{input: self.clone(), power: power, out: out})
+    }
+
+    // A scalar Constant, which broadcasts against any shape.
+    fn scalar(value: T) -> Node<T> {
+        Node::new(Constant::new(vec![], value))
+    }
+
+    fn scale(&self, factor: T) -> Node<T> {
+        self * &Node::scalar(factor)
+    }
+}
+
+// Sum a Res along an axis, or over all of its elements if
+// the axis is None.
+struct SumRes<T: Float> {
+    input: Node<T>,
+    axis: Option<usize>,
+    out: Tensor<T>
+}
+
+impl<T: Float> Res<T> for SumRes<T> {
+    fn value(&self) -> &Tensor<T> {
+        &self.out
+    }
+
+    fn into_value(self) -> Tensor<T> {
+        self.out
+    }
+
+    fn name(&self) -> String {
+        match self.axis {
+            Some(axis) => format!("Sum<{}, {}>", self.input.name(), axis),
+            None => format!("Sum<{}>", self.input.name())
+        }
+    }
+
+    fn op(&self) -> &'static str {
+        "Sum"
+    }
+
+    fn attrs(&self) -> Vec<(&'static str, f64)> {
+        match self.axis {
+            Some(axis) => vec![("axis", axis as f64)],
+            None => Vec::new()
+        }
+    }
+
+    fn inputs(&self) -> Vec<Node<T>> {
+        vec![self.input.clone()]
+    }
+
+    fn backward(&self, out_grad: &Tensor<T>) -> Vec<Tensor<T>> {
+        vec![match self.axis {
+            Some(axis) => out_grad.expand_axis(axis, self.input.value().shape[axis]),
+            None => out_grad.broadcast_to(&self.input.value().shape)
+        }]
+    }
+
+    fn jvp(&self, in_tangents: &[&Tensor<T>]) -> Tensor<T> {
+        match self.axis {
+            Some(axis) => in_tangents[0].sum_axis(axis),
+            None => in_tangents[0].sum_to(&[])
+        }
+    }
+
+    // Since the backward pass is linear, its tangent is just
+    // the backward pass applied to the out_grad tangent.
+    fn backward_jvp(&self, _: &[&Tensor<T>], _: &Tensor<T>,
+                    out_grad_tangent: &Tensor<T>) -> Vec<Tensor<T>> {
+        self.backward(out_grad_tangent)
+    }
+
+    fn backward_graph(&self, output: &Node<T>, out_grad: &Node<T>) -> Vec<Node<T>> {
+        vec![output.linear_vjp(out_grad)]
+    }
+}
+
+// Average a Res along an axis, or over all of its elements
+// if the axis is None.
+struct MeanRes<T: Float> {
+    input: Node<T>,
+    axis: Option<usize>,
+    out: Tensor<T>
+}
+
+impl<T: Float> MeanRes<T> {
+    fn count(&self) -> T {
+        match self.axis {
+            Some(axis) => T::from_f64(self.input.value().shape[axis] as f64),
+            None => T::from_f64(self.input.value().data.len() as f64)
+        }
+    }
+}
+
+impl<T: Float> Res<T> for MeanRes<T> {
+    fn value(&self) -> &Tensor<T> {
+        &self.out
+    }
+
+    fn into_value(self) -> Tensor<T> {
+        self.out
+    }
+
+    fn name(&self) -> String {
+        match self.axis {
+            Some(axis) => format!("Mean<{}, {}>", self.input.name(), axis),
+            None => format!("Mean<{}>", self.input.name())
+        }
+    }
+
+    fn op(&self) -> &'static str {
+        "Mean"
+    }
+
+    fn attrs(&self) -> Vec<(&'static str, f64)> {
+        match self.axis {
+            Some(axis) => vec![("axis", axis as f64)],
+            None => Vec::new()
+        }
+    }
+
+    fn inputs(&self) -> Vec<Node<T>> {
+        vec![self.input.clone()]
+    }
+
+    fn backward(&self, out_grad: &Tensor<T>) -> Vec<Tensor<T>> {
+        let scaled = out_grad / self.count();
+        vec![match self.axis {
+            Some(axis) => scaled.expand_axis(axis, self.input.value().shape[axis]),
+            None => scaled.broadcast_to(&self.input.value().shape)
+        }]
+    }
+
+    fn jvp(&self, in_tangents: &[&Tensor<T>]) -> Tensor<T> {
+        let sum = match self.axis {
+            Some(axis) => in_tangents[0].sum_axis(axis),
+            None => in_tangents[0].sum_to(&[])
+        };
+        &sum / self.count()
+    }
+
+    fn backward_jvp(&self, _: &[&Tensor<T>], _: &Tensor<T>,
+                    out_grad_tangent: &Tensor<T>) -> Vec<Tensor<T>> {
+        self.backward(out_grad_tangent)
+    }
+
+    fn backward_graph(&self, output: &Node<T>, out_grad: &Node<T>) -> Vec<Node<T>> {
+        vec![output.linear_vjp(out_grad)]
+    }
+}
+
+// Take the maximum of a Res along an axis.
+// The gradient only flows to the first maximal element.
+struct MaxRes<T: Float> {
+    input: Node<T>,
+    axis: usize,
+    argmax: Vec<usize>,
+    out: Tensor<T>
+}
+
+impl<T: Float> Res<T> for MaxRes<T> {
+    fn value(&self) -> &Tensor<T> {
+        &self.out
+    }
+
+    fn into_value(self) -> Tensor<T> {
+        self.out
+    }
+
+    fn name(&self) -> String {
+        format!("Max<{}, {}>", self.input.name(), self.axis)
+    }
+
+    fn op(&self) -> &'static str {
+        "Max"
+    }
+
+    fn attrs(&self) -> Vec<(&'static str, f64)> {
+        vec![("axis", self.axis as f64)]
+    }
+
+    fn inputs(&self) -> Vec<Node<T>> {
+        vec![self.input.clone()]
+    }
+
+    fn backward(&self, out_grad: &Tensor<T>) -> Vec<Tensor<T>> {
+        let mut in_grad = Tensor::new(self.input.value().shape.clone());
+        let (_, size, inner) = in_grad.axis_layout(self.axis);
+        for (j, k) in self.argmax.iter().enumerate() {
+            in_grad.data[((j / inner) * size + k) * inner + j % inner] = out_grad.data[j];
+        }
+        vec![in_grad]
+    }
+
+    fn jvp(&self, in_tangents: &[&Tensor<T>]) -> Tensor<T> {
+        let mut out = Tensor::new(self.out.shape.clone());
+        let (_, size, inner) = in_tangents[0].axis_layout(self.axis);
+        for (j, k) in self.argmax.iter().enumerate() {
+            out.data[j] = in_tangents[0].data[((j / inner) * size + k) * inner + j % inner];
+        }
+        out
+    }
+
+    fn backward_jvp(&self, _: &[&Tensor<T>], _: &Tensor<T>,
+                    out_grad_tangent: &Tensor<T>) -> Vec<Tensor<T>> {
+        self.backward(out_grad_tangent)
+    }
+
+    fn backward_graph(&self, output: &Node<T>, out_grad: &Node<T>) -> Vec<Node<T>> {
+        vec![output.linear_vjp(out_grad)]
+    }
+}
+
+impl<T: Float> Node<T> {
+    fn check_axis(&self, op: &'static str, axis: usize) -> Result<(), ShapeError> {
+        let shape = &self.value().shape;
+        if axis >= shape.len() {
+            return Err(ShapeError::new(op, &[shape], format!("axis {} is out of range", axis)));
+        }
+        Ok(())
+    }
+
+    fn sum(&self, axis: usize) -> Node<T> {
+        self.try_sum(axis).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn try_sum(&self, axis: usize) -> Result<Node<T>, ShapeError> {
+        self.check_axis("Sum", axis)?;
+        let out = self.value().sum_axis(axis);
+        Ok(Node::new(SumRes{input: self.clone(), axis: Some(axis), out: out}))
+    }
+
+    fn sum_all(&self) -> Node<T> {
+        let mut out = Tensor::new(vec![]);
+        out.data[0] = self.value().data.iter().fold(T::zero(), |x, &y| x + y);
+        Node::new(SumRes{input: self.clone(), axis: None, out: out})
+    }
+
+    fn mean(&self, axis: usize) -> Node<T> {
+        self.try_mean(axis).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn try_mean(&self, axis: usize) -> Result<Node<T>, ShapeError> {
+        self.check_axis("Mean", axis)?;
+        let out = &self.value().sum_axis(axis) / T::from_f64(self.value().shape[axis] as f64);
+        Ok(Node::new(MeanRes{input: self.clone(), axis: Some(axis), out: out}))
+    }
+
+    fn mean_all(&self) -> Node<T> {
+        let mut out = Tensor::new(vec![]);
+        let count = T::from_f64(self.value().data.len() as f64);
+        out.data[0] = self.value().data.iter().fold(T::zero(), |x, &y| x + y) / count;
+        Node::new(MeanRes{input: self.clone(), axis: None, out: out})
+    }
+
+    fn max(&self, axis: usize) -> Node<T> {
+        self.try_max(axis).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn try_max(&self, axis: usize) -> Result<Node<T>, ShapeError> {
+        self.check_axis("Max", axis)?;
+        if self.value().shape[axis] == 0 {
+            let reason = format!("axis {} is empty", axis);
+            return Err(ShapeError::new("Max", &[&self.value().shape], reason));
+        }
+        let argmax = self.value().argmax(axis);
+        let out = self.value().max_axis(axis);
+        Ok(Node::new(MaxRes{input: self.clone(), axis: axis, argmax: argmax, out: out}))
+    }
+}
+
+// Sum a Res down to a shape that it was broadcast from, e.g.
+// to reduce the gradient of a broadcast operand.
+struct SumToRes<T: Float> {
+    input: Node<T>,
+    out: Tensor<T>
+}
+
+impl<T: Float> Res<T> for SumToRes<T> {
+    fn value(&self) -> &Tensor<T> {
+        &self.out
+    }
+
+    fn into_value(self) -> Tensor<T> {
+        self.out
+    }
+
+    fn name(&self) -> String {
+        format!("SumTo<{}, {:?}>", self.input.name(), self.out.shape)
+    }
+
+    fn op(&self) -> &'static str {
+        "SumTo"
+    }
+
+    fn inputs(&self) -> Vec<Node<T>> {
+        vec![self.input.clone()]
+    }
+
+    fn backward(&self, out_grad: &Tensor<T>) -> Vec<Tensor<T>> {
+        vec![out_grad.broadcast_to(&self.input.value().shape)]
+    }
+
+    fn jvp(&self, in_tangents: &[&Tensor<T>]) -> Tensor<T> {
+        in_tangents[0].sum_to(&self.out.shape)
+    }
+
+    fn backward_jvp(&self, _: &[&Tensor<T>], _: &Tensor<T>,
+                    out_grad_tangent: &Tensor<T>) -> Vec<Tensor<T>> {
+        self.backward(out_grad_tangent)
+    }
+
+    fn backward_graph(&self, output: &Node<T>, out_grad: &Node<T>) -> Vec<Node<T>> {
+        vec![output.linear_vjp(out_grad)]
+    }
+}
+
+impl<T: Float> Node<T> {
+    // Sum down to a shape, or do nothing if the Node already
+    // has it.
+    fn sum_to(&self, shape: &[usize]) -> Node<T> {
+        self.try_sum_to(shape).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn try_sum_to(&self, shape: &[usize]) -> Result<Node<T>, ShapeError> {
+        let in_shape = &self.value().shape;
+        if try_broadcast_shape("SumTo", shape, in_shape)? != *in_shape {
+            let reason = "shape doesn't broadcast to the input";
+            return Err(ShapeError::new("SumTo", &[in_shape, shape], reason));
+        }
+        if *in_shape == shape {
+            return Ok(self.clone());
+        }
+        let out = self.value().sum_to(shape);
+        Ok(Node::new(SumToRes{input: self.clone(), out: out}))
+    }
+}
+
+// The backward pass of a linear op with a single input, such
+// as Sum or MaxPool, as a Res of its out_grad.
+//
+// The backward pass of a linear op is linear too, and its
+// own backward pass is the op's forward pass (i.e. its
+// jvp), so a VjpRes and a JvpRes differentiate into each
+// other however many times they're differentiated.
+struct VjpRes<T: Float> {
+    op: Node<T>,
+    out_grad: Node<T>,
+    out: Tensor<T>
+}
+
+impl<T: Float> Res<T> for VjpRes<T> {
+    fn value(&self) -> &Tensor<T> {
+        &self.out
+    }
+
+    fn into_value(self) -> Tensor<T> {
+        self.out
+    }
+
+    fn name(&self) -> String {
+        format!("Vjp<{}, {}>", self.op.0.op(), self.out_grad.name())
+    }
+
+    fn op(&self) -> &'static str {
+        "Vjp"
+    }
+
+    fn inputs(&self) -> Vec<Node<T>> {
+        vec![self.out_grad.clone()]
+    }
+
+    fn backward(&self, out_grad: &Tensor<T>) -> Vec<Tensor<T>> {
+        vec![self.op.0.jvp(&[out_grad])]
+    }
+
+    fn backward_graph(&self, _: &Node<T>, out_grad: &Node<T>) -> Vec<Node<T>> {
+        vec![self.op.linear_jvp(out_grad)]
+    }
+
+    fn jvp(&self, in_tangents: &[&Tensor<T>]) -> Tensor<T> {
+        self.op.0.backward(in_tangents[0]).remove(0)
+    }
+
+    fn backward_jvp(&self, _: &[&Tensor<T>], _: &Tensor<T>,
+                    out_grad_tangent: &Tensor<T>) -> Vec<Tensor<T>> {
+        self.backward(out_grad_tangent)
+    }
+}
+
+// The forward pass of a linear op with a single input,
+// applied to another Res (see VjpRes).
+struct JvpRes<T: Float> {
+    op: Node<T>,
+    tangent: Node<T>,
+    out: Tensor<T>
+}
+
+impl<T: Float> Res<T> for JvpRes<T> {
+    fn value(&self) -> &Tensor<T> {
+        &self.out
+    }
+
+    fn into_value(self) -> Tensor<T> {
+        self.out
+    }
+
+    fn name(&self) -> String {
+        format!("Jvp<{}, {}>", self.op.0.op(), self.tangent.name())
+    }
+
+    fn op(&self) -> &'static str {
+        "Jvp"
+    }
+
+    fn inputs(&self) -> Vec<Node<T>> {
+        vec![self.tangent.clone()]
+    }
+
+    fn backward(&self, out_grad: &Tensor<T>) -> Vec<Tensor<T>> {
+        self.op.0.backward(out_grad)
+    }
+
+    fn backward_graph(&self, _: &Node<T>, out_grad: &Node<T>) -> Vec<Node<T>> {
+        vec![self.op.linear_vjp(out_grad)]
+    }
+
+    fn jvp(&self, in_tangents: &[&Tensor<T>]) -> Tensor<T> {
+        self.op.0.jvp(in_tangents)
+    }
+
+    fn backward_jvp(&self, _: &[&Tensor<T>], _: &Tensor<T>,
+                    out_grad_tangent: &Tensor<T>) -> Vec<Tensor<T>> {
+        self.backward(out_grad_tangent)
+    }
+}
+
+impl<T: Float> Node<T> {
+    // Apply the backward pass of this linear op to out_grad.
+    fn linear_vjp(&self, out_grad: &Node<T>) -> Node<T> {
+        let out = self.0.backward(out_grad.value()).remove(0);
+        Node::new(VjpRes{op: self.clone(), out_grad: out_grad.clone(), out: out})
+    }
+
+    // Apply this linear op to a tangent.
+    fn linear_jvp(&self, tangent: &Node<T>) -> Node<T> {
+        let out = self.0.jvp(&[tangent.value()]);
+        Node::new(JvpRes{op: self.clone(), tangent: tangent.clone(), out: out})
+    }
+}
+
+// The geometry of a window sliding over the spatial axes of
+// a tensor. Inputs have the shape [batch, channels, length]
+// or [batch, channels, height, width]; 1D inputs are treated
+// as 2D inputs with a height of one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Window {
+    input: (usize, usize),
+    kernel: (usize, usize),
+    output: (usize, usize),
+    stride: (usize, usize),
+    padding: (usize, usize),
+    dilation: (usize, usize)
+}
+
+impl Window {
+    // Fit a window to the spatial axes of an input, or say
+    // why it doesn't fit.
+    fn new(input: &[usize], kernel: &[usize], stride: usize, padding: usize, dilation: usize)
+        -> Result<Window, String>
+    {
+        if stride == 0 || dilation == 0 {
+            return Err("stride and dilation must be positive".to_string());
+        }
+        let (input, kernel, stride, padding, dilation) = if input.len() == 1 {
+            ((1, input[0]), (1, kernel[0]), (1, stride), (0, padding), (1, dilation))
+        } else {
+            ((input[0], input[1]), (kernel[0], kernel[1]),
+                (stride, stride), (padding, padding), (dilation, dilation))
+        };
+        let out_size = |i: usize, k: usize, s: usize, p: usize, d: usize| {
+            if k == 0 {
+                return Err("window is empty".to_string());
+            }
+            let span = d * (k - 1) + 1;
+            if i + 2 * p < span {
+                return Err(format!("window of {} doesn't fit in {} with padding {}", span, i, p));
+            }
+            Ok((i + 2 * p - span) / s + 1)
+        };
+        Ok(Window{
+            input: input,
+            kernel: kernel,
+            output: (
+                out_size(input.0, kernel.0, stride.0, padding.0, dilation.0)?,
+                out_size(input.1, kernel.1, stride.1, padding.1, dilation.1)?
+            ),
+            stride: stride,
+            padding: padding,
+            dilation: dilation
+        })
+    }
+
+    // The shape of an output with the given leading axes,
+    // keeping the spatial rank of the input.
+    fn output_shape(&self, batch: usize, channels: usize, rank: usize) -> Vec<usize> {
+        if rank == 3 {
+            vec![batch, channels, self.output.1]
+        } else {
+            vec![batch, channels, self.output.0, self.output.1]
+        }
+    }
+
+    // Call f(input, kernel, output) with the flat spatial
+    // offsets of every input element under the window, for
+    // every output position. Padding is skipped.
+    fn for_each<F: FnMut(usize, usize, usize)>(&self, mut f: F) {
+        for oy in 0..self.output.0 {
+            for ox in 0..self.output.1 {
+                for ky in 0..self.kernel.0 {
+                    let iy = (oy * self.stride.0 + ky * self.dilation.0) as isize
+                        - self.padding.0 as isize;
+                    if iy < 0 || iy >= self.input.0 as isize {
+                        continue;
+                    }
+                    for kx in 0..self.kernel.1 {
+                        let ix = (ox * self.stride.1 + kx * self.dilation.1) as isize
+                            - self.padding.1 as isize;
+                        if ix < 0 || ix >= self.input.1 as isize {
+                            continue;
+                        }
+                        f(iy as usize * self.input.1 + ix as usize,
+                            ky * self.kernel.1 + kx,
+                            oy * self.output.1 + ox);
+                    }
+                }
+            }
+        }
+    }
+
+    fn input_size(&self) -> usize {
+        self.input.0 * self.input.1
+    }
+
+    fn kernel_size(&self) -> usize {
+        self.kernel.0 * self.kernel.1
+    }
+
+    fn output_size(&self) -> usize {
+        self.output.0 * self.output.1
+    }
+}
+
+// Cross-correlate an input of shape [batch, in_channels, ...]
+// with a kernel of shape [out_channels, in_channels, ...],
+// like the convolution layers of most frameworks.
+struct ConvRes<T: Float> {
+    input: Node<T>,
+    kernel: Node<T>,
+    window: Window,
+    out: Tensor<T>
+}
+
+// Call f(input, kernel, output) with the flat indices of
+// every product that a convolution sums up. Since the output
+// is bilinear in the input and kernel, this is all that's
+// needed for the forward pass and both gradients.
+fn for_each_conv_term<F>(in_shape: &[usize], kernel_shape: &[usize], window: &Window, mut f: F)
+    where F: FnMut(usize, usize, usize)
+{
+    let (batch, in_channels, out_channels) = (in_shape[0], in_shape[1], kernel_shape[0]);
+    let (in_size, kernel_size) = (window.input_size(), window.kernel_size());
+    let out_size = window.output_size();
+    for b in 0..batch {
+        for o in 0..out_channels {
+            for c in 0..in_channels {
+                let in_base = (b * in_channels + c) * in_size;
+                let kernel_base = (o * in_channels + c) * kernel_size;
+                let out_base = (b * out_channels + o) * out_size;
+                window.for_each(|i, k, j| f(in_base + i, kernel_base + k, out_base + j));
+            }
+        }
+    }
+}
+
+fn conv_forward<T: Float>(input: &Tensor<T>, kernel: &Tensor<T>, window: &Window) -> Tensor<T> {
+    let shape = window.output_shape(input.shape[0], kernel.shape[0], input.shape.len());
+    let mut out = Tensor::new(shape);
+    for_each_conv_term(&input.shape, &kernel.shape, window, |i, k, j| {
+        out.data[j] += input.data[i] * kernel.data[k];
+    });
+    out
+}
+
+fn conv_input_grad<T: Float>(out_grad: &Tensor<T>, kernel: &Tensor<T>, in_shape: &[usize],
+                             window: &Window) -> Tensor<T> {
+    let mut in_grad = Tensor::new(in_shape.to_vec());
+    for_each_conv_term(in_shape, &kernel.shape, window, |i, k, j| {
+        in_grad.data[i] += out_grad.data[j] * kernel.data[k];
+    });
+    in_grad
+}
+
+fn conv_kernel_grad<T: Float>(input: &Tensor<T>, out_grad: &Tensor<T>, kernel_shape: &[usize],
+                              window: &Window) -> Tensor<T> {
+    let mut kernel_grad = Tensor::new(kernel_shape.to_vec());
+    for_each_conv_term(&input.shape, kernel_shape, window, |i, k, j| {
+        kernel_grad.data[k] += input.data[i] * out_grad.data[j];
+    });
+    kernel_grad
+}
+
+impl<T: Float> Res<T> for ConvRes<T> {
+    fn value(&self) -> &Tensor<T> {
+        &self.out
+    }
+
+    fn into_value(self) -> Tensor<T> {
+        self.out
+    }
+
+    fn name(&self) -> String {
+        format!("{}<{}, {}>", self.op(), self.input.name(), self.kernel.name())
+    }
+
+    fn op(&self) -> &'static str {
+        if self.input.value().shape.len() == 3 { "Conv1d" } else { "Conv2d" }
+    }
+
+    fn attrs(&self) -> Vec<(&'static str, f64)> {
+        vec![
+            ("stride", self.window.stride.1 as f64),
+            ("padding", self.window.padding.1 as f64),
+            ("dilation", self.window.dilation.1 as f64)
+        ]
+    }
+
+    fn inputs(&self) -> Vec<Node<T>> {
+        vec![self.input.clone(), self.kernel.clone()]
+    }
+
+    fn backward(&self, out_grad: &Tensor<T>) -> Vec<Tensor<T>> {
+        let (input, kernel) = (self.input.value(), self.kernel.value());
+        vec![
+            conv_input_grad(out_grad, kernel, &input.shape, &self.window),
+            conv_kernel_grad(input, out_grad, &kernel.shape, &self.window)
+        ]
+    }
+
+    fn jvp(&self, in_tangents: &[&Tensor<T>]) -> Tensor<T> {
+        let (input, kernel) = (self.input.value(), self.kernel.value());
+        &conv_forward(in_tangents[0], kernel, &self.window)
+            + &conv_forward(input, in_tangents[1], &self.window)
+    }
+
+    fn backward_jvp(&self, in_tangents: &[&Tensor<T>], out_grad: &Tensor<T>,
+                    out_grad_tangent: &Tensor<T>) -> Vec<Tensor<T>> {
+        let (input, kernel) = (self.input.value(), self.kernel.value());
+        let w = &self.window;
+        vec![
+            &conv_input_grad(out_grad_tangent, kernel, &input.shape, w)
+                + &conv_input_grad(out_grad, in_tangents[1], &input.shape, w),
+            &conv_kernel_grad(input, out_grad_tangent, &kernel.shape, w)
+                + &conv_kernel_grad(in_tangents[0], out_grad, &kernel.shape, w)
+        ]
+    }
+
+    fn backward_graph(&self, output: &Node<T>, out_grad: &Node<T>) -> Vec<Node<T>> {
+        let shapes = [self.input.value().shape.clone(), self.kernel.value().shape.clone(),
+            output.value().shape.clone()];
+        vec![
+            Node::conv_contract(0, (1, &self.kernel), (2, out_grad), &shapes, &self.window),
+            Node::conv_contract(1, (0, &self.input), (2, out_grad), &shapes, &self.window)
+        ]
+    }
+}
+
+// A convolution is a sum of products of an input, a kernel
+// and an output element (see for_each_conv_term), so its
+// gradients contract two of the three into the slot of the
+// third: slot 0 for the input, 1 for the kernel and 2 for
+// the output. Contracting into slot 2 is the convolution
+// itself.
+fn conv_contract<T: Float>(slot: usize, a: (usize, &Tensor<T>), b: (usize, &Tensor<T>),
+                           shapes: &[Vec<usize>; 3], window: &Window) -> Tensor<T> {
+    let mut out = Tensor::new(shapes[slot].clone());
+    for_each_conv_term(&shapes[0], &shapes[1], window, |i, k, j| {
+        let terms = [i, k, j];
+        out.data[terms[slot]] += a.1.data[terms[a.0]] * b.1.data[terms[b.0]];
+    });
+    out
+}
+
+// A contraction of two Res (see conv_contract), as built by
+// the backward pass of a convolution. It differentiates into
+// more contractions.
+struct ConvGradRes<T: Float> {
+    slot: usize,
+    a: (usize, Node<T>),
+    b: (usize, Node<T>),
+    shapes: [Vec<usize>; 3],
+    window: Window,
+    out: Tensor<T>
+}
+
+impl<T: Float> ConvGradRes<T> {
+    fn contract(&self, slot: usize, a: (usize, &Tensor<T>), b: (usize, &Tensor<T>)) -> Tensor<T> {
+        conv_contract(slot, a, b, &self.shapes, &self.window)
+    }
+}
+
+impl<T: Float> Res<T> for ConvGradRes<T> {
+    fn value(&self) -> &Tensor<T> {
+        &self.out
+    }
+
+    fn into_value(self) -> Tensor<T> {
+        self.out
+    }
+
+    fn name(&self) -> String {
+        format!("ConvGrad<{}, {}>", self.a.1.name(), self.b.1.name())
+    }
+
+    fn op(&self) -> &'static str {
+        "ConvGrad"
+    }
+
+    fn attrs(&self) -> Vec<(&'static str, f64)> {
+        vec![("slot", self.slot as f64)]
+    }
+
+    fn inputs(&self) -> Vec<Node<T>> {
+        vec![self.a.1.clone(), self.b.1.clone()]
+    }
+
+    fn backward(&self, out_grad: &Tensor<T>) -> Vec<Tensor<T>> {
+        let (a, b) = ((self.a.0, self.a.1.value()), (self.b.0, self.b.1.value()));
+        vec![
+            self.contract(a.0, (self.slot, out_grad), b),
+            self.contract(b.0, (self.slot, out_grad), a)
+        ]
+    }
+
+    fn backward_graph(&self, _: &Node<T>, out_grad: &Node<T>) -> Vec<Node<T>> {
+        let (a, b) = ((self.a.0, &self.a.1), (self.b.0, &self.b.1));
+        vec![
+            Node::conv_contract(a.0, (self.slot, out_grad), b, &self.shapes, &self.window),
+            Node::conv_contract(b.0, (self.slot, out_grad), a, &self.shapes, &self.window)
+        ]
+    }
+
+    fn jvp(&self, in_tangents: &[&Tensor<T>]) -> Tensor<T> {
+        let (a, b) = ((self.a.0, self.a.1.value()), (self.b.0, self.b.1.value()));
+        &self.contract(self.slot, (a.0, in_tangents[0]), b)
+            + &self.contract(self.slot, a, (b.0, in_tangents[1]))
+    }
+
+    fn backward_jvp(&self, in_tangents: &[&Tensor<T>], out_grad: &Tensor<T>,
+                    out_grad_tangent: &Tensor<T>) -> Vec<Tensor<T>> {
+        let (a, b) = ((self.a.0, self.a.1.value()), (self.b.0, self.b.1.value()));
+        let (da, db) = ((a.0, in_tangents[0]), (b.0, in_tangents[1]));
+        vec![
+            &self.contract(a.0, (self.slot, out_grad_tangent), b)
+                + &self.contract(a.0, (self.slot, out_grad), db),
+            &self.contract(b.0, (self.slot, out_grad_tangent), a)
+                + &self.contract(b.0, (self.slot, out_grad), da)
+        ]
+    }
+}
+
+impl<T: Float> Node<T> {
+    fn conv_contract(slot: usize, a: (usize, &Node<T>), b: (usize, &Node<T>),
+                     shapes: &[Vec<usize>; 3], window: &Window) -> Node<T> {
+        let out = conv_contract(slot, (a.0, a.1.value()), (b.0, b.1.value()), shapes, window);
+        Node::new(ConvGradRes{slot: slot, a: (a.0, a.1.clone()), b: (b.0, b.1.clone()),
+            shapes: shapes.clone(), window: *window, out: out})
+    }
+}
+
+// Call f(input, output) with the flat indices of every
+// input element under the pooling window of every output.
+fn for_each_pool_term<F: FnMut(usize, usize)>(in_shape: &[usize], window: &Window, mut f: F) {
+    let (in_size, out_size) = (window.input_size(), window.output_size());
+    for plane in 0..in_shape[0] * in_shape[1] {
+        window.for_each(|i, _, j| f(plane * in_size + i, plane * out_size + j));
+    }
+}
+
+// Average each channel over a sliding window.
+struct AvgPoolRes<T: Float> {
+    input: Node<T>,
+    window: Window,
+    out: Tensor<T>
+}
+
+impl<T: Float> AvgPoolRes<T> {
+    fn pool(input: &Tensor<T>, window: &Window) -> Tensor<T> {
+        let shape = window.output_shape(input.shape[0], input.shape[1], input.shape.len());
+        let mut out = Tensor::new(shape);
+        let scale = T::one() / T::from_f64(window.kernel_size() as f64);
+        for_each_pool_term(&input.shape, window, |i, j| out.data[j] += input.data[i] * scale);
+        out
+    }
+}
+
+impl<T: Float> Res<T> for AvgPoolRes<T> {
+    fn value(&self) -> &Tensor<T> {
+        &self.out
+    }
+
+    fn into_value(self) -> Tensor<T> {
+        self.out
+    }
+
+    fn name(&self) -> String {
+        format!("{}<{}>", self.op(), self.input.name())
+    }
+
+    fn op(&self) -> &'static str {
+        if self.input.value().shape.len() == 3 { "AvgPool1d" } else { "AvgPool2d" }
+    }
+
+    fn attrs(&self) -> Vec<(&'static str, f64)> {
+        vec![("size", self.window.kernel.1 as f64), ("stride", self.window.stride.1 as f64)]
+    }
+
+    fn inputs(&self) -> Vec<Node<T>> {
+        vec![self.input.clone()]
+    }
+
+    fn backward(&self, out_grad: &Tensor<T>) -> Vec<Tensor<T>> {
+        let shape = &self.input.value().shape;
+        let mut in_grad = Tensor::new(shape.clone());
+        let scale = T::one() / T::from_f64(self.window.kernel_size() as f64);
+        for_each_pool_term(shape, &self.window, |i, j| {
+            in_grad.data[i] += out_grad.data[j] * scale;
+        });
+        vec![in_grad]
+    }
+
+    fn jvp(&self, in_tangents: &[&Tensor<T>]) -> Tensor<T> {
+        AvgPoolRes::pool(in_tangents[0], &self.window)
+    }
+
+    fn backward_jvp(&self, _: &[&Tensor<T>], _: &Tensor<T>,
+                    out_grad_tangent: &Tensor<T>) -> Vec<Tensor<T>> {
+        self.backward(out_grad_tangent)
+    }
+
+    fn backward_graph(&self, output: &Node<T>, out_grad: &Node<T>) -> Vec<Node<T>> {
+        vec![output.linear_vjp(out_grad)]
+    }
+}
+
+// Take the maximum of each channel over a sliding window.
+// Like MaxRes, the gradient only flows to the first maximal
+// element of each window.
+struct MaxPoolRes<T: Float> {
+    input: Node<T>,
+    window: Window,
+    argmax: Vec<usize>,
+    out: Tensor<T>
+}
+
+impl<T: Float> Res<T> for MaxPoolRes<T> {
+    fn value(&self) -> &Tensor<T> {
+        &self.out
+    }
+
+    fn into_value(self) -> Tensor<T> {
+        self.out
+    }
+
+    fn name(&self) -> String {
+        format!("{}<{}>", self.op(), self.input.name())
+    }
+
+    fn op(&self) -> &'static str {
+        if self.input.value().shape.len() == 3 { "MaxPool1d" } else { "MaxPool2d" }
+    }
+
+    fn attrs(&self) -> Vec<(&'static str, f64)> {
+        vec![("size", self.window.kernel.1 as f64), ("stride", self.window.stride.1 as f64)]
+    }
+
+    fn inputs(&self) -> Vec<Node<T>> {
+        vec![self.input.clone()]
+    }
+
+    fn backward(&self, out_grad: &Tensor<T>) -> Vec<Tensor<T>> {
+        let mut in_grad = Tensor::new(self.input.value().shape.clone());
+        for (j, &i) in self.argmax.iter().enumerate() {
+            in_grad.data[i] += out_grad.data[j];
+        }
+        vec![in_grad]
+    }
+
+    fn jvp(&self, in_tangents: &[&Tensor<T>]) -> Tensor<T> {
+        let mut out = Tensor::new(self.out.shape.clone());
+        for (j, &i) in self.argmax.iter().enumerate() {
+            out.data[j] = in_tangents[0].data[i];
+        }
+        out
+    }
+
+    fn backward_jvp(&self, _: &[&Tensor<T>], _: &Tensor<T>,
+                    out_grad_tangent: &Tensor<T>) -> Vec<Tensor<T>> {
+        self.backward(out_grad_tangent)
+    }
+
+    fn backward_graph(&self, output: &Node<T>, out_grad: &Node<T>) -> Vec<Node<T>> {
+        vec![output.linear_vjp(out_grad)]
+    }
+}
+
+impl<T: Float> Node<T> {
+    // Check the shapes of a convolution with the given
+    // number of spatial axes, and build it.
+    fn try_conv(&self, op: &'static str, rank: usize, kernel: &Node<T>,
+                stride: usize, padding: usize, dilation: usize) -> Result<Node<T>, ShapeError> {
+        let (input_shape, kernel_shape) = (&self.value().shape, &kernel.value().shape);
+        let err = |reason: String| ShapeError::new(op, &[input_shape, kernel_shape], reason);
+        if input_shape.len() != rank + 2 || kernel_shape.len() != rank + 2 {
+            return Err(err(format!("input and kernel must have {} axes", rank + 2)));
+        }
+        if input_shape[1] != kernel_shape[1] {
+            return Err(err("input and kernel have different channels".to_string()));
+        }
+        let window = Window::new(&input_shape[2..], &kernel_shape[2..], stride, padding, dilation)
+            .map_err(&err)?;
+        let out = conv_forward(self.value(), kernel.value(), &window);
+        Ok(Node::new(ConvRes{input: self.clone(), kernel: kernel.clone(), window: window,
+            out: out}))
+    }
+
+    // Convolve a [batch, channels, length] input with a
+    // [out_channels, channels, size] kernel.
+    fn conv1d(&self, kernel: &Node<T>, stride: usize, padding: usize, dilation: usize) -> Node<T> {
+        self.try_conv1d(kernel, stride, padding, dilation).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn try_conv1d(&self, kernel: &Node<T>, stride: usize, padding: usize, dilation: usize)
+        -> Result<Node<T>, ShapeError>
+    {
+        self.try_conv("Conv1d", 1, kernel, stride, padding, dilation)
+    }
+
+    // Convolve a [batch, channels, height, width] input with
+    // an [out_channels, channels, height, width] kernel.
+    fn conv2d(&self, kernel: &Node<T>, stride: usize, padding: usize, dilation: usize) -> Node<T> {
+        self.try_conv2d(kernel, stride, padding, dilation).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn try_conv2d(&self, kernel: &Node<T>, stride: usize, padding: usize, dilation: usize)
+        -> Result<Node<T>, ShapeError>
+    {
+        self.try_conv("Conv2d", 2, kernel, stride, padding, dilation)
+    }
+
+    // The Window for pooling, with errors named after the 1D
+    // or 2D op in ops, like the op() of the pooling Res.
+    fn pool_window(&self, ops: (&'static str, &'static str), size: usize, stride: usize)
+        -> Result<Window, ShapeError>
+    {
+        let shape = &self.value().shape;
+        let op = if shape.len() == 3 { ops.0 } else { ops.1 };
+        if shape.len() != 3 && shape.len() != 4 {
+            return Err(ShapeError::new(op, &[shape], "input must have 3 or 4 axes"));
+        }
+        Window::new(&shape[2..], &vec![size; shape.len() - 2], stride, 0, 1)
+            .map_err(|reason| ShapeError::new(op, &[shape], reason))
+    }
+
+    // Average over size-wide windows (size x size in 2D) of
+    // the spatial axes, moving stride steps at a time.
+    fn avg_pool(&self, size: usize, stride: usize) -> Node<T> {
+        self.try_avg_pool(size, stride).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn try_avg_pool(&self, size: usize, stride: usize) -> Result<Node<T>, ShapeError> {
+        let window = self.pool_window(("AvgPool1d", "AvgPool2d"), size, stride)?;
+        let out = AvgPoolRes::pool(self.value(), &window);
+        Ok(Node::new(AvgPoolRes{input: self.clone(), window: window, out: out}))
+    }
+
+    fn max_pool(&self, size: usize, stride: usize) -> Node<T> {
+        self.try_max_pool(size, stride).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn try_max_pool(&self, size: usize, stride: usize) -> Result<Node<T>, ShapeError> {
+        let window = self.pool_window(("MaxPool1d", "MaxPool2d"), size, stride)?;
+        let input = self.value();
+        let shape = window.output_shape(input.shape[0], input.shape[1], input.shape.len());
+        let mut out = Tensor::new(shape);
+        let mut argmax = vec![usize::max_value(); out.data.len()];
+        for_each_pool_term(&input.shape, &window, |i, j| {
+            if argmax[j] == usize::max_value() || input.data[i] > out.data[j] {
+                out.data[j] = input.data[i];
+                argmax[j] = i;
+            }
+        });
+        Ok(Node::new(MaxPoolRes{input: self.clone(), window: window, argmax: argmax, out: out}))
+    }
+}
+
+struct Variable<T: Float> {
+    data: Tensor<T>,
+    name: String
+}
+
+impl<T: Float> Variable<T> {
+    fn new(name: String, value: Tensor<T>) -> Variable<T> {
+        Variable{
+            data: value,
+            name: name
+        }
+    }
+}
+
+impl<T: Float> Res<T> for Variable<T> {
+    fn value(&self) -> &Tensor<T> {
+        &self.data
+    }
+
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn op(&self) -> &'static str {
+        "Variable"
+    }
+
+    fn inputs(&self) -> Vec<Node<T>> {
+        Vec::new()
+    }
+
+    fn backward(&self, _: &Tensor<T>) -> Vec<Tensor<T>> {
+        Vec::new()
+    }
+
+    fn backward_graph(&self, _: &Node<T>, _: &Node<T>) -> Vec<Node<T>> {
+        Vec::new()
+    }
+
+    // The Tape seeds Variable tangents itself, so this is
+    // only used for Variables that are held fixed.
+    fn jvp(&self, _: &[&Tensor<T>]) -> Tensor<T> {
+        Tensor::new(self.data.shape.clone())
+    }
+
+    fn backward_jvp(&self, _: &[&Tensor<T>], _: &Tensor<T>, _: &Tensor<T>) -> Vec<Tensor<T>> {
+        Vec::new()
+    }
+
+    fn variable_name(&self) -> Option<String> {
+        Some(self.name.clone())
+    }
+
+    fn is_leaf(&self) -> bool {
+        true
+    }
+}
+
+struct Constant<T: Float = f32>(Tensor<T>, String);
+
+impl<T: Float> Constant<T> {
+    fn new(shape: Vec<usize>, value: T) -> Constant<T> {
+        let mut res = Constant(Tensor::new(shape), format!("{}", value));
+        for i in 0..res.0.data.len() {
+            res.0.data[i] = value;
+        }
+        res
+    }
+}
+
+impl<T: Float> Res<T> for Constant<T> {
+    fn value(&self) -> &Tensor<T> {
+        &self.0
+    }
+
+    fn name(&self) -> String {
+        self.1.clone()
+    }
+
+    fn op(&self) -> &'static str {
+        "Constant"
+    }
+
+    fn inputs(&self) -> Vec<Node<T>> {
+        Vec::new()
+    }
+
+    fn backward(&self, _: &Tensor<T>) -> Vec<Tensor<T>> {
+        Vec::new()
+    }
+
+    fn backward_graph(&self, _: &Node<T>, _: &Node<T>) -> Vec<Node<T>> {
+        Vec::new()
+    }
+
+    fn jvp(&self, _: &[&Tensor<T>]) -> Tensor<T> {
+        Tensor::new(self.0.shape.clone())
+    }
+
+    fn backward_jvp(&self, _: &[&Tensor<T>], _: &Tensor<T>, _: &Tensor<T>) -> Vec<Tensor<T>> {
+        Vec::new()
+    }
+
+    fn is_leaf(&self) -> bool {
+        true
+    }
+}
+
+// A set of named tensors, such as the weights of a model.
+//
+// Models are rebuilt from the Params on every step with
+// variable(), and an Optimizer updates the tensors in place
+// using the Gradient from the resulting graph.
+#[derive(Clone)]
+struct Params<T: Float = f32>(HashMap<String, Tensor<T>>);
+
+impl<T: Float> Params<T> {
+    fn new() -> Params<T> {
+        Params(HashMap::<String, Tensor<T>>::new())
+    }
+
+    fn insert(&mut self, name: &str, value: Tensor<T>) {
+        self.0.insert(name.to_string(), value);
+    }
+
+    fn get(&self, name: &str) -> &Tensor<T> {
+        match self.0.get(name) {
+            Some(value) => value,
+            None => panic!("no parameter named {}", name)
+        }
+    }
+
+    // Create a Variable holding the current value of a
+    // parameter, so that its gradient uses the same name.
+    fn variable(&self, name: &str) -> Node<T> {
+        Node::new(Variable::new(name.to_string(), self.get(name).clone()))
+    }
+
+    // Get the names in sorted order, so that saved files
+    // don't depend on the order of the HashMap.
+    fn names(&self) -> Vec<&String> {
+        let mut names: Vec<&String> = self.0.keys().collect();
+        names.sort();
+        names
+    }
+
+    // Write the parameters as a JSON object that maps each
+    // name to its shape and values, e.g.
+    //   {"bias":{"shape":[1],"value":[0.5]}}
+    fn to_json(&self) -> String {
+        let fields = self.names().into_iter().map(|name| {
+            let value = &self.0[name];
+            (name.clone(), Json::Object(vec![
+                ("shape".to_string(), Json::from_shape(&value.shape)),
+                ("value".to_string(), Json::from_floats(&value.data))
+            ]))
+        }).collect();
+        Json::Object(fields).to_string()
+    }
+
+    fn from_json(text: &str) -> Result<Params<T>, String> {
+        let mut res = Params::new();
+        match Json::parse(text)? {
+            Json::Object(fields) => {
+                for (name, entry) in fields {
+                    let value = Tensor{
+                        shape: entry.field("shape")?.to_shape()?,
+                        data: entry.field("value")?.to_floats()?
+                    };
+                    res.insert_checked(name, value)?;
+                }
+            },
+            doc => return Err(format!("expected object but got {}", doc))
+        }
+        Ok(res)
+    }
+
+    // Write the parameters in a binary format. All integers
+    // are little-endian:
+    //   magic     the 8 bytes "ADPARAMS"
+    //   version   u32, currently 1
+    //   width     u32, the size of each element in bytes
+    //             (4 for f32 or 8 for f64)
+    //   count     u32, the number of tensors
+    // Then for each tensor, sorted by name:
+    //   name      u32 length, followed by UTF-8 bytes
+    //   shape     u32 rank, followed by a u64 per dimension
+    //   data      the elements in row-major order, as IEEE
+    //             754 floats of the given width
+    fn to_bytes(&self) -> Vec<u8> {
+        let width = std::mem::size_of::<T>();
+        let mut res = PARAMS_MAGIC.to_vec();
+        res.extend_from_slice(&PARAMS_VERSION.to_le_bytes());
+        res.extend_from_slice(&(width as u32).to_le_bytes());
+        res.extend_from_slice(&(self.0.len() as u32).to_le_bytes());
+        for name in self.names() {
+            let value = &self.0[name];
+            res.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            res.extend_from_slice(name.as_bytes());
+            res.extend_from_slice(&(value.shape.len() as u32).to_le_bytes());
+            for &dim in &value.shape {
+                res.extend_from_slice(&(dim as u64).to_le_bytes());
+            }
+            for &x in &value.data {
+                if width == 4 {
+                    res.extend_from_slice(&(x.to_f64() as f32).to_bits().to_le_bytes());
+                } else {
+                    res.extend_from_slice(&x.to_f64().to_bits().to_le_bytes());
+                }
+            }
+        }
+        res
+    }
+
+    // Read the output of to_bytes(). Files written with
+    // either width can be loaded, converting the elements.
+    fn from_bytes(bytes: &[u8]) -> Result<Params<T>, String> {
+        let mut pos = 0;
+        if read_bytes(bytes, &mut pos, PARAMS_MAGIC.len())? != PARAMS_MAGIC {
+            return Err("not a parameter file".to_string());
+        }
+        let version = read_u32(bytes, &mut pos)?;
+        if version != PARAMS_VERSION {
+            return Err(format!("unsupported version {}", version));
+        }
+        let width = read_u32(bytes, &mut pos)?;
+        if width != 4 && width != 8 {
+            return Err(format!("unsupported element width {}", width));
+        }
+        let mut res = Params::new();
+        for _ in 0..read_u32(bytes, &mut pos)? {
+            let len = read_u32(bytes, &mut pos)? as usize;
+            let name = String::from_utf8(read_bytes(bytes, &mut pos, len)?.to_vec())
+                .map_err(|_| "name is not valid UTF-8".to_string())?;
+            let mut shape = Vec::<usize>::new();
+            for _ in 0..read_u32(bytes, &mut pos)? {
+                shape.push(read_u64(bytes, &mut pos)? as usize);
+            }
+            let size = checked_size(&shape)
+                .ok_or(format!("{}: shape {:?} is too large", name, shape))?;
+            if size > (bytes.len() - pos) / width as usize {
+                return Err(format!("{}: unexpected end of data", name));
+            }
+            let mut data = Vec::<T>::with_capacity(size);
+            for _ in 0..size {
+                data.push(T::from_f64(if width == 4 {
+                    f32::from_bits(read_u32(bytes, &mut pos)?) as f64
+                } else {
+                    f64::from_bits(read_u64(bytes, &mut pos)?)
+                }));
+            }
+            res.insert_checked(name, Tensor{data: data, shape: shape})?;
+        }
+        if pos != bytes.len() {
+            return Err(format!("unexpected trailing data at offset {}", pos));
+        }
+        Ok(res)
+    }
+
+    fn insert_checked(&mut self, name: String, value: Tensor<T>) -> Result<(), String> {
+        if checked_size(&value.shape) != Some(value.data.len()) {
+            return Err(format!("{}: {} values don't fit shape {:?}",
+                name, value.data.len(), value.shape));
+        }
+        if self.0.contains_key(&name) {
+            return Err(format!("{}: duplicate name", name));
+        }
+        self.0.insert(name, value);
+        Ok(())
+    }
+
+    // Save to a file, as JSON if the path ends with ".json"
+    // and in the binary format otherwise.
+    fn save(&self, path: &str) -> Result<(), String> {
+        let bytes = if path.ends_with(".json") {
+            self.to_json().into_bytes()
+        } else {
+            self.to_bytes()
+        };
+        std::fs::write(path, bytes).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    fn load(path: &str) -> Result<Params<T>, String> {
+        let bytes = std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        let res = if path.ends_with(".json") {
+            let text = String::from_utf8(bytes).map_err(|_| format!("{}: not valid UTF-8", path))?;
+            Params::from_json(&text)
+        } else {
+            Params::from_bytes(&bytes)
+        };
+        res.map_err(|e| format!("{}: {}", path, e))
+    }
+
+    // Replace the values of the parameters with a checkpoint,
+    // e.g. to resume training. The checkpoint must have the
+    // same names and shapes; otherwise nothing is changed.
+    fn restore(&mut self, checkpoint: Params<T>) -> Result<(), String> {
+        for name in self.names() {
+            match checkpoint.0.get(name) {
+                Some(value) if value.shape != self.0[name].shape =>
+                    return Err(format!("{}: expected shape {:?} but got {:?}",
+                        name, self.0[name].shape, value.shape)),
+                Some(_) => {},
+                None => return Err(format!("{}: missing from checkpoint", name))
+            }
+        }
+        let unknown = checkpoint.names().into_iter().find(|name| !self.0.contains_key(*name));
+        if let Some(name) = unknown {
+            return Err(format!("{}: not a parameter", name));
+        }
+        self.0 = checkpoint.0;
+        Ok(())
+    }
+}
+
+const PARAMS_MAGIC: &[u8] = b"ADPARAMS";
+const PARAMS_VERSION: u32 = 1;
+
+fn read_bytes<'a>(bytes: &'a [u8], pos: &mut usize, len: usize) -> Result<&'a [u8], String> {
+    if len > bytes.len() - *pos {
+        return Err(format!("unexpected end of data at offset {}", *pos));
+    }
+    *pos += len;
+    Ok(&bytes[*pos - len..*pos])
+}
+
+fn read_u32(bytes: &[u8], pos: &mut usize) -> Result<u32, String> {
+    let mut buf = [0u8; 4];
+    buf.copy_from_slice(read_bytes(bytes, pos, 4)?);
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(bytes: &[u8], pos: &mut usize) -> Result<u64, String> {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(read_bytes(bytes, pos, 8)?);
+    Ok(u64::from_le_bytes(buf))
+}
+
+// An algorithm that updates parameters using gradients.
+//
+// Parameters without an entry in the Gradient are left as
+// they are, and gradients for unknown names are ignored.
+trait Optimizer<T: Float = f32> {
+    fn step(&mut self, params: &mut Params<T>, grad: &Gradient<T>);
+}
+
+// Get the gradient of a parameter for an Optimizer, or None
+// if it has no gradient. A gradient of the wrong shape is a
+// bug in the model, so this panics, naming the parameter.
+fn param_grad<'a, T: Float>(grad: &'a Gradient<T>, name: &str, value: &Tensor<T>)
+                            -> Option<&'a Tensor<T>> {
+    let g = grad.0.get(name)?;
+    if g.shape != value.shape {
+        panic!("gradient of parameter {} has shape {:?} but the parameter has shape {:?}",
+            name, g.shape, value.shape);
+    }
+    Some(g)
+}
+
+// Plain stochastic gradient descent.
+struct SGD<T: Float = f32> {
+    lr: T
+}
+
+impl<T: Float> SGD<T> {
+    fn new(lr: T) -> SGD<T> {
+        SGD{lr: lr}
+    }
+}
+
+impl<T: Float> Optimizer<T> for SGD<T> {
+    fn step(&mut self, params: &mut Params<T>, grad: &Gradient<T>) {
+        for (name, value) in params.0.iter_mut() {
+            if let Some(g) = param_grad(grad, name, value) {
+                for i in 0..value.data.len() {
+                    value.data[i] -= self.lr * g.data[i];
+                }
+            }
+        }
+    }
+}
+
+// SGD with (heavy-ball) momentum.
+struct Momentum<T: Float = f32> {
+    lr: T,
+    momentum: T,
+    velocity: HashMap<String, Tensor<T>>
+}
+
+impl<T: Float> Momentum<T> {
+    fn new(lr: T, momentum: T) -> Momentum<T> {
+        Momentum{lr: lr, momentum: momentum, velocity: HashMap::<String, Tensor<T>>::new()}
+    }
+}
+
+impl<T: Float> Optimizer<T> for Momentum<T> {
+    fn step(&mut self, params: &mut Params<T>, grad: &Gradient<T>) {
+        for (name, value) in params.0.iter_mut() {
+            if let Some(g) = param_grad(grad, name, value) {
+                let v = self.velocity.entry(name.clone())
+                    .or_insert_with(|| Tensor::new(value.shape.clone()));
+                for i in 0..value.data.len() {
+                    v.data[i] = self.momentum * v.data[i] + g.data[i];
+                    value.data[i] -= self.lr * v.data[i];
+                }
+            }
+        }
+    }
+}
+
+// Adam (https://arxiv.org/abs/1412.6980).
+struct Adam<T: Float = f32> {
+    lr: T,
+    beta1: T,
+    beta2: T,
+    epsilon: T,
+    steps: i32,
+    first_moment: HashMap<String, Tensor<T>>,
+    second_moment: HashMap<String, Tensor<T>>
+}
+
+impl<T: Float> Adam<T> {
+    fn new(lr: T) -> Adam<T> {
+        Adam{
+            lr: lr,
+            beta1: T::from_f64(0.9),
+            beta2: T::from_f64(0.999),
+            epsilon: T::from_f64(1e-8),
+            steps: 0,
+            first_moment: HashMap::<String, Tensor<T>>::new(),
+            second_moment: HashMap::<String, Tensor<T>>::new()
+        }
+    }
+}
+
+impl<T: Float> Optimizer<T> for Adam<T> {
+    fn step(&mut self, params: &mut Params<T>, grad: &Gradient<T>) {
+        self.steps += 1;
+        let m_scale = T::one() / (T::one() - self.beta1.powi(self.steps));
+        let v_scale = T::one() / (T::one() - self.beta2.powi(self.steps));
+        for (name, value) in params.0.iter_mut() {
+            if let Some(g) = param_grad(grad, name, value) {
+                let m = self.first_moment.entry(name.clone())
+                    .or_insert_with(|| Tensor::new(value.shape.clone()));
+                let v = self.second_moment.entry(name.clone())
+                    .or_insert_with(|| Tensor::new(value.shape.clone()));
+                for i in 0..value.data.len() {
+                    m.data[i] = self.beta1 * m.data[i] + (T::one() - self.beta1) * g.data[i];
+                    v.data[i] = self.beta2 * v.data[i] +
+                        (T::one() - self.beta2) * g.data[i] * g.data[i];
+                    let step = (m.data[i] * m_scale) /
+                        ((v.data[i] * v_scale).sqrt() + self.epsilon);
+                    value.data[i] -= self.lr * step;
+                }
+            }
+        }
+    }
+}
+
+// A small xorshift random number generator, so that
+// initialization and tests don't need any crates.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        Rng(seed.wrapping_mul(0x9e3779b97f4a7c15) | 1)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    // Sample uniformly from [0, 1).
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    // Sample a tensor uniformly from [-1, 1).
+    fn tensor<T: Float>(&mut self, shape: Vec<usize>) -> Tensor<T> {
+        let mut res = Tensor::new(shape);
+        for i in 0..res.data.len() {
+            res.data[i] = T::from_f64(self.next_f64() * 2f64 - 1f64);
+        }
+        res
+    }
+}
+
+// A layer or model whose parameters live in Params. Each
+// module registers its parameters under its own name when
+// it's created, e.g. "hidden.weight", and forward() turns
+// them into Variables, so that Gradients, Optimizers and
+// checkpoints all use the same names.
+trait Module<T: Float = f32> {
+    fn forward(&self, params: &Params<T>, input: &Node<T>) -> Result<Node<T>, ShapeError>;
+}
+
+// A fully-connected layer mapping [batch, inputs] to
+// [batch, outputs].
+struct Linear {
+    weight: String,
+    bias: String
+}
+
+impl Linear {
+    // Register the weight, with Glorot uniform initialization,
+    // and a zero bias.
+    fn new<T: Float>(params: &mut Params<T>, rng: &mut Rng, name: &str, inputs: usize,
+                     outputs: usize) -> Linear {
+        let scale = T::from_f64((6f64 / (inputs + outputs) as f64).sqrt());
+        let layer = Linear{weight: format!("{}.weight", name), bias: format!("{}.bias", name)};
+        params.insert(&layer.weight, &rng.tensor(vec![inputs, outputs]) * scale);
+        params.insert(&layer.bias, Tensor::new(vec![outputs]));
+        layer
+    }
+}
+
+impl<T: Float> Module<T> for Linear {
+    fn forward(&self, params: &Params<T>, input: &Node<T>) -> Result<Node<T>, ShapeError> {
+        input.try_matmul(&params.variable(&self.weight))?.try_add(&params.variable(&self.bias))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Activation {
+    ReLU,
+    Tanh,
+    Sigmoid
+}
+
+impl Activation {
+    fn apply<T: Float>(&self, x: &Node<T>) -> Node<T> {
+        match *self {
+            Activation::ReLU => x.relu(),
+            Activation::Tanh => x.tanh(),
+            Activation::Sigmoid => x.sigmoid()
+        }
+    }
+}
+
+// A stack of Linear layers with an activation between each
+// pair. The output layer has no activation, so it produces
+// e.g. logits for softmax_cross_entropy().
+struct MLP {
+    layers: Vec<Linear>,
+    activation: Activation
+}
+
+impl MLP {
+    // Create layers named "{name}.0", "{name}.1", etc. that
+    // map sizes[0] inputs to sizes[sizes.len() - 1] outputs.
+    fn new<T: Float>(params: &mut Params<T>, rng: &mut Rng, name: &str, sizes: &[usize],
+                     activation: Activation) -> MLP {
+        let layers = (1..sizes.len())
+            .map(|i| {
+                let name = format!("{}.{}", name, i - 1);
+                Linear::new(params, rng, &name, sizes[i - 1], sizes[i])
+            })
+            .collect();
+        MLP{layers: layers, activation: activation}
+    }
+}
+
+impl<T: Float> Module<T> for MLP {
+    fn forward(&self, params: &Params<T>, input: &Node<T>) -> Result<Node<T>, ShapeError> {
+        let mut x = input.clone();
+        for (i, layer) in self.layers.iter().enumerate() {
+            x = layer.forward(params, &x)?;
+            if i + 1 < self.layers.len() {
+                x = self.activation.apply(&x);
+            }
+        }
+        Ok(x)
+    }
+}
+
+// The mean squared error between a prediction and a target
+// of the same (or a broadcastable) shape.
+fn mse_loss<T: Float>(pred: &Node<T>, target: &Node<T>) -> Result<Node<T>, ShapeError> {
+    Ok(pred.try_sub(target)?.pow(T::from_f64(2.0)).mean_all())
+}
+
+// The softmax cross-entropy of [batch, classes] logits with
+// integer class labels, averaged over the batch.
+fn cross_entropy_loss<T: Float>(logits: &Node<T>, labels: &[usize]) -> Result<Node<T>, ShapeError> {
+    let shape = &logits.value().shape;
+    if shape.len() != 2 || shape[0] != labels.len() {
+        return Err(ShapeError::new("SoftmaxCrossEntropy", &[shape, &[labels.len()]],
+            "expected [batch, classes] logits and a label per row"));
+    }
+    if let Some(label) = labels.iter().find(|&&label| label >= shape[1]) {
+        return Err(ShapeError::new("SoftmaxCrossEntropy", &[shape],
+            format!("label {} is out of range", label)));
+    }
+    let targets = Node::new(Constant(Tensor::one_hot(labels, shape[1]), "labels".to_string()));
+    logits.try_softmax_cross_entropy(&targets)
+}
+
+impl<T: Float> Tensor<T> {
+    // Encode class labels as rows of a [labels, classes]
+    // matrix, with a one in the column of each label.
+    fn one_hot(labels: &[usize], classes: usize) -> Tensor<T> {
+        let mut res = Tensor::new(vec![labels.len(), classes]);
+        for (i, &label) in labels.iter().enumerate() {
+            res.data[i * classes + label] = T::one();
+        }
+        res
+    }
+
+    // Compute log(softmax(x)) along each row of a matrix,
+    // subtracting the row maximum so that exp() can't
+    // overflow.
+    fn log_softmax_rows(&self) -> Tensor<T> {
+        let mut res = self.clone();
+        for row in res.data.chunks_mut(self.shape[1]) {
+            let max = row.iter().fold(row[0], |m, &x| m.max(x));
+            let sum = row.iter().fold(T::zero(), |s, &x| s + (x - max).exp());
+            let log_sum = max + sum.ln();
+            for x in row.iter_mut() {
+                *x = *x - log_sum;
+            }
+        }
+        res
+    }
+
+    // Take the dot product of each pair of rows.
+    fn row_dots(&self, rhs: &Tensor<T>) -> Vec<T> {
+        let classes = self.shape[1];
+        self.data.chunks(classes).zip(rhs.data.chunks(classes))
+            .map(|(a, b)| a.iter().zip(b).fold(T::zero(), |s, (&x, &y)| s + x * y))
+            .collect()
+    }
+
+    // Multiply each row of a matrix by a scalar.
+    fn scale_rows(&self, scales: &[T]) -> Tensor<T> {
+        let classes = self.shape[1];
+        let mut res = self.clone();
+        for (i, x) in res.data.iter_mut().enumerate() {
+            *x = *x * scales[i / classes];
+        }
+        res
+    }
+}
+
+// The cross-entropy of softmax(logits) with target
+// probabilities, averaged over the rows of the batch:
+//   -1/batch * sum(targets * log_softmax(logits))
+// Both inputs are [batch, classes] matrices. Targets are
+// usually one-hot, but can be any distribution, and are
+// differentiable too (e.g. for distillation).
+struct SoftmaxCrossEntropyRes<T: Float> {
+    logits: Node<T>,
+    targets: Node<T>,
+    log_probs: Tensor<T>,
+    out: Tensor<T>
+}
+
+impl<T: Float> SoftmaxCrossEntropyRes<T> {
+    fn batch_scale(&self) -> T {
+        T::one() / T::from_f64(self.log_probs.shape[0] as f64)
+    }
+
+    // The total probability of each row of the targets.
+    fn target_sums(targets: &Tensor<T>) -> Vec<T> {
+        targets.data.chunks(targets.shape[1])
+            .map(|row| row.iter().fold(T::zero(), |s, &x| s + x))
+            .collect()
+    }
+}
+
+impl<T: Float> Res<T> for SoftmaxCrossEntropyRes<T> {
+    fn value(&self) -> &Tensor<T> {
+        &self.out
+    }
+
+    fn into_value(self) -> Tensor<T> {
+        self.out
+    }
+
+    fn name(&self) -> String {
+        format!("SoftmaxCrossEntropy<{}, {}>", self.logits.name(), self.targets.name())
+    }
+
+    fn op(&self) -> &'static str {
+        "SoftmaxCrossEntropy"
+    }
+
+    fn inputs(&self) -> Vec<Node<T>> {
+        vec![self.logits.clone(), self.targets.clone()]
+    }
+
+    // The logit gradient is (sum(targets) * softmax - targets)
+    // for each row, and the target gradient is -log_softmax,
+    // both scaled by out_grad / batch.
+    fn backward(&self, out_grad: &Tensor<T>) -> Vec<Tensor<T>> {
+        let targets = self.targets.value();
+        let scale = out_grad.data[0] * self.batch_scale();
+        let probs = self.log_probs.map(|x| x.exp());
+        let sums = SoftmaxCrossEntropyRes::target_sums(targets);
+        let logits_grad = &probs.scale_rows(&sums) - targets;
+        vec![&logits_grad * scale, &self.log_probs * -scale]
+    }
+
+    fn jvp(&self, in_tangents: &[&Tensor<T>]) -> Tensor<T> {
+        let one = Tensor{shape: vec![], data: vec![T::one()]};
+        let grads = self.backward(&one);
+        let total = (&(&grads[0] * in_tangents[0]) + &(&grads[1] * in_tangents[1]))
+            .data.iter().fold(T::zero(), |s, &x| s + x);
+        Tensor{shape: vec![], data: vec![total]}
+    }
+
+    fn backward_jvp(&self, in_tangents: &[&Tensor<T>], out_grad: &Tensor<T>,
+                    out_grad_tangent: &Tensor<T>) -> Vec<Tensor<T>> {
+        let (logits_tangent, targets_tangent) = (in_tangents[0], in_tangents[1]);
+        let targets = self.targets.value();
+        let probs = self.log_probs.map(|x| x.exp());
+        let sums = SoftmaxCrossEntropyRes::target_sums(targets);
+        let sum_tangents = SoftmaxCrossEntropyRes::target_sums(targets_tangent);
+
+        // The tangent of log_softmax is the logit tangent minus
+        // its softmax-weighted mean.
+        let means: Vec<T> = probs.row_dots(logits_tangent).into_iter().map(|x| -x).collect();
+        let mut log_probs_tangent = logits_tangent.clone();
+        for (i, x) in log_probs_tangent.data.iter_mut().enumerate() {
+            *x += means[i / probs.shape[1]];
+        }
+        let probs_tangent = &probs * &log_probs_tangent;
+
+        let (scale, scale_tangent) = (out_grad.data[0] * self.batch_scale(),
+            out_grad_tangent.data[0] * self.batch_scale());
+        let logits_grad = &probs.scale_rows(&sums) - targets;
+        let probs_grad_tangent =
+            &probs.scale_rows(&sum_tangents) + &probs_tangent.scale_rows(&sums);
+        let logits_grad_tangent = &(&probs_grad_tangent - targets_tangent) * scale;
+        vec![
+            &(&logits_grad * scale_tangent) + &logits_grad_tangent,
+            &(&(&self.log_probs * scale_tangent) + &(&log_probs_tangent * scale)) * -T::one()
+        ]
+    }
+
+    // The same gradients as backward(), from Nodes. Row sums
+    // are taken by multiplying with a matrix of ones, which
+    // also broadcasts them back across each row. The row
+    // maximum only keeps exp() from overflowing, so it's
+    // held constant.
+    fn backward_graph(&self, _: &Node<T>, out_grad: &Node<T>) -> Vec<Node<T>> {
+        let (logits, targets) = (&self.logits, &self.targets);
+        let classes = self.log_probs.shape[1];
+        let ones = Node::new(Constant::new(vec![classes, classes], T::one()));
+        let max = logits.value().max_axis(1).reshape(vec![self.log_probs.shape[0], 1]);
+        let max = Node::new(Constant(max, "max".to_string()));
+        let shifted = logits - &max;
+        let log_probs = &shifted - &shifted.exp().matmul(&ones).log();
+        let scale = out_grad.scale(self.batch_scale());
+        let logits_grad = &(&log_probs.exp() * &targets.matmul(&ones)) - targets;
+        vec![&logits_grad * &scale, &log_probs * &scale.scale(-T::one())]
+    }
+}
+
+impl<T: Float> Node<T> {
+    fn softmax_cross_entropy(&self, targets: &Node<T>) -> Node<T> {
+        self.try_softmax_cross_entropy(targets).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn try_softmax_cross_entropy(&self, targets: &Node<T>) -> Result<Node<T>, ShapeError> {
+        let (logits_shape, targets_shape) = (&self.value().shape, &targets.value().shape);
+        if logits_shape.len() != 2 || logits_shape != targets_shape {
+            return Err(ShapeError::new("SoftmaxCrossEntropy", &[logits_shape, targets_shape],
+                "expected [batch, classes] logits and targets of the same shape"));
+        }
+        if logits_shape[0] == 0 || logits_shape[1] == 0 {
+            return Err(ShapeError::new("SoftmaxCrossEntropy", &[logits_shape, targets_shape],
+                "batch and classes must be non-empty"));
+        }
+        let log_probs = self.value().log_softmax_rows();
+        let total = (targets.value() * &log_probs).data.iter().fold(T::zero(), |s, &x| s + x);
+        let batch = T::from_f64(logits_shape[0] as f64);
+        let out = Tensor{shape: vec![], data: vec![-total / batch]};
+        Ok(Node::new(SoftmaxCrossEntropyRes{
+            logits: self.clone(),
+            targets: targets.clone(),
+            log_probs: log_probs,
+            out: out
+        }))
+    }
+}
+
+// Compare the gradients computed by the backward pass to
+// central finite differences, perturbing every element of
+// every parameter in turn.
+//
+// The function may produce any shape; its output is reduced
+// to a scalar by a dot product with fixed random weights so
+// that every output element contributes to the check.
+//
+// Returns a description of the first mismatch, if any.
+fn check_gradients<T: Float, F>(params: &Params<T>, f: F, epsilon: T, tolerance: T)
+    -> Result<(), String>
+    where F: Fn(&Params<T>) -> Node<T>
+{
+    let output = f(params);
+    let weights = Rng::new(1337).tensor(output.value().shape.clone());
+    let analytic = output.backward(&weights);
+    let objective = |p: &Params<T>| -> f64 {
+        let out = f(p);
+        let mut res = 0f64;
+        for i in 0..weights.data.len() {
+            res += out.value().data[i].to_f64() * weights.data[i].to_f64();
+        }
+        res
+    };
+
+    let mut names: Vec<&String> = params.0.keys().collect();
+    names.sort();
+    for name in names {
+        let size = params.get(name).data.len();
+        for i in 0..size {
+            let mut perturbed = params.clone();
+            let orig = perturbed.get(name).data[i];
+            perturbed.0.get_mut(name).expect("missing parameter").data[i] = orig + epsilon;
+            let plus = objective(&perturbed);
+            perturbed.0.get_mut(name).expect("missing parameter").data[i] = orig - epsilon;
+            let minus = objective(&perturbed);
+            let numerical = T::from_f64((plus - minus) / (2f64 * epsilon.to_f64()));
+            let actual = match analytic.0.get(name.as_str()) {
+                Some(grad) => grad.data[i],
+                None => T::zero()
+            };
+            let scale = T::one().max(actual.abs()).max(numerical.abs());
+            if (actual - numerical).abs() > tolerance * scale {
+                return Err(format!("{}[{}]: backward gave {} but finite differences gave {}",
+                    name, i, actual, numerical));
+            }
+        }
+    }
+    Ok(())
+}
+
+// A minimal JSON document, used for exporting graphs and
+// saving Params.
+#[derive(Clone, Debug, PartialEq)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    Str(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>)
+}
+
+impl Json {
+    fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            &Json::Object(ref fields) => {
+                fields.iter().find(|field| field.0 == key).map(|field| &field.1)
+            },
+            _ => None
+        }
+    }
+
+    fn field(&self, key: &str) -> Result<&Json, String> {
+        self.get(key).ok_or(format!("missing field: {}", key))
+    }
+
+    // Read a number, including the forms that from_float()
+    // writes for NaN and infinities.
+    fn as_f64(&self) -> Result<f64, String> {
+        match self {
+            &Json::Number(x) => Ok(x),
+            &Json::Null => Ok(std::f64::NAN),
+            &Json::Str(ref x) if x == "Infinity" => Ok(std::f64::INFINITY),
+            &Json::Str(ref x) if x == "-Infinity" => Ok(std::f64::NEG_INFINITY),
+            _ => Err(format!("expected number but got {}", self))
+        }
+    }
+
+    // Read a size or an index, which must be a whole number
+    // that fits in a usize.
+    fn as_usize(&self) -> Result<usize, String> {
+        match self {
+            &Json::Number(x) if x >= 0f64 && x == x.trunc() && x < usize::max_value() as f64 =>
+                Ok(x as usize),
+            _ => Err(format!("expected non-negative integer but got {}", self))
+        }
+    }
+
+    fn as_str(&self) -> Result<&str, String> {
+        match self {
+            &Json::Str(ref x) => Ok(x),
+            _ => Err(format!("expected string but got {}", self))
+        }
+    }
+
+    fn as_array(&self) -> Result<&Vec<Json>, String> {
+        match self {
+            &Json::Array(ref x) => Ok(x),
+            _ => Err(format!("expected array but got {}", self))
+        }
+    }
+
+    // Convert a float by way of its shortest decimal form,
+    // so that e.g. 0.2f32 isn't written as 0.20000000298023224.
+    // JSON has no infinities, so they're written as the
+    // strings "Infinity" and "-Infinity", and NaN as null.
+    fn from_float<T: Float>(x: T) -> Json {
+        match x.to_f64() {
+            x if x == std::f64::INFINITY => Json::Str("Infinity".to_string()),
+            x if x == std::f64::NEG_INFINITY => Json::Str("-Infinity".to_string()),
+            _ => Json::Number(format!("{}", x).parse::<f64>().unwrap_or(std::f64::NAN))
+        }
+    }
+
+    fn from_floats<T: Float>(data: &[T]) -> Json {
+        Json::Array(data.iter().map(|&x| Json::from_float(x)).collect())
+    }
+
+    fn from_shape(shape: &[usize]) -> Json {
+        Json::Array(shape.iter().map(|&x| Json::Number(x as f64)).collect())
+    }
+
+    fn to_floats<T: Float>(&self) -> Result<Vec<T>, String> {
+        self.as_array()?.iter().map(|x| x.as_f64().map(T::from_f64)).collect()
+    }
+
+    fn to_shape(&self) -> Result<Vec<usize>, String> {
+        self.as_array()?.iter().map(Json::as_usize).collect()
+    }
+
+    fn parse(text: &str) -> Result<Json, String> {
+        let chars: Vec<char> = text.chars().collect();
+        let mut pos = 0;
+        let res = parse_json_value(&chars, &mut pos)?;
+        skip_json_whitespace(&chars, &mut pos);
+        if pos != chars.len() {
+            return Err(format!("unexpected trailing data at offset {}", pos));
+        }
+        Ok(res)
+    }
+}
+
+impl std::fmt::Display for Json {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            &Json::Null => write!(f, "null"),
+            &Json::Bool(x) => write!(f, "{}", x),
+            // JSON has no NaN or infinity, so those become null.
+            &Json::Number(x) => if x.is_finite() { write!(f, "{}", x) } else { write!(f, "null") },
+            &Json::Str(ref x) => write_json_string(f, x),
+            &Json::Array(ref items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            },
+            &Json::Object(ref fields) => {
+                write!(f, "{{")?;
+                for (i, &(ref key, ref value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_json_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn write_json_string(f: &mut std::fmt::Formatter, x: &str) -> std::fmt::Result {
+    write!(f, "\"")?;
+    for c in x.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\t' => write!(f, "\\t")?,
+            '\r' => write!(f, "\\r")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?
+        }
+    }
+    write!(f, "\"")
+}
+
+fn skip_json_whitespace(chars: &[char], pos: &mut usize) {
+    while *pos < chars.len() && chars[*pos].is_whitespace() {
+        *pos += 1;
+    }
+}
+
+fn expect_json_char(chars: &[char], pos: &mut usize, c: char) -> Result<(), String> {
+    skip_json_whitespace(chars, pos);
+    if *pos < chars.len() && chars[*pos] == c {
+        *pos += 1;
+        Ok(())
+    } else {
+        Err(format!("expected '{}' at offset {}", c, pos))
+    }
+}
+
+// Read the 4 hex digits of a \u escape after pos, and move
+// pos to the last of them.
+fn parse_json_hex(chars: &[char], pos: &mut usize) -> Result<u32, String> {
+    if *pos + 4 >= chars.len() || !chars[*pos + 1..*pos + 5].iter().all(|c| c.is_digit(16)) {
+        return Err(format!("bad escape at offset {}", pos));
+    }
+    let hex: String = chars[*pos + 1..*pos + 5].iter().collect();
+    *pos += 4;
+    Ok(u32::from_str_radix(&hex, 16).unwrap())
+}
+
+fn parse_json_value(chars: &[char], pos: &mut usize) -> Result<Json, String> {
+    skip_json_whitespace(chars, pos);
+    if *pos >= chars.len() {
+        return Err("unexpected end of JSON".to_string());
+    }
+    match chars[*pos] {
+        '{' => {
+            *pos += 1;
+            let mut fields = Vec::<(String, Json)>::new();
+            skip_json_whitespace(chars, pos);
+            if *pos < chars.len() && chars[*pos] == '}' {
+                *pos += 1;
+                return Ok(Json::Object(fields));
+            }
+            loop {
+                skip_json_whitespace(chars, pos);
+                let key = match parse_json_value(chars, pos)? {
+                    Json::Str(key) => key,
+                    _ => return Err(format!("expected string key at offset {}", pos))
+                };
+                expect_json_char(chars, pos, ':')?;
+                fields.push((key, parse_json_value(chars, pos)?));
+                skip_json_whitespace(chars, pos);
+                if *pos < chars.len() && chars[*pos] == ',' {
+                    *pos += 1;
+                } else {
+                    expect_json_char(chars, pos, '}')?;
+                    return Ok(Json::Object(fields));
+                }
+            }
+        },
+        '[' => {
+            *pos += 1;
+            let mut items = Vec::<Json>::new();
+            skip_json_whitespace(chars, pos);
+            if *pos < chars.len() && chars[*pos] == ']' {
+                *pos += 1;
+                return Ok(Json::Array(items));
+            }
+            loop {
+                items.push(parse_json_value(chars, pos)?);
+                skip_json_whitespace(chars, pos);
+                if *pos < chars.len() && chars[*pos] == ',' {
+                    *pos += 1;
+                } else {
+                    expect_json_char(chars, pos, ']')?;
+                    return Ok(Json::Array(items));
+                }
+            }
+        },
+        '"' => {
+            *pos += 1;
+            let mut res = String::new();
+            while *pos < chars.len() && chars[*pos] != '"' {
+                if chars[*pos] == '\\' && *pos + 1 < chars.len() {
+                    *pos += 1;
+                    match chars[*pos] {
+                        'n' => res.push('\n'),
+                        't' => res.push('\t'),
+                        'r' => res.push('\r'),
+                        'b' => res.push('\u{8}'),
+                        'f' => res.push('\u{c}'),
+                        '"' | '\\' | '/' => res.push(chars[*pos]),
+                        'u' => {
+                            let mut code = parse_json_hex(chars, pos)?;
+                            // Characters outside the BMP are escaped
+                            // as a UTF-16 surrogate pair.
+                            if code >= 0xd800 && code < 0xdc00 && *pos + 6 < chars.len() &&
+                                    chars[*pos + 1] == '\\' && chars[*pos + 2] == 'u' {
+                                *pos += 2;
+                                let low = parse_json_hex(chars, pos)?;
+                                if low >= 0xdc00 && low < 0xe000 {
+                                    code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                                }
+                            }
+                            res.push(std::char::from_u32(code)
+                                .ok_or(format!("unpaired surrogate at offset {}", pos))?);
+                        },
+                        _ => return Err(format!("bad escape at offset {}", pos))
+                    }
+                } else {
+                    res.push(chars[*pos]);
+                }
+                *pos += 1;
+            }
+            expect_json_char(chars, pos, '"')?;
+            Ok(Json::Str(res))
+        },
+        _ => {
+            let start = *pos;
+            while *pos < chars.len() && (chars[*pos].is_alphanumeric() ||
+                    chars[*pos] == '-' || chars[*pos] == '+' || chars[*pos] == '.') {
+                *pos += 1;
+            }
+            let word: String = chars[start..*pos].iter().collect();
+            match word.as_str() {
+                "null" => Ok(Json::Null),
+                "true" => Ok(Json::Bool(true)),
+                "false" => Ok(Json::Bool(false)),
+                _ => word.parse::<f64>().map(Json::Number)
+                    .map_err(|_| format!("unexpected token {:?} at offset {}", word, start))
+            }
+        }
+    }
+}
+
+impl<T: Float> Tape<T> {
+    // Compute the gradient of every node on the tape.
+    fn node_gradients(&self, out_grad: &Tensor<T>) -> HashMap<usize, Tensor<T>> {
+        let mut res = HashMap::<usize, Tensor<T>>::new();
+        self.propagate(out_grad, |node, grad| {
+            res.insert(node.id(), grad.clone());
+        });
+        res
+    }
+
+    // Export the graph in the Graphviz DOT language.
+    //
+    // If values is set, each node is labeled with its value.
+    // If out_grad is given, it is back-propagated and each
+    // node is labeled with its gradient as well.
+    fn to_dot(&self, values: bool, out_grad: Option<&Tensor<T>>) -> String {
+        let grads = out_grad.map(|g| self.node_gradients(g));
+        let indices = self.indices();
+        let mut res = "digraph {\n".to_string();
+        for (i, node) in self.0.iter().enumerate() {
+            let mut label = match node.0.variable_name() {
+                Some(name) => format!("{} {}", node.0.op(), name),
+                None => node.0.op().to_string()
+            };
+            for (key, value) in node.0.attrs() {
+                label += &format!(" {}={}", key, value);
+            }
+            label += &format!("\\nshape={:?}", node.value().shape);
+            if values {
+                label += &format!("\\nvalue={:?}", node.value().data);
+            }
+            if let Some(ref grads) = grads {
+                if let Some(grad) = grads.get(&node.id()) {
+                    label += &format!("\\ngrad={:?}", grad.data);
+                }
+            }
+            res += &format!("  n{} [label=\"{}\"];\n", i, label.replace('"', "\\\""));
+            for input in node.0.inputs() {
+                res += &format!("  n{} -> n{};\n", indices[&input.id()], i);
+            }
+        }
+        res + "}\n"
+    }
+
+    // Export the graph as JSON, with one entry per node in
+    // topological order. Inputs refer to earlier entries by
+    // index, and the output is the last entry.
+    //
+    // Variables and Constants always include their values so
+    // that the graph can be rebuilt with from_json(). Other
+    // values, and gradients, are included as in to_dot().
+    //
+    // Vjp, Jvp and ConvGrad depend on more than their inputs
+    // and attrs (the linear op of a Vjp or Jvp, the Window of
+    // a ConvGrad), so graphs with them can't be exported.
+    fn to_json(&self, values: bool, out_grad: Option<&Tensor<T>>) -> Result<String, String> {
+        let grads = out_grad.map(|g| self.node_gradients(g));
+        let indices = self.indices();
+        let mut nodes = Vec::<Json>::new();
+        for node in &self.0 {
+            if let "Vjp" | "Jvp" | "ConvGrad" = node.0.op() {
+                return Err(format!("cannot save op {}", node.0.op()));
+            }
+            let mut fields = vec![("op".to_string(), Json::Str(node.0.op().to_string()))];
+            let inputs = node.0.inputs();
+            if inputs.len() == 0 {
+                fields.push(("name".to_string(), Json::Str(node.name())));
+            }
+            for (key, value) in node.0.attrs() {
+                fields.push((key.to_string(), Json::from_float(value)));
+            }
+            let input_indices = inputs.iter()
+                .map(|x| Json::Number(indices[&x.id()] as f64))
+                .collect();
+            fields.push(("inputs".to_string(), Json::Array(input_indices)));
+            fields.push(("shape".to_string(), Json::from_shape(&node.value().shape)));
+            if values || inputs.len() == 0 {
+                fields.push(("value".to_string(), Json::from_floats(&node.value().data)));
+            }
+            if let Some(ref grads) = grads {
+                if let Some(grad) = grads.get(&node.id()) {
+                    fields.push(("grad".to_string(), Json::from_floats(&grad.data)));
+                }
+            }
+            nodes.push(Json::Object(fields));
+        }
+        Ok(Json::Object(vec![("nodes".to_string(), Json::Array(nodes))]).to_string())
+    }
+
+    // Rebuild a graph from the output of to_json().
+    // Only the built-in operations can be loaded.
+    fn from_json(text: &str) -> Result<Tape<T>, String> {
+        let doc = Json::parse(text)?;
+        let mut nodes = Vec::<Node<T>>::new();
+        for (i, entry) in doc.field("nodes")?.as_array()?.iter().enumerate() {
+            let op = entry.field("op")?.as_str()?;
+            let mut inputs = Vec::<Node<T>>::new();
+            for index in entry.field("inputs")?.as_array()? {
+                let index = index.as_usize()?;
+                if index >= i {
+                    return Err(format!("node {} has invalid input {}", i, index));
+                }
+                inputs.push(nodes[index].clone());
+            }
+            let axis = match entry.get("axis") {
+                Some(axis) => Some(axis.as_usize()?),
+                None => None
+            };
+            let usize_attr = |name: &str| -> Result<usize, String> {
+                entry.field(name)?.as_usize()
+            };
+            let node = match (op, inputs.len()) {
+                ("Variable", 0) | ("Constant", 0) => {
+                    let value = Tensor{
+                        shape: entry.field("shape")?.to_shape()?,
+                        data: entry.field("value")?.to_floats()?
+                    };
+                    if checked_size(&value.shape) != Some(value.data.len()) {
+                        return Err(format!("node {} has the wrong number of values", i));
+                    }
+                    let name = entry.field("name")?.as_str()?.to_string();
+                    if op == "Variable" {
+                        Node::new(Variable::new(name, value))
+                    } else {
+                        Node::new(Constant(value, name))
+                    }
+                },
+                ("Add", 2) => inputs[0].try_add(&inputs[1])?,
+                ("Sub", 2) => inputs[0].try_sub(&inputs[1])?,
+                ("Mul", 2) => inputs[0].try_mul(&inputs[1])?,
+                ("Div", 2) => inputs[0].try_div(&inputs[1])?,
+                ("MatMul", 2) => inputs[0].try_matmul(&inputs[1])?,
+                ("Transpose", 1) => inputs[0].try_transpose()?,
+                ("Exp", 1) => inputs[0].exp(),
+                ("Log", 1) => inputs[0].log(),
+                ("Tanh", 1) => inputs[0].tanh(),
+                ("ReLU", 1) => inputs[0].relu(),
+                ("Sigmoid", 1) => inputs[0].sigmoid(),
+                ("Pow", 1) => inputs[0].pow(T::from_f64(entry.field("power")?.as_f64()?)),
+                ("Sum", 1) => match axis {
+                    Some(axis) => inputs[0].try_sum(axis)?,
+                    None => inputs[0].sum_all()
+                },
+                ("Mean", 1) => match axis {
+                    Some(axis) => inputs[0].try_mean(axis)?,
+                    None => inputs[0].mean_all()
+                },
+                ("Max", 1) => inputs[0].try_max(axis.ok_or("missing field: axis")?)?,
+                ("SumTo", 1) => inputs[0].try_sum_to(&entry.field("shape")?.to_shape()?)?,
+                ("Conv1d", 2) => inputs[0].try_conv1d(&inputs[1],
+                    usize_attr("stride")?, usize_attr("padding")?, usize_attr("dilation")?)?,
+                ("Conv2d", 2) => inputs[0].try_conv2d(&inputs[1],
+                    usize_attr("stride")?, usize_attr("padding")?, usize_attr("dilation")?)?,
+                ("AvgPool1d", 1) | ("AvgPool2d", 1) =>
+                    inputs[0].try_avg_pool(usize_attr("size")?, usize_attr("stride")?)?,
+                ("MaxPool1d", 1) | ("MaxPool2d", 1) =>
+                    inputs[0].try_max_pool(usize_attr("size")?, usize_attr("stride")?)?,
+                ("SoftmaxCrossEntropy", 2) => inputs[0].try_softmax_cross_entropy(&inputs[1])?,
+                _ => return Err(format!("cannot load op {} with {} inputs", op, inputs.len()))
+            };
+            nodes.push(node);
+        }
+        Ok(Tape(nodes))
+    }
+
+    // Map node ids to their positions on the tape.
+    fn indices(&self) -> HashMap<usize, usize> {
+        self.0.iter().enumerate().map(|(i, node)| (node.id(), i)).collect()
+    }
+}
+
+impl<T: Float> Node<T> {
+    // Approximate sin(x) with the first three terms of its
+    // Taylor series.
+    fn sin_taylor(&self) -> Node<T> {
+        let x2 = self * self;
+        let x3 = &x2 * self;
+        let x5 = &x3 * &x2;
+        &(self - &(x3 / T::from_f64(6.0))) + &(x5 / T::from_f64(120.0))
+    }
+}
+
+// Wrap the system allocator to count allocations, so that
+// the bench example can report them. This slows down every
+// allocation in the program, so it's only built with
+// `rustc --cfg 'feature="count-allocations"'`.
+#[cfg(feature = "count-allocations")]
+mod counting {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct CountingAllocator;
+
+    static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+    static LIVE_BYTES: AtomicUsize = AtomicUsize::new(0);
+    static PEAK_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+            let live = LIVE_BYTES.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK_BYTES.fetch_max(live, Ordering::Relaxed);
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            LIVE_BYTES.fetch_sub(layout.size(), Ordering::Relaxed);
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    // The allocations so far and the live bytes, to pass to
+    // since(). This also resets the peak.
+    pub fn start() -> (usize, usize) {
+        let live = LIVE_BYTES.load(Ordering::Relaxed);
+        PEAK_BYTES.store(live, Ordering::Relaxed);
+        (ALLOCATIONS.load(Ordering::Relaxed), live)
+    }
+
+    // How many allocations were made since start(), and how
+    // far the heap grew.
+    pub fn since(start: (usize, usize)) -> Option<(usize, usize)> {
+        let (allocations, live) = start;
+        let peak = PEAK_BYTES.load(Ordering::Relaxed) - live;
+        Some((ALLOCATIONS.load(Ordering::Relaxed) - allocations, peak))
+    }
+}
+
+#[cfg(not(feature = "count-allocations"))]
+mod counting {
+    pub fn start() -> (usize, usize) {
+        (0, 0)
+    }
+
+    pub fn since(_: (usize, usize)) -> Option<(usize, usize)> {
+        None
+    }
+}
+
+fn main() {
+    let example = std::env::args().nth(1).unwrap_or("derivatives".to_string());
+    let result = match example.as_str() {
+        "derivatives" => derivatives_example(),
+        "regression" => regression_example(),
+        "graph" => graph_example(),
+        "conv" => conv_example(),
+        "spiral" => spiral_example(std::env::args().nth(2)),
+        "bench" => bench_example(),
+        _ => {
+            eprintln!("Usage: autodiff [derivatives | regression | graph | conv | \
+                       spiral [relu | tanh | sigmoid] | bench]");
+            std::process::exit(1);
+        }
+    };
+    if let Err(err) = result {
+        eprintln!("{} example failed: {}", example, err);
+        std::process::exit(1);
+    }
+}
+
+fn derivatives_example() -> Result<(), String> {
+    // Approximate sin(x) for x=0, x=0.2, x=0.4.
+    let x = Node::new(Variable::new("x".to_string(),
+        Tensor{shape: vec![3], data: vec![0f32, 0.2f32, 0.4f32]}));
+    let sin = x.sin_taylor();
+    println!("sin(0, 0.2, 0.4): {:?}", sin.value().data);
+    println!("cos(0, 0.2, 0.4): {:?}", sin.sum_all().backward_scalar().0["x"].data);
+
+    // Forward mode gives the same derivatives as a directional
+    // derivative, and forward-over-reverse gives -sin(x) as the
+    // diagonal of the Hessian.
+    let ones = Gradient::new("x".to_string(), Tensor{shape: vec![3], data: vec![1f32; 3]});
+    println!("jvp cos(0, 0.2, 0.4): {:?}", sin.jvp(&ones).data);
+    println!("-sin(0, 0.2, 0.4): {:?}", sin.sum_all().hvp(&ones).0["x"].data);
+
+    // Building the backward pass as a graph gives cos(x) as a
+    // Node, which can be differentiated again.
+    let cos = &sin.sum_all().grad()["x"];
+    println!("d/dx cos(0, 0.2, 0.4): {:?}", cos.sum_all().backward_scalar().0["x"].data);
+
+    // A linear layer applied to a batch of two inputs.
+    // The bias is broadcast across the batch.
+    let inputs = Node::new(Variable::new("inputs".to_string(),
+        Tensor{shape: vec![2, 3], data: vec![1f32, 2f32, 3f32, 4f32, 5f32, 6f32]}));
+    let weights = Node::new(Variable::new("weights".to_string(),
+        Tensor{shape: vec![3, 2], data: vec![0.1f32, -0.2f32, 0.3f32, 0.4f32, -0.5f32, 0.6f32]}));
+    let bias = Node::new(Variable::new("bias".to_string(),
+        Tensor{shape: vec![2], data: vec![1f32, -1f32]}));
+    let linear = inputs.try_matmul(&weights)?.try_add(&bias)?;
+    println!("linear: {:?}", linear.value().data);
+    let loss = linear.pow(2f32).mean_all();
+    println!("mean squared output: {:?}", loss.value().data);
+    let grad = loss.backward_scalar();
+    println!("d/dweights: {:?}", grad.0["weights"].data);
+    println!("d/dbias: {:?}", grad.0["bias"].data);
+
+    // Reductions along an axis of the inputs. The gradient of
+    // the mean is spread evenly over each column, and that of
+    // the max all goes to the largest element of each row.
+    println!("row sums: {:?}", inputs.sum(1).value().data);
+    println!("column means: {:?}", inputs.mean(0).value().data);
+    println!("d/dinputs mean: {:?}", inputs.mean(0).sum_all().backward_scalar().0["inputs"].data);
+    println!("row maxima: {:?}", inputs.max(1).value().data);
+    println!("d/dinputs max: {:?}", inputs.max(1).sum_all().backward_scalar().0["inputs"].data);
+
+    // The derivative of tanh(x) is 1 - tanh(x)^2.
+    let x = Node::new(Variable::new("x".to_string(),
+        Tensor{shape: vec![3], data: vec![-1f32, 0f32, 2f32]}));
+    let t = x.tanh();
+    println!("tanh(-1, 0, 2): {:?}", t.value().data);
+    println!("1 - tanh^2: {:?}", t.value().map(|y| 1f32 - y * y).data);
+    println!("d/dx tanh: {:?}", t.sum_all().backward_scalar().0["x"].data);
+
+    // The derivative of softmax cross-entropy with respect to
+    // the logits is softmax(logits) - targets, over the batch
+    // size.
+    let logits = Node::new(Variable::new("logits".to_string(),
+        Tensor{shape: vec![2, 3], data: vec![1f32, 2f32, 3f32, 0f32, 0f32, 0f32]}));
+    let targets = Node::new(Constant(Tensor::one_hot(&[2, 0], 3), "targets".to_string()));
+    let loss = logits.softmax_cross_entropy(&targets);
+    println!("cross-entropy: {:?}", loss.value().data);
+    println!("softmax: {:?}", logits.value().log_softmax_rows().map(|x| x.exp()).data);
+    println!("d/dlogits: {:?}", loss.backward_scalar().0["logits"].data);
+
+    // Check that gradient against finite differences, in f64
+    // so that they're accurate.
+    let mut params = Params::<f64>::new();
+    params.insert("logits", Rng::new(11).tensor(vec![2, 3]));
+    let targets = Node::new(Constant(Tensor::one_hot(&[2, 0], 3), "targets".to_string()));
+    check_gradients(&params, |p| p.variable("logits").softmax_cross_entropy(&targets),
+                    1e-6, 1e-6)?;
+    println!("d/dlogits matches finite differences");
+    Ok(())
+}
+
+// Fit a linear model to y = 2*x0 - 3*x1 + 0.5 with each of
+// the optimizers, stopping once the loss is tiny, then save
+// the model and restore it into a new one.
+fn regression_example() -> Result<(), String> {
+    let mut inputs = Tensor::new(vec![8, 2]);
+    let mut targets = Tensor::new(vec![8, 1]);
+    for i in 0..8 {
+        let (x0, x1) = ((i as f32) / 4f32 - 1f32, ((i * 3) % 8) as f32 / 8f32);
+        inputs.data[i * 2] = x0;
+        inputs.data[i * 2 + 1] = x1;
+        targets.data[i] = 2f32 * x0 - 3f32 * x1 + 0.5f32;
+    }
+
+    let new_params = || {
+        let mut params = Params::new();
+        params.insert("weights", Tensor::new(vec![2, 1]));
+        params.insert("bias", Tensor::new(vec![1]));
+        params
+    };
+    let optimizers: Vec<(&str, Box<Optimizer>)> = vec![
+        ("SGD", Box::new(SGD::new(0.1))),
+        ("Momentum", Box::new(Momentum::new(0.05, 0.9))),
+        ("Adam", Box::new(Adam::new(0.05)))
+    ];
+    for (name, mut opt) in optimizers {
+        let mut params = new_params();
+        let mut step = 0;
+        loop {
+            let x = Node::new(Constant(inputs.clone(), "inputs".to_string()));
+            let y = Node::new(Constant(targets.clone(), "targets".to_string()));
+            let pred = x.try_matmul(&params.variable("weights"))?
+                .try_add(&params.variable("bias"))?;
+            let loss = mse_loss(&pred, &y)?;
+            let loss_value = loss.value().data[0];
+            if loss_value < 1e-6 || step == 10000 {
+                println!("{}: loss {} after {} steps", name, loss_value, step);
+                break;
+            }
+            opt.step(&mut params, &loss.backward_scalar());
+            step += 1;
+        }
+        println!("  weights={:?} bias={:?}", params.get("weights").data, params.get("bias").data);
+
+        for ext in &["params", "json"] {
+            let path = std::env::temp_dir().join(format!("autodiff-regression.{}", ext));
+            let path = path.to_string_lossy();
+            params.save(&path)?;
+            let mut restored = new_params();
+            let loaded = Params::load(&path);
+            let _ = std::fs::remove_file(&*path);
+            restored.restore(loaded?)?;
+            let differs = |&name: &&String| restored.get(name).data != params.get(name).data;
+            if restored.names().iter().any(differs) {
+                return Err(format!("{}: restored parameters differ", path));
+            }
+        }
+        println!("  saved and restored as binary and JSON");
+    }
+    Ok(())
+}
+
+// Export the sin approximation as a DOT graph with values
+// and gradients, and check that its JSON form reloads.
+fn graph_example() -> Result<(), String> {
+    let x = Node::new(Variable::new("x".to_string(),
+        Tensor{shape: vec![3], data: vec![0f32, 0.2f32, 0.4f32]}));
+    let tape = Tape::new(&x.sin_taylor().sum_all());
+    let out_grad = Tensor{shape: vec![], data: vec![1f32]};
+    println!("{}", tape.to_dot(true, Some(&out_grad)));
+
+    let json = tape.to_json(false, None)?;
+    println!("{}", json);
+    let loaded = Tape::from_json(&json)?;
+    println!("reloaded cos(0, 0.2, 0.4): {:?}", loaded.backward(&out_grad).0["x"].data);
+    Ok(())
+}
+
+// Recover a 3x3 edge-detection filter from random images
+// and their filtered versions, then a 1D smoothing filter
+// from signals compared after pooling.
+fn conv_example() -> Result<(), String> {
+    let mut rng = Rng::new(7);
+    let images = Node::new(Constant(rng.tensor(vec![4, 1, 8, 8]), "images".to_string()));
+    let sobel = Tensor{shape: vec![1, 1, 3, 3], data: vec![
+        -1f32, 0f32, 1f32,
+        -2f32, 0f32, 2f32,
+        -1f32, 0f32, 1f32
+    ]};
+    let targets = images.conv2d(&Node::new(Constant(sobel, "sobel".to_string())), 1, 1, 1);
+
+    let mut params = Params::new();
+    params.insert("kernel", Tensor::new(vec![1, 1, 3, 3]));
+    let mut opt = Adam::new(0.1);
+    for step in 0..=300 {
+        let pred = images.conv2d(&params.variable("kernel"), 1, 1, 1);
+        let loss = (&pred - &targets).pow(2f32).mean_all();
+        if step % 100 == 0 {
+            println!("step {}: loss {}", step, loss.value().data[0]);
+        }
+        opt.step(&mut params, &loss.backward_scalar());
+    }
+    for row in params.get("kernel").data.chunks(3) {
+        let row: Vec<String> = row.iter().map(|x| format!("{:6.3}", x)).collect();
+        println!("  [{}]", row.join(", "));
+    }
+
+    // Recover the 1D filter the same way, but compare the
+    // outputs after pooling. Average pooling passes gradients
+    // to every position, and max pooling only to the largest
+    // in each window.
+    let signals = Node::new(Constant(rng.tensor(vec![4, 1, 32]), "signals".to_string()));
+    let smooth = Tensor{shape: vec![1, 1, 3], data: vec![0.25f32, 0.5f32, 0.25f32]};
+    let targets = signals.conv1d(&Node::new(Constant(smooth, "smooth".to_string())), 1, 1, 1);
+    params.insert("kernel1d", Tensor::new(vec![1, 1, 3]));
+    let mut opt = Adam::new(0.1);
+    for step in 0..=300 {
+        let pred = signals.conv1d(&params.variable("kernel1d"), 1, 1, 1);
+        let loss = &mse_loss(&pred.avg_pool(2, 2), &targets.avg_pool(2, 2))? +
+            &mse_loss(&pred.max_pool(2, 2), &targets.max_pool(2, 2))?;
+        if step % 100 == 0 {
+            println!("step {}: pooled loss {}", step, loss.value().data[0]);
+        }
+        opt.step(&mut params, &loss.backward_scalar());
+    }
+    println!("  {:.3?}", params.get("kernel1d").data);
+    Ok(())
+}
+
+// Classify points on two interleaved spirals with an MLP,
+// using the named activation (tanh by default). The classes
+// aren't linearly separable, so this needs the hidden layers.
+fn spiral_example(activation: Option<String>) -> Result<(), String> {
+    let activation = match activation.as_ref().map(|name| name.as_str()) {
+        None | Some("tanh") => Activation::Tanh,
+        Some("relu") => Activation::ReLU,
+        Some("sigmoid") => Activation::Sigmoid,
+        Some(name) => return Err(format!("unknown activation {}", name))
+    };
+    let (points, labels) = spiral_dataset(100);
+    let inputs = Node::new(Constant(points, "points".to_string()));
+
+    let mut rng = Rng::new(5);
+    let mut params = Params::new();
+    let model = MLP::new(&mut params, &mut rng, "mlp", &[2, 32, 32, 2], activation);
+    let mut opt = Adam::new(0.01);
+    for step in 0..=1500 {
+        let logits = model.forward(&params, &inputs)?;
+        let loss = cross_entropy_loss(&logits, &labels)?;
+        if step % 300 == 0 {
+            let predictions = logits.value().argmax(1);
+            let correct = predictions.iter().zip(&labels).filter(|&(a, b)| a == b).count();
+            println!("step {}: loss {:.4}, accuracy {}/{}",
+                step, loss.value().data[0], correct, labels.len());
+        }
+        opt.step(&mut params, &loss.backward_scalar());
+    }
+    Ok(())
+}
+
+// Sample points from two spirals that wind around each
+// other, labelled 0 and 1.
+fn spiral_dataset(per_class: usize) -> (Tensor, Vec<usize>) {
+    let mut points = Tensor::new(vec![2 * per_class, 2]);
+    let mut labels = Vec::<usize>::new();
+    for class in 0..2 {
+        for i in 0..per_class {
+            let r = (i + 1) as f32 / per_class as f32;
+            let angle = 2.5f32 * std::f32::consts::PI * r + class as f32 * std::f32::consts::PI;
+            let row = labels.len();
+            points.data[row * 2] = r * angle.cos();
+            points.data[row * 2 + 1] = r * angle.sin();
+            labels.push(class);
+        }
+    }
+    (points, labels)
+}
+
+// Compare the time taken by each way of evaluating and
+// differentiating a deep expression, and the allocations
+// made if they're counted (see counting).
+fn bench_example() -> Result<(), String> {
+    let (depth, width) = (1000, 256);
+    let mut rng = Rng::new(3);
+    let mut params = Params::<f32>::new();
+    for name in &["x", "w", "b"] {
+        params.insert(name, rng.tensor(vec![width]));
+    }
+    let deep = |params: &Params| {
+        let (w, b) = (params.variable("w"), params.variable("b"));
+        let mut y = params.variable("x");
+        for _ in 0..depth {
+            y = (&(&y * &w) + &b).tanh();
+        }
+        y.sum_all()
+    };
+
+    println!("{} layers of tanh(y * w + b) on {} elements:", depth, width);
+    let output = measure("forward", || deep(&params));
+    measure("forward (no_grad)", || no_grad(|| deep(&params)));
+    let out_grad = output.scalar_grad("bench");
+    let tape = Tape::new(&output);
+    measure("backward", || tape.backward(&out_grad));
+    let mut buffers = GradBuffers::new();
+    tape.backward_with(&out_grad, &mut buffers);
+    let grad = measure("backward (reused buffers)", || tape.backward_with(&out_grad, &mut buffers));
+    if grad.0["x"].data != tape.backward(&out_grad).0["x"].data {
+        return Err("gradients differ with reused buffers".to_string());
+    }
+    Ok(())
+}
+
+// Run f, and report how long it took, and how many
+// allocations it made and how far the heap grew while it ran
+// if they're counted.
+fn measure<R, F: FnOnce() -> R>(label: &str, f: F) -> R {
+    let counts = counting::start();
+    let start = Instant::now();
+    let res = f();
+    let elapsed = start.elapsed();
+    let allocations = match counting::since(counts) {
+        Some((allocations, peak)) =>
+            format!("{:>7} allocations, {:>9} peak bytes, ", allocations, peak),
+        None => String::new()
+    };
+    println!("  {:<26} {}{:>6.1}ms", label, allocations, elapsed.as_secs_f64() * 1000f64);
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Activation, Adam, Constant, Float, GradBuffers, Gradient, Json, Linear, MLP, Module,
+        Momentum, Node, Optimizer, Params, Res, Rng, SGD, ShapeError, Tape, Tensor, Variable,
+        broadcast_shape, check_gradients, cross_entropy_loss, mse_loss, no_grad};
+    use std::panic;
+
+    // The checks use f64, so that finite differences are
+    // accurate enough to catch small mistakes.
+    const EPSILON: f64 = 1e-6;
+    const TOLERANCE: f64 = 1e-6;
+
+    fn random_shape(rng: &mut Rng) -> Vec<usize> {
+        let rank = 1 + (rng.next_u64() % 3) as usize;
+        (0..rank).map(|_| 1 + (rng.next_u64() % 4) as usize).collect()
+    }
+
+    // Get a shape that broadcasts to the given shape, by
+    // dropping leading dimensions and replacing some with 1.
+    fn broadcastable_shape(rng: &mut Rng, shape: &[usize]) -> Vec<usize> {
+        let skip = (rng.next_u64() % (shape.len() as u64 + 1)) as usize;
+        shape[skip..].iter().map(|&x| if rng.next_u64() % 3 == 0 { 1 } else { x }).collect()
+    }
+
+    fn assert_close(name: &str, actual: &Tensor<f64>, expected: &Tensor<f64>) {
+        assert_eq!(actual.shape, expected.shape);
+        for i in 0..actual.data.len() {
+            let (a, e) = (actual.data[i], expected.data[i]);
+            let scale = 1f64.max(a.abs()).max(e.abs());
+            assert!((a - e).abs() <= TOLERANCE * scale,
+                "{}[{}]: got {} but expected {}", name, i, a, e);
+        }
+    }
+
+    fn move_params(p: &Params<f64>, direction: &Gradient<f64>, scale: f64) -> Params<f64> {
+        let mut res = p.clone();
+        for (name, value) in res.0.iter_mut() {
+            *value = &*value + &(&direction.0[name] * scale);
+        }
+        res
+    }
+
+    // Check jvp() and hvp() against finite differences of
+    // the output and of the gradient, respectively, along a
+    // random direction.
+    fn check_forward_mode<F>(p: &Params<f64>, f: F) where F: Fn(&Params<f64>) -> Node<f64> {
+        let mut rng = Rng::new(7);
+        let mut direction = Gradient::empty();
+        for (name, value) in &p.0 {
+            direction.accumulate(name.clone(), rng.tensor(value.shape.clone()));
+        }
+        let (plus, minus) = (move_params(p, &direction, EPSILON),
+            move_params(p, &direction, -EPSILON));
+
+        let output = f(p);
+        let expected = &(f(&plus).value() - f(&minus).value()) / (2f64 * EPSILON);
+        assert_close("jvp", &output.jvp(&direction), &expected);
+
+        let weights = rng.tensor(output.value().shape.clone());
+        let hvp = Tape::new(&output).hvp(&weights, &direction).1;
+        let (grad_plus, grad_minus) = (f(&plus).backward(&weights), f(&minus).backward(&weights));
+        for name in p.0.keys() {
+            let zero = Tensor::new(p.get(name).shape.clone());
+            let expected = &(grad_plus.0.get(name).unwrap_or(&zero) -
+                grad_minus.0.get(name).unwrap_or(&zero)) / (2f64 * EPSILON);
+            assert_close(&format!("hvp {}", name), hvp.0.get(name).unwrap_or(&zero), &expected);
+        }
+    }
+
+    // Check that the backward pass built as a graph gives the
+    // same gradients as backward(), and that differentiating
+    // it again works, using the squared norm of the gradient
+    // as a penalty.
+    fn check_backward_graph<F>(p: &Params<f64>, f: F) where F: Fn(&Params<f64>) -> Node<f64> {
+        let output = f(p);
+        let weights = Rng::new(8).tensor(output.value().shape.clone());
+        let expected = output.backward(&weights);
+        let out_grad = Node::new(Constant(weights, "weights".to_string()));
+        let grads = Tape::new(&output).backward_graph(&out_grad);
+        assert_eq!(grads.len(), expected.0.len());
+        for (name, grad) in &expected.0 {
+            assert_close(&format!("grad {}", name), grads[name].value(), grad);
+        }
+
+        let penalty = |p: &Params<f64>| {
+            let mut names: Vec<String> = p.0.keys().cloned().collect();
+            names.sort();
+            let grads = Tape::new(&f(p)).backward_graph(&out_grad);
+            names.iter().filter_map(|name| grads.get(name))
+                .fold(Node::scalar(0f64), |sum, grad| &sum + &grad.pow(2f64).sum_all())
+        };
+        check_gradients(p, &penalty, EPSILON, TOLERANCE).unwrap();
+        check_forward_mode(p, &penalty);
+    }
+
+    fn params(rng: &mut Rng, shapes: &[Vec<usize>]) -> Params<f64> {
+        let mut res = Params::new();
+        for (i, shape) in shapes.iter().enumerate() {
+            res.insert(&format!("x{}", i), rng.tensor(shape.clone()));
+        }
+        res
+    }
+
+    // Run a gradient check on random inputs with random
+    // shapes, using map_input to keep values in the domain
+    // of the function under test.
+    fn check_unary<F, G>(map_input: G, f: F)
+        where F: Fn(&Node<f64>) -> Node<f64>, G: Fn(f64) -> f64
+    {
+        let mut rng = Rng::new(1);
+        for _ in 0..10 {
+            let shape = random_shape(&mut rng);
+            let mut p = params(&mut rng, &[shape]);
+            let x = p.get("x0").map(&map_input);
+            p.insert("x0", x);
+            check_gradients(&p, |p| f(&p.variable("x0")), EPSILON, TOLERANCE).unwrap();
+            check_forward_mode(&p, |p| f(&p.variable("x0")));
+        }
+    }
+
+    fn check_binary<F>(f: F) where F: Fn(&Node<f64>, &Node<f64>) -> Node<f64> {
+        let mut rng = Rng::new(2);
+        for i in 0..20 {
+            let shape = random_shape(&mut rng);
+            let other = broadcastable_shape(&mut rng, &shape);
+            let shapes = if i % 2 == 0 { [shape, other] } else { [other, shape] };
+            let mut p = params(&mut rng, &shapes);
+
+            // Keep the values away from zero for division.
+            let y = p.get("x1").map(|y| if y < 0f64 { y - 0.5 } else { y + 0.5 });
+            p.insert("x1", y);
+
+            check_gradients(&p, |p| f(&p.variable("x0"), &p.variable("x1")),
+                EPSILON, TOLERANCE).unwrap();
+            check_forward_mode(&p, |p| f(&p.variable("x0"), &p.variable("x1")));
+        }
+    }
+
+    #[test]
+    fn broadcast_shapes() {
+        assert_eq!(broadcast_shape(&[2, 3], &[3]), vec![2, 3]);
+        assert_eq!(broadcast_shape(&[4, 1, 3], &[2, 1]), vec![4, 2, 3]);
+        assert_eq!(broadcast_shape(&[], &[5]), vec![5]);
+        let t = Tensor{shape: vec![2, 1], data: vec![1f64, 2f64]};
+        assert_eq!(t.broadcast_to(&[2, 3]).data, vec![1f64, 1f64, 1f64, 2f64, 2f64, 2f64]);
+        assert_eq!(t.broadcast_to(&[2, 3]).sum_to(&[2, 1]).data, vec![3f64, 6f64]);
+    }
+
+    #[test]
+    fn tensor_views() {
+        let t = Tensor{shape: vec![2, 3], data: vec![1f64, 2f64, 3f64, 4f64, 5f64, 6f64]};
+        let transposed = t.view().transpose(0, 1);
+        assert_eq!(transposed.shape, vec![3, 2]);
+        assert_eq!(transposed.to_tensor().data, vec![1f64, 4f64, 2f64, 5f64, 3f64, 6f64]);
+        assert_eq!(transposed.transpose(0, 1).to_tensor().data, t.data);
+
+        let column = Tensor{shape: vec![2, 1], data: vec![1f64, 2f64]};
+        assert_eq!(column.view().broadcast_to(&[2, 3]).transpose(0, 1).to_tensor().data,
+            vec![1f64, 2f64, 1f64, 2f64, 1f64, 2f64]);
+
+        let reshaped = t.reshape(vec![3, 2]);
+        assert_eq!(reshaped.shape, vec![3, 2]);
+        assert_eq!(reshaped.data, vec![1f64, 2f64, 3f64, 4f64, 5f64, 6f64]);
+    }
+
+    #[test]
+    fn check_f32() {
+        // The f32 checks need much looser tolerances.
+        let mut rng = Rng::new(9);
+        let mut p = Params::<f32>::new();
+        p.insert("x0", rng.tensor(vec![3, 3]));
+        p.insert("x1", rng.tensor(vec![3]));
+        check_gradients(&p, shared_node_model, 1e-2f32, 1e-2f32).unwrap();
+    }
+
+    #[test]
+    fn check_add() {
+        check_binary(|a, b| a + b);
+    }
+
+    #[test]
+    fn check_sub() {
+        check_binary(|a, b| a - b);
+    }
+
+    #[test]
+    fn check_mul() {
+        check_binary(|a, b| a * b);
+    }
+
+    #[test]
+    fn check_div() {
+        check_binary(|a, b| a / b);
+    }
+
+    #[test]
+    fn check_matmul() {
+        let mut rng = Rng::new(3);
+        for _ in 0..10 {
+            let dims: Vec<usize> = (0..3).map(|_| 1 + (rng.next_u64() % 4) as usize).collect();
+            let p = params(&mut rng, &[vec![dims[0], dims[1]], vec![dims[1], dims[2]]]);
+            check_gradients(&p, |p| p.variable("x0").matmul(&p.variable("x1")),
+                EPSILON, TOLERANCE).unwrap();
+            check_forward_mode(&p, |p| p.variable("x0").matmul(&p.variable("x1")));
+        }
+    }
+
+    #[test]
+    fn check_unary_ops() {
+        check_unary(|x| x, |x| x.exp());
+        check_unary(|x| x.abs() + 0.5, |x| x.log());
+        check_unary(|x| x, |x| x.tanh());
+        check_unary(|x| x, |x| x.sigmoid());
+        check_unary(|x| x.abs() + 0.5, |x| x.pow(2.5));
+        check_unary(|x| x, |x| x.pow(3f64));
+
+        // Keep inputs away from the kink at zero.
+        check_unary(|x| if x < 0f64 { x - 0.1 } else { x + 0.1 }, |x| x.relu());
+    }
+
+    #[test]
+    fn check_reductions() {
+        let mut rng = Rng::new(4);
+        for _ in 0..10 {
+            let shape = random_shape(&mut rng);
+            let axis = (rng.next_u64() % shape.len() as u64) as usize;
+            let p = params(&mut rng, &[shape]);
+            check_gradients(&p, |p| p.variable("x0").sum(axis), EPSILON, TOLERANCE).unwrap();
+            check_gradients(&p, |p| p.variable("x0").sum_all(), EPSILON, TOLERANCE).unwrap();
+            check_gradients(&p, |p| p.variable("x0").mean(axis), EPSILON, TOLERANCE).unwrap();
+            check_gradients(&p, |p| p.variable("x0").mean_all(), EPSILON, TOLERANCE).unwrap();
+            check_gradients(&p, |p| p.variable("x0").max(axis), EPSILON, TOLERANCE).unwrap();
+            check_forward_mode(&p, |p| p.variable("x0").sum(axis));
+            check_forward_mode(&p, |p| p.variable("x0").mean_all());
+            check_forward_mode(&p, |p| p.variable("x0").max(axis));
+        }
+    }
+
+    // A random [batch, channels, ...] shape whose spatial
+    // axes are at least min_size long.
+    fn spatial_shape(rng: &mut Rng, rank: usize, channels: usize, min_size: usize) -> Vec<usize> {
+        let mut shape = vec![1 + (rng.next_u64() % 2) as usize, channels];
+        for _ in 0..rank {
+            shape.push(min_size + (rng.next_u64() % 4) as usize);
+        }
+        shape
+    }
+
+    // Check that a graph reloads from JSON with the same values.
+    fn assert_reloads(output: &Node<f64>) {
+        let tape = Tape::new(output);
+        let loaded: Tape<f64> = Tape::from_json(&tape.to_json(true, None).unwrap()).unwrap();
+        for (a, b) in loaded.0.iter().zip(tape.0.iter()) {
+            assert_eq!(a.name(), b.name());
+            assert_eq!(a.value().data, b.value().data);
+        }
+    }
+
+    #[test]
+    fn check_conv() {
+        let mut rng = Rng::new(9);
+        for i in 0..20 {
+            let rank = 1 + i % 2;
+            let channels = 1 + (rng.next_u64() % 2) as usize;
+            let out_channels = 1 + (rng.next_u64() % 3) as usize;
+            let size = 1 + (rng.next_u64() % 3) as usize;
+            let (stride, padding, dilation) = (1 + i % 3, (i / 2) % 3, 1 + (i / 3) % 2);
+            let input = spatial_shape(&mut rng, rank, channels, dilation * (size - 1) + 1);
+            let mut kernel = vec![out_channels, channels];
+            kernel.extend(vec![size; rank]);
+            let p = params(&mut rng, &[input, kernel]);
+
+            let conv = |p: &Params<f64>| if rank == 1 {
+                p.variable("x0").conv1d(&p.variable("x1"), stride, padding, dilation)
+            } else {
+                p.variable("x0").conv2d(&p.variable("x1"), stride, padding, dilation)
+            };
+            check_gradients(&p, &conv, EPSILON, TOLERANCE).unwrap();
+            check_forward_mode(&p, |p| conv(p).tanh());
+            assert_reloads(&conv(&p));
+        }
+    }
+
+    #[test]
+    fn check_pooling() {
+        let mut rng = Rng::new(10);
+        for i in 0..20 {
+            let (size, stride) = (1 + i % 3, 1 + (i / 3) % 2);
+            let shape = spatial_shape(&mut rng, 1 + i % 2, 2, size);
+            let p = params(&mut rng, &[shape]);
+            check_gradients(&p, |p| p.variable("x0").avg_pool(size, stride), EPSILON, TOLERANCE)
+                .unwrap();
+            check_gradients(&p, |p| p.variable("x0").max_pool(size, stride), EPSILON, TOLERANCE)
+                .unwrap();
+            check_forward_mode(&p, |p| p.variable("x0").avg_pool(size, stride).exp());
+            check_forward_mode(&p, |p| p.variable("x0").max_pool(size, stride).exp());
+            assert_reloads(&p.variable("x0").max_pool(size, stride).avg_pool(1, 1));
+        }
+
+        let x = Node::new(Variable::new("x".to_string(),
+            Tensor{shape: vec![1, 1, 2, 4],
+                data: vec![1f64, 5f64, 2f64, 0f64, 3f64, 4f64, 8f64, 6f64]}));
+        assert_eq!(x.max_pool(2, 2).value().data, vec![5f64, 8f64]);
+        assert_eq!(x.avg_pool(2, 2).value().data, vec![3.25f64, 4f64]);
+    }
+
+    #[test]
+    fn shape_errors() {
+        let mut rng = Rng::new(11);
+        let p = params(&mut rng, &[vec![2, 3], vec![4], vec![1, 2, 5], vec![3, 3, 2]]);
+        let (a, b, c, k) = (p.variable("x0"), p.variable("x1"), p.variable("x2"), p.variable("x3"));
+
+        let err = a.try_add(&b).err().unwrap();
+        let reason = "shapes can't be broadcast together";
+        assert_eq!(err, ShapeError::new("Add", &[&[2, 3], &[4]], reason));
+        assert_eq!(err.to_string(), "Add of [2, 3] and [4]: shapes can't be broadcast together");
+        assert_eq!(a.try_matmul(&a).err().unwrap().to_string(),
+            "MatMul of [2, 3] and [2, 3]: inner dimensions differ");
+        assert_eq!(a.try_sum(2).err().unwrap().to_string(),
+            "Sum of [2, 3]: axis 2 is out of range");
+        assert_eq!(c.try_conv1d(&k, 1, 0, 1).err().unwrap().to_string(),
+            "Conv1d of [1, 2, 5] and [3, 3, 2]: input and kernel have different channels");
+        assert_eq!(c.try_conv2d(&k, 1, 0, 1).err().unwrap().op, "Conv2d");
+        assert_eq!(c.try_max_pool(6, 1).err().unwrap().to_string(),
+            "MaxPool1d of [1, 2, 5]: window of 6 doesn't fit in 5 with padding 0");
+        let d = Node::new(Constant(Tensor::<f64>::new(vec![1, 1, 2, 5]), "d".to_string()));
+        assert_eq!(d.try_avg_pool(3, 1).err().unwrap().to_string(),
+            "AvgPool2d of [1, 1, 2, 5]: window of 3 doesn't fit in 2 with padding 0");
+        assert_eq!(k.sum(0).try_max_pool(1, 1).err().unwrap().op, "MaxPool2d");
+
+        assert!(a.try_add(&a.sum(0)).is_ok());
+        assert!(c.try_conv1d(&k.sum(0), 1, 0, 1).is_err());
+        assert!(c.try_conv1d(&c, 1, 0, 1).is_ok());
+
+        // A graph with bad shapes fails to load instead of panicking.
+        let json = Tape::new(&a.sum(0)).to_json(false, None).unwrap();
+        let bad = json.replace("\"axis\":0", "\"axis\":5");
+        assert_ne!(bad, json);
+        assert_eq!(Tape::<f64>::from_json(&bad).err().unwrap(),
+            "Sum of [2, 3]: axis 5 is out of range");
+    }
+
+    fn assert_same_params<T: Float, U: Float>(a: &Params<T>, b: &Params<U>) {
+        assert_eq!(a.names(), b.names());
+        for name in a.names() {
+            assert_eq!(a.get(name).shape, b.get(name).shape);
+            let x: Vec<f64> = a.get(name).data.iter().map(|x| x.to_f64()).collect();
+            let y: Vec<f64> = b.get(name).data.iter().map(|x| x.to_f64()).collect();
+            assert_eq!(x, y);
+        }
+    }
+
+    #[test]
+    fn params_round_trip() {
+        let mut rng = Rng::new(12);
+        let mut p = params(&mut rng, &[vec![2, 3], vec![], vec![4, 0]]);
+        p.insert("layer 1/wéights \"q\"", rng.tensor(vec![3, 1, 2]));
+        assert_same_params(&p, &Params::<f64>::from_json(&p.to_json()).unwrap());
+        assert_same_params(&p, &Params::<f64>::from_bytes(&p.to_bytes()).unwrap());
+
+        // f32 files load exactly into f64 Params and back.
+        let small: Params<f32> = Params::from_bytes(&p.to_bytes()).unwrap();
+        let bytes = small.to_bytes();
+        assert_eq!(&bytes[8..16], &[1, 0, 0, 0, 4, 0, 0, 0]);
+        let wide: Params<f64> = Params::from_bytes(&bytes).unwrap();
+        assert_same_params(&small, &wide);
+        assert_same_params(&small, &Params::<f32>::from_json(&small.to_json()).unwrap());
+
+        // Infinities and NaN survive JSON too.
+        let mut special = Params::<f64>::new();
+        let infinities = vec![std::f64::INFINITY, std::f64::NEG_INFINITY, 1f64];
+        special.insert("x", Tensor{shape: vec![3], data: infinities});
+        assert_same_params(&special, &Params::<f64>::from_json(&special.to_json()).unwrap());
+        special.insert("y", Tensor{shape: vec![], data: vec![std::f64::NAN]});
+        assert!(Params::<f64>::from_json(&special.to_json()).unwrap().get("y").data[0].is_nan());
+
+        let dir = std::env::temp_dir();
+        for ext in &["json", "bin"] {
+            let path = dir.join(format!("autodiff_test_params_{}.{}", std::process::id(), ext));
+            let path = path.to_str().unwrap();
+            p.save(path).unwrap();
+            let loaded = Params::<f64>::load(path);
+            std::fs::remove_file(path).unwrap();
+            assert_same_params(&p, &loaded.unwrap());
+        }
+    }
+
+    #[test]
+    fn params_validation() {
+        let mut rng = Rng::new(13);
+        let p = params(&mut rng, &[vec![2, 3], vec![4]]);
+        let bytes = p.to_bytes();
+        for len in 0..bytes.len() {
+            assert!(Params::<f64>::from_bytes(&bytes[..len]).is_err());
+        }
+        let mut extra = bytes.clone();
+        extra.push(0);
+        assert!(Params::<f64>::from_bytes(&extra).is_err());
+        assert_eq!(Params::<f64>::from_json(r#"{"x":{"shape":[2],"value":[1]}}"#).err().unwrap(),
+            "x: 1 values don't fit shape [2]");
+        let duplicate = r#"{"x":{"shape":[],"value":[1]},"x":{"shape":[],"value":[2]}}"#;
+        assert!(Params::<f64>::from_json(duplicate).is_err());
+        for shape in &["[-1]", "[1.5]", "[null]", "[1e300]"] {
+            let json = format!(r#"{{"x":{{"shape":{},"value":[1]}}}}"#, shape);
+            let err = Params::<f64>::from_json(&json).err().unwrap();
+            assert!(err.starts_with("expected non-negative integer"), "{}", err);
+        }
+        let huge = r#"{"x":{"shape":[4294967296,4294967296],"value":[]}}"#;
+        assert_eq!(Params::<f64>::from_json(huge).err().unwrap(),
+            "x: 0 values don't fit shape [4294967296, 4294967296]");
+
+        // Restoring checks the names and shapes against the
+        // model, and leaves it unchanged on failure.
+        let mut model = params(&mut rng, &[vec![2, 3], vec![4]]);
+        let mut wrong_shape = p.clone();
+        wrong_shape.insert("x1", Tensor::new(vec![4, 1]));
+        assert_eq!(model.restore(wrong_shape).err().unwrap(),
+            "x1: expected shape [4] but got [4, 1]");
+        let mut missing = p.clone();
+        missing.0.remove("x0");
+        assert_eq!(model.restore(missing).err().unwrap(), "x0: missing from checkpoint");
+        let mut unknown = p.clone();
+        unknown.insert("x2", Tensor::new(vec![1]));
+        assert_eq!(model.restore(unknown).err().unwrap(), "x2: not a parameter");
+        assert!(model.get("x0").data != p.get("x0").data);
+        model.restore(Params::from_bytes(&bytes).unwrap()).unwrap();
+        assert_same_params(&model, &p);
+    }
+
+    #[test]
+    fn check_softmax_cross_entropy() {
+        let mut rng = Rng::new(14);
+        for i in 0..10 {
+            let shape = vec![1 + i % 3, 1 + (rng.next_u64() % 4) as usize];
+            let mut p = params(&mut rng, &[shape.clone(), shape]);
+            let targets = p.get("x1").map(|x| x.abs());
+            p.insert("x1", targets);
+            check_gradients(&p, |p| p.variable("x0").softmax_cross_entropy(&p.variable("x1")),
+                EPSILON, TOLERANCE).unwrap();
+            check_forward_mode(&p, |p| p.variable("x0").softmax_cross_entropy(&p.variable("x1")));
+        }
+
+        // Large logits don't overflow.
+        let logits = Tensor{shape: vec![2, 2], data: vec![1000f64, 0f64, 0f64, 0f64]};
+        let logits = Node::new(Constant(logits, "logits".to_string()));
+        let loss = cross_entropy_loss(&logits, &[0, 1]).unwrap().value().data[0];
+        assert!((loss - 2f64.ln() / 2f64).abs() < 1e-12);
+        assert_eq!(cross_entropy_loss(&logits, &[0, 2]).err().unwrap().to_string(),
+            "SoftmaxCrossEntropy of [2, 2]: label 2 is out of range");
+    }
+
+    #[test]
+    fn nn_modules() {
+        let mut rng = Rng::new(15);
+        let mut p = Params::<f64>::new();
+        let model = MLP::new(&mut p, &mut rng, "mlp", &[2, 8, 1], Activation::Tanh);
+        let mut names: Vec<&String> = p.0.keys().collect();
+        names.sort();
+        assert_eq!(names, vec!["mlp.0.bias", "mlp.0.weight", "mlp.1.bias", "mlp.1.weight"]);
+        assert_eq!(p.get("mlp.0.weight").shape, vec![2, 8]);
+        assert_eq!(p.get("mlp.1.bias").shape, vec![1]);
+
+        let layer = Linear::new(&mut p, &mut rng, "extra", 3, 2);
+        let x = Node::new(Constant(rng.tensor(vec![5, 3]), "x".to_string()));
+        assert_eq!(layer.forward(&p, &x).unwrap().value().shape, vec![5, 2]);
+        assert_eq!(model.forward(&p, &x).err().unwrap().op, "MatMul");
+
+        // Learn XOR with each activation.
+        let inputs = Node::new(Constant(Tensor{shape: vec![4, 2],
+            data: vec![0f64, 0f64, 0f64, 1f64, 1f64, 0f64, 1f64, 1f64]}, "inputs".to_string()));
+        let targets = Node::new(Constant(Tensor{shape: vec![4, 1],
+            data: vec![0f64, 1f64, 1f64, 0f64]}, "targets".to_string()));
+        for &activation in &[Activation::Tanh, Activation::ReLU, Activation::Sigmoid] {
+            let mut p = Params::<f64>::new();
+            let model = MLP::new(&mut p, &mut rng, "mlp", &[2, 8, 1], activation);
+            let mut opt = Adam::new(0.05);
+            for _ in 0..500 {
+                let loss = mse_loss(&model.forward(&p, &inputs).unwrap(), &targets).unwrap();
+                opt.step(&mut p, &loss.backward_scalar());
+            }
+            let pred = model.forward(&p, &inputs).unwrap();
+            for (y, t) in pred.value().data.iter().zip(&targets.value().data) {
+                assert!((y - t).abs() < 0.01, "{:?} predicted {:?}", activation, pred.value().data);
+            }
+        }
+
+        // Only the model's own parameters get gradients.
+        let loss = mse_loss(&model.forward(&p, &inputs).unwrap(), &targets).unwrap();
+        let grad = loss.backward_scalar();
+        assert!(grad.0.contains_key("mlp.0.weight") && !grad.0.contains_key("extra.weight"));
+    }
+
+    #[test]
+    fn optimizer_shapes() {
+        let optimizers: Vec<Box<Optimizer<f64>>> = vec![
+            Box::new(SGD::new(0.1)),
+            Box::new(Momentum::new(0.1, 0.9)),
+            Box::new(Adam::new(0.1))
+        ];
+        for mut opt in optimizers {
+            let mut p = Params::<f64>::new();
+            p.insert("w", Tensor::new(vec![2, 3]));
+            let grad = Gradient::new("w".to_string(), Tensor::new(vec![3, 2]));
+            let res = panic::catch_unwind(panic::AssertUnwindSafe(|| opt.step(&mut p, &grad)));
+            let message = res.err().and_then(|e| e.downcast::<String>().ok()).expect("no panic");
+            assert_eq!(*message,
+                "gradient of parameter w has shape [3, 2] but the parameter has shape [2, 3]");
+        }
+    }
+
+    #[test]
+    fn no_grad_mode() {
+        let mut rng = Rng::new(16);
+        let p = params(&mut rng, &[vec![3, 3], vec![3]]);
+        let expected = shared_node_model(&p);
+        let actual = no_grad(|| {
+            let inner = no_grad(|| shared_node_model(&p));
+            assert_eq!(inner.0.op(), "Constant");
+            assert!(inner.0.is_leaf());
+
+            // Leaves are kept as they are, so Variables still
+            // get gradients and Constants keep their names.
+            let x = p.variable("x0");
+            assert_eq!(x.0.op(), "Variable");
+            assert!(x.0.is_leaf());
+            assert!(x.sum_all().0.inputs().is_empty());
+            assert!(x.backward(x.value()).0.contains_key("x0"));
+            let c = Node::new(Constant(x.value().clone(), "c".to_string()));
+            assert_eq!(c.name(), "c");
+            shared_node_model(&p)
+        });
+        assert_eq!(actual.value().data, expected.value().data);
+        assert_eq!(actual.0.inputs().len(), 0);
+        assert!(actual.backward(&expected.value().clone()).0.is_empty());
+
+        // Recording resumes afterwards.
+        assert_eq!(shared_node_model(&p).0.op(), "Sub");
+        assert!(!shared_node_model(&p).0.is_leaf());
+    }
+
+    #[test]
+    fn reused_buffers() {
+        let mut rng = Rng::new(17);
+        let mut buffers = GradBuffers::new();
+        for i in 0..10 {
+            let n = 1 + i % 3;
+            let p = params(&mut rng, &[vec![n, n], vec![n], vec![1, n]]);
+            let output = &(&shared_node_model(&p) / &p.variable("x2").exp())
+                * &p.variable("x1").sigmoid();
+            let out_grad = rng.tensor(output.value().shape.clone());
+            let expected = output.backward(&out_grad);
+            let actual = Tape::new(&output).backward_with(&out_grad, &mut buffers);
+            for name in p.0.keys() {
+                assert_eq!(actual.0[name].data, expected.0[name].data);
+            }
+        }
+    }
+
+    fn shared_node_model<T: Float>(p: &Params<T>) -> Node<T> {
+        let x = p.variable("x0");
+        let h = (x.matmul(&x) + p.variable("x1")).tanh();
+        &(&h * &h) - &x
+    }
+
+    #[test]
+    fn shared_first_input() {
+        fn grad<F: Fn(&Node) -> Node>(f: F, x: f32) -> f32 {
+            let x = Tensor{shape: vec![1], data: vec![x]};
+            let x = Node::new(super::Variable::new("x".to_string(), x));
+            f(&x).sum_all().backward_scalar().0["x"].data[0]
+        }
+        let close = |a: f32, b: f32| (a - b).abs() < 1e-4;
+
+        // x is the first input of the sum, and also reachable
+        // from exp(x), so it has to come before exp(x) on the
+        // tape for both gradients to reach it.
+        assert!(close(grad(|x| x + &x.exp(), 1f32), 1f32 + 1f32.exp()));
+
+        // d/dx y / tanh(y) = (1 / tanh(y) - y / sinh(y)^2) * y
+        // for y = exp(x).
+        let y = 0.5f32.exp();
+        let expected = y / y.tanh() - y * y / (y.sinh() * y.sinh());
+        assert!(close(grad(|x| { let y = x.exp(); &y / &y.tanh() }, 0.5f32), expected));
+    }
+
+    #[test]
+    fn check_shared_nodes() {
+        let mut rng = Rng::new(5);
+        let p = params(&mut rng, &[vec![3, 3], vec![3]]);
+        check_gradients(&p, shared_node_model, EPSILON, TOLERANCE).unwrap();
+        check_forward_mode(&p, shared_node_model);
+
+        // x1 is the first input of the sum, and also an input of
+        // exp().
+        let model = |p: &Params<f64>| {
+            let x = p.variable("x1");
+            &x + &x.exp()
+        };
+        check_gradients(&p, &model, EPSILON, TOLERANCE).unwrap();
+        check_forward_mode(&p, &model);
+    }
+
+    #[test]
+    fn sin_hvp() {
+        // The Taylor approximation x - x^3/6 + x^5/120 has the
+        // derivative 1 - x^2/2 + x^4/24 (approximately cos(x))
+        // and second derivative -x + x^3/6 (approximately -sin(x)).
+        let mut p = Params::new();
+        p.insert("x", Tensor{shape: vec![3], data: vec![0f64, 0.5f64, 1f64]});
+        let sin_sum = p.variable("x").sin_taylor().sum_all();
+        let ones = Tensor{shape: vec![3], data: vec![1f64; 3]};
+        let hvp = sin_sum.hvp(&Gradient::new("x".to_string(), ones.clone()));
+        assert_close("hvp", &hvp.0["x"], &p.get("x").map(|x| -x + x.powi(3) / 6f64));
+        let jvp = sin_sum.jvp(&Gradient::new("x".to_string(), ones));
+        let cos = p.get("x").map(|x| 1f64 - x * x / 2f64 + x.powi(4) / 24f64);
+        assert_close("jvp", &jvp, &cos.sum_to(&[]));
+    }
+
+    #[test]
+    fn higher_order_gradients() {
+        // Differentiating the gradient of sin_taylor() gives the
+        // same second derivative as hvp() (see sin_hvp).
+        let mut p = Params::new();
+        p.insert("x", Tensor{shape: vec![3], data: vec![0f64, 0.5f64, 1f64]});
+        let cos = &p.variable("x").sin_taylor().sum_all().grad()["x"];
+        let expected = p.get("x").map(|x| 1f64 - x * x / 2f64 + x.powi(4) / 24f64);
+        assert_close("cos", cos.value(), &expected);
+        let sin = cos.sum_all().backward_scalar();
+        assert_close("-sin", &sin.0["x"], &p.get("x").map(|x| -x + x.powi(3) / 6f64));
+
+        // The third derivative of x^5 is 60x^2.
+        let mut grad = p.variable("x").pow(5f64).sum_all();
+        for _ in 0..2 {
+            grad = grad.grad()["x"].sum_all();
+        }
+        assert_close("d3", &grad.backward_scalar().0["x"], &p.get("x").map(|x| 60f64 * x * x));
+
+        let mut rng = Rng::new(16);
+        for i in 0..10 {
+            let shape = random_shape(&mut rng);
+            let other = broadcastable_shape(&mut rng, &shape);
+            let axis = (rng.next_u64() % shape.len() as u64) as usize;
+            let mut p = params(&mut rng, &[shape, other]);
+            let y = p.get("x1").map(|y| y.abs() + 0.5);
+            p.insert("x1", y);
+            let (a, b) = if i % 2 == 0 { ("x0", "x1") } else { ("x1", "x0") };
+            check_backward_graph(&p, |p| {
+                &(&p.variable(a) * &p.variable(b)) - &p.variable(a).tanh()
+            });
+            check_backward_graph(&p, |p| {
+                (&p.variable("x0") / &p.variable("x1")).sigmoid().sum(axis)
+            });
+            check_backward_graph(&p, |p| {
+                (&p.variable("x0") + &p.variable("x1").log()).exp().mean(axis)
+            });
+            check_backward_graph(&p, |p| {
+                p.variable("x1").pow(2.5).mean_all() * p.variable("x0").max(axis)
+            });
+            check_backward_graph(&p, |p| p.variable("x0").relu().pow(3f64).sum_all());
+        }
+
+        for i in 0..4 {
+            check_backward_graph(&params(&mut rng, &[vec![3, 3], vec![3]]), shared_node_model);
+            let p = params(&mut rng, &[vec![2, 3], vec![3, 3], vec![2, 3]]);
+            check_backward_graph(&p, |p| {
+                p.variable("x0").matmul(&p.variable("x1")).transpose().tanh()
+            });
+            check_backward_graph(&p, |p| p.variable("x0").softmax_cross_entropy(&p.variable("x2")));
+
+            let rank = 1 + i % 2;
+            let input = spatial_shape(&mut rng, rank, 2, 3);
+            let mut kernel = vec![2, 2];
+            kernel.extend(vec![2; rank]);
+            let p = params(&mut rng, &[input, kernel]);
+            let conv = |p: &Params<f64>| if rank == 1 {
+                p.variable("x0").conv1d(&p.variable("x1"), 1 + i / 2, i / 2, 1)
+            } else {
+                p.variable("x0").conv2d(&p.variable("x1"), 1 + i / 2, i / 2, 1)
+            };
+            check_backward_graph(&p, |p| conv(p).tanh());
+            check_backward_graph(&p, |p| conv(p).avg_pool(2, 1).pow(2f64));
+            check_backward_graph(&p, |p| (&conv(p) * &conv(p)).max_pool(2, 1));
+        }
+        assert_reloads(&params(&mut rng, &[vec![2, 3]]).variable("x0").transpose());
+
+        // The gradient of a broadcast operand is a SumTo, which
+        // reloads. A Vjp can't be saved.
+        let p = params(&mut rng, &[vec![2, 3], vec![3]]);
+        let out_grad = Node::new(Constant(p.get("x0").map(|x| x * x), "g".to_string()));
+        let y = &p.variable("x0") * &p.variable("x1").exp();
+        let grad = Tape::new(&y).backward_graph(&out_grad);
+        assert!(Tape::new(&grad["x1"]).to_json(false, None).unwrap().contains("\"op\":\"SumTo\""));
+        assert_reloads(&grad["x1"]);
+        let grad = p.variable("x0").sum(0).sum_all().grad();
+        let json = Tape::new(&grad["x0"]).to_json(false, None);
+        assert_eq!(json.err().unwrap(), "cannot save op Vjp");
+    }
+
+    // A Res that doubles its input but claims the gradient
+    // is the identity.
+    struct WrongRes {
+        input: Node<f64>,
+        out: Tensor<f64>
+    }
+
+    impl Res<f64> for WrongRes {
+        fn value(&self) -> &Tensor<f64> {
+            &self.out
+        }
+
+        fn name(&self) -> String {
+            format!("Wrong<{}>", self.input.name())
+        }
+
+        fn op(&self) -> &'static str {
+            "Wrong"
+        }
+
+        fn inputs(&self) -> Vec<Node<f64>> {
+            vec![self.input.clone()]
+        }
+
+        fn backward(&self, out_grad: &Tensor<f64>) -> Vec<Tensor<f64>> {
+            vec![out_grad.clone()]
+        }
+
+        fn jvp(&self, in_tangents: &[&Tensor<f64>]) -> Tensor<f64> {
+            in_tangents[0].clone()
+        }
+
+        fn backward_jvp(&self, _: &[&Tensor<f64>], _: &Tensor<f64>,
+                        dg: &Tensor<f64>) -> Vec<Tensor<f64>> {
+            vec![dg.clone()]
+        }
+
+        fn backward_graph(&self, _: &Node<f64>, out_grad: &Node<f64>) -> Vec<Node<f64>> {
+            vec![out_grad.clone()]
+        }
+    }
+
+    #[test]
+    fn check_detects_errors() {
+        let mut rng = Rng::new(6);
+        let p = params(&mut rng, &[vec![4]]);
+        let res = check_gradients(&p, |p| {
+            let x = p.variable("x0");
+            let out = x.value() * 2f64;
+            Node::new(WrongRes{input: x, out: out})
+        }, EPSILON, TOLERANCE);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let text = "{\"a\": [1, -2.5e3, null, true], \"b\\n\": {}, \"c\": \"\\u0041\\\"\"}";
+        let doc = Json::parse(text).unwrap();
+        assert_eq!(doc.field("a").unwrap(), &Json::Array(vec![Json::Number(1f64),
+            Json::Number(-2500f64), Json::Null, Json::Bool(true)]));
+        assert_eq!(doc.field("c").unwrap().as_str().unwrap(), "A\"");
+        assert_eq!(Json::parse(&doc.to_string()).unwrap(), doc);
+        assert!(Json::parse("[1, 2").is_err());
+        assert!(Json::parse("{} x").is_err());
+
+        // Surrogate pairs make one character. Unpaired halves
+        // are errors.
+        let pair = Json::parse("\"\\ud83d\\ude00!\"").unwrap();
+        assert_eq!(pair.as_str().unwrap(), "\u{1f600}!");
+        for text in &["\"\\ud83d\"", "\"\\ude00\"", "\"\\ud83d\\u0041\"", "\"\\ud83d\\ud83d\""] {
+            assert!(Json::parse(text).err().unwrap().starts_with("unpaired surrogate"), "{}", text);
+        }
+
+        // Every escape in the JSON spec, and nothing else.
+        let escapes = Json::parse("\"\\\"\\\\\\/\\b\\f\\n\\r\\t\"").unwrap();
+        assert_eq!(escapes.as_str().unwrap(), "\"\\/\u{8}\u{c}\n\r\t");
+        assert_eq!(Json::parse(&escapes.to_string()).unwrap(), escapes);
+        for text in &["\"\\x\"", "\"\\u41\"", "\"\\u004g\"", "\"\\u+041\""] {
+            assert!(Json::parse(text).err().unwrap().starts_with("bad escape"), "{}", text);
+        }
+    }
+
+    #[test]
+    fn graph_json_round_trip() {
+        let mut rng = Rng::new(8);
+        let p = params(&mut rng, &[vec![3, 3], vec![3]]);
+        let output = (shared_node_model(&p).pow(2f64).max(1) +
+            p.variable("x1").sigmoid().mean(0)).exp().sum_all();
+        let tape = Tape::new(&output);
+        let out_grad = Tensor{shape: vec![], data: vec![1f64]};
+        let json = tape.to_json(true, Some(&out_grad)).unwrap();
+        let loaded = Tape::from_json(&json).unwrap();
+
+        assert_eq!(loaded.0.len(), tape.0.len());
+        for (a, b) in loaded.0.iter().zip(tape.0.iter()) {
+            assert_eq!(a.name(), b.name());
+            assert_eq!(a.value().data, b.value().data);
+        }
+        let (expected, actual) = (tape.backward(&out_grad), loaded.backward(&out_grad));
+        for name in &["x0", "x1"] {
+            assert_eq!(actual.0[*name].data, expected.0[*name].data);
+        }
+    }
+
+    #[test]
+    fn graph_dot() {
+        let x = Node::new(Variable::new("x".to_string(),
+            Tensor{shape: vec![2], data: vec![1f64, 2f64]}));
+        let y = (&x * &x).sum(0);
+        let dot = Tape::new(&y).to_dot(true, Some(&Tensor{shape: vec![], data: vec![1f64]}));
+        assert!(dot.starts_with("digraph {"));
+        assert!(dot.contains(
+            "n0 [label=\"Variable x\\nshape=[2]\\nvalue=[1.0, 2.0]\\ngrad=[2.0, 4.0]\"];"));
+        assert!(dot.contains("n2 [label=\"Sum axis=0"));
+        assert_eq!(dot.matches("n0 -> n1;").count(), 2);
+        assert!(dot.contains("n1 -> n2;"));
+    }
 }
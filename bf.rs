@@ -1,19 +1,1188 @@
 // A Brainfuck (https://en.wikipedia.org/wiki/Brainfuck)
 // interpreter written in Rust.
+//
+// Programs are first parsed into a list of Ops, which merges
+// runs of +-<> into single instructions, precomputes the
+// target of every jump, and replaces common loops like [-]
+// (clear) and [->++>+<<] (multiply) with a single Op.
+//
+// On bench.bf, the Op interpreter takes about 0.37s, while
+// walking the raw characters and searching for the matching
+// bracket on every jump takes about 3.0s (8x slower). With
+// --jit, the Ops are compiled to x86-64 machine code, which
+// takes about 0.17s (18x faster than the characters). Other
+// platforms fall back to the Op interpreter. Run
+// `bf --bench bench.bf` to compare them on another program.
+//
+// Programs in the wild assume different tape sizes, cell
+// widths and end-of-input behaviors, so these can be set on
+// the command line (see USAGE).
+//
+// To run programs from other code, parse() them and give
+// them to an Interpreter, which takes any Read and Write, like
+// in-memory buffers, and can stop after a number of steps
+// (--steps on the command line). I/O errors stop the program
+// with an Error instead of a panic.
+//
+// `bf --emit c` or `--emit rust` prints a program as C or
+// Rust source instead of running it, with the same Ops and
+// options, so hot programs can be compiled natively.
+//
+// Unmatched brackets are reported with their line and column
+// before the program runs, and so is moving off the end of
+// the tape while it runs, unless the tape is set to grow or
+// wrap around.
+//
+// `bf --profile` counts how many times each Op runs, and
+// prints the loops that went round the most with where they
+// are in the source, which shows what the parser should
+// simplify next.
+//
+// `bf --debug` steps through a program instead, showing the
+// next Op and the cells around the current one, and stops at
+// each '#' in the source when continuing. Type help at the
+// (bf) prompt for the commands. `bf --dump` just prints the
+// cells at each '#' instead.
+//
+// `bf --generate` goes the other way, and prints a program
+// that prints its input, like `bf --generate --input hi`.
+//
+// Ook! programs (*.ook) are translated to Brainfuck while
+// parsing. pbrain programs (*.pb) add procedures: '(' defines
+// one numbered by the current cell, up to its ')', and ':'
+// calls the one numbered by the current cell. They run on the
+// same tape, but only in the interpreter, so --jit falls back
+// to it and --emit refuses them.
 
+use std::collections::HashMap;
+use std::env;
+use std::fmt::{Display, Formatter};
 use std::fs::File;
-use std::io::{Read, Write, stdin, stdout};
+use std::io::{BufRead, BufReader, Read, Write, stderr, stdin, stdout};
 use std::iter::FromIterator;
+use std::process;
+use std::time::Instant;
 
 const BUFFER_SIZE: usize = 8192;
 
+// How deep pbrain procedures can call each other.
+const MAX_CALLS: usize = 1 << 20;
+
+const USAGE: &str = "Usage: bf [options] [program.bf]
+Options:
+  -e CODE              run CODE instead of a file
+  -i FILE              read input from FILE instead of stdin
+  --input TEXT         use TEXT as the input
+  --tape N             use N cells (default 8192)
+  --cell 8|16|32       use cells of this many bits (default 8)
+  --eof leave|zero|-1  what ',' does at the end of the input (default leave)
+  --tape-mode fixed|grow|wrap
+                       what moving off the end of the tape does (default
+                       fixed, which is an error)
+  --dialect bf|ook|pbrain
+                       the language of the program (default from the
+                       extension: .ook is Ook!, .pb and .pbrain are pbrain,
+                       anything else is Brainfuck)
+  --emit c|rust        print the program as C or Rust source instead of
+                       running it
+  --jit                compile the program to x86-64 machine code first
+  --steps N            stop with an error after running N Ops
+  --profile            print how often each loop and Op ran to stderr
+  --debug              step through the program, stopping at each '#'.
+                       Commands are read from stdin, so the program's
+                       input has to be given with -i or --input
+  --dump               print the cells around the current one to stderr at
+                       each '#'
+  --bench              time the program against the character interpreter
+                       and the JIT. This only runs Brainfuck on the default
+                       tape, so it can't be given --tape, --cell, --eof or
+                       --tape-mode
+  --generate           print a program that prints the input, instead of
+                       running one";
+
+// What ',' does when there is no more input.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Eof {
+    // Leave the cell as it was.
+    Leave,
+    Zero,
+    // Store -1, i.e. the largest cell value.
+    MinusOne
+}
+
+// What moving off either end of the tape does.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TapeMode {
+    // Stop with an error.
+    Fixed,
+    // Add cells on the right. Moving left of the first cell
+    // is still an error.
+    Grow,
+    // Go around to the other end.
+    Wrap
+}
+
+// The language a program is written in. Ook! programs are
+// translated to Brainfuck, and pbrain adds procedures to it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Dialect {
+    Brainfuck,
+    Ook,
+    Pbrain
+}
+
+// What --emit translates a program to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Lang {
+    C,
+    Rust
+}
+
+// Where to read a program or its input from.
+#[derive(Clone, Debug, PartialEq)]
+enum Source {
+    File(String),
+    Text(String),
+    Stdin
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Options {
+    program: Source,
+    input: Source,
+    tape_size: usize,
+    cell_bits: u32,
+    eof: Eof,
+    tape_mode: TapeMode,
+    dialect: Dialect,
+    emit: Option<Lang>,
+    jit: bool,
+    debug: bool,
+    // Print the cells around the current one at each '#'.
+    dump: bool,
+    steps: Option<u64>,
+    profile: bool,
+    bench: bool,
+    // Print a program that prints the input instead.
+    generate: bool
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Op {
+    // Add to the current cell. The amount wraps around.
+    Add(i32),
+    Move(isize),
+    Output,
+    Input,
+    // Jump past the matching JumpIfNonZero if the current
+    // cell is zero. Both jumps hold the index of the other.
+    JumpIfZero(usize),
+    JumpIfNonZero(usize),
+    // Set the current cell to zero.
+    Clear,
+    // Add the current cell times a factor to the cell at an
+    // offset. A multiply loop becomes a Mul for each target
+    // followed by a Clear.
+    Mul(isize, i32),
+    // A '#', where the debugger stops. Only parsed for --debug
+    // and --dump.
+    Breakpoint,
+    // A pbrain '(', which defines a procedure numbered by the
+    // current cell, and jumps past the Return at the index it
+    // holds.
+    Define(usize),
+    // A pbrain ':', which calls the procedure numbered by the
+    // current cell.
+    Call,
+    // A pbrain ')'.
+    Return
+}
+
+// A position in the source, counting from 1.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub struct Pos {
+    pub line: usize,
+    pub col: usize
+}
+
+impl Pos {
+    // Move past the character c.
+    fn advance(&mut self, c: char) {
+        if c == '\n' {
+            *self = Pos{line: self.line + 1, col: 1};
+        } else {
+            self.col += 1;
+        }
+    }
+}
+
+impl Display for Pos {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
+// A syntax error, or an error while running a program, at
+// the position of the instruction that caused it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Error {
+    pos: Pos,
+    message: String
+}
+
+impl Error {
+    pub fn pos(&self) -> Pos {
+        self.pos
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "{}: {}", self.pos, self.message)
+    }
+}
+
+// The Ops of a program, and the position in the source where
+// each one starts. Ops made from a whole loop start at its
+// '['.
+pub struct Program {
+    ops: Vec<Op>,
+    positions: Vec<Pos>
+}
+
+impl Program {
+    fn push(&mut self, op: Op, pos: Pos) {
+        self.ops.push(op);
+        self.positions.push(pos);
+    }
+
+    fn pop(&mut self) -> Option<Pos> {
+        self.ops.pop();
+        self.positions.pop()
+    }
+
+    fn truncate(&mut self, len: usize) {
+        self.ops.truncate(len);
+        self.positions.truncate(len);
+    }
+
+    // Whether this uses pbrain procedures, which only run() and
+    // Interpreter can run.
+    fn has_procedures(&self) -> bool {
+        self.ops.iter().any(|op| match *op {
+            Op::Define(_) | Op::Call | Op::Return => true,
+            _ => false
+        })
+    }
+}
+
+// The cells of a program, which wrap around at a width of
+// 8, 16 or 32 bits. They're stored as u32s and masked.
+pub struct Tape {
+    cells: Vec<u32>,
+    mask: u32,
+    mode: TapeMode
+}
+
+impl Tape {
+    pub fn new(size: usize, bits: u32, mode: TapeMode) -> Tape {
+        let mask = if bits == 32 { u32::max_value() } else { (1 << bits) - 1 };
+        Tape{cells: vec![0; size], mask: mask, mode: mode}
+    }
+
+    // Find the cell at an offset from ptr, growing the tape
+    // or wrapping around if the mode allows it.
+    fn offset(&mut self, ptr: usize, offset: isize) -> Result<usize, String> {
+        let (target, len) = (ptr as isize + offset, self.cells.len() as isize);
+        if target >= 0 && target < len {
+            return Ok(target as usize);
+        }
+        match self.mode {
+            TapeMode::Wrap => Ok(target.rem_euclid(len) as usize),
+            TapeMode::Grow if target >= len => {
+                let size = (target as usize + 1).max(2 * self.cells.len());
+                self.cells.resize(size, 0);
+                Ok(target as usize)
+            }
+            _ if target < 0 => Err(format!("moved to cell {}, left of the first cell", target)),
+            _ => Err(format!("moved to cell {}, past the last cell ({})", target, len - 1))
+        }
+    }
+}
+
+// Where a running program is.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct State {
+    code_ptr: usize,
+    data_ptr: usize
+}
+
+// The pbrain procedures of a running program. These are kept
+// out of State, which is copied on every step.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Procedures {
+    // The Op::Define of each procedure, by number.
+    defined: HashMap<u32, usize>,
+    // The Op::Call of each procedure that is running.
+    calls: Vec<usize>
+}
+
 fn main() {
-    let code = read_program("program.bf");
-    let mut data = Vec::<u8>::with_capacity(BUFFER_SIZE);
-    for _ in 0..BUFFER_SIZE {
-        data.push(0);
+    let args = Vec::<String>::from_iter(env::args().skip(1));
+    let options = match parse_args(&args) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("bf: {}\n{}", err, USAGE);
+            process::exit(2);
+        }
+    };
+    let mut input: Box<dyn Read> = match options.input {
+        Source::File(ref path) => match File::open(path) {
+            Ok(f) => Box::new(f),
+            Err(err) => {
+                eprintln!("bf: {}: {}", path, err);
+                process::exit(1);
+            }
+        },
+        Source::Text(ref text) => Box::new(text.as_bytes()),
+        Source::Stdin => Box::new(stdin())
+    };
+    if options.generate {
+        print!("{}", generate(&read_all(&mut input, &options.input)));
+        return;
+    }
+    let (name, code) = match options.program {
+        Source::File(ref path) => match read_program(path) {
+            Ok(code) => (path.as_str(), code),
+            Err(err) => {
+                eprintln!("bf: {}: {}", path, err);
+                process::exit(1);
+            }
+        },
+        Source::Text(ref text) => ("-e", Vec::<char>::from_iter(text.chars())),
+        Source::Stdin => panic!("Programs can't be read from stdin.")
+    };
+    let program = match parse_dialect(&code, options.dialect, options.debug || options.dump) {
+        Ok(program) => program,
+        Err(err) => {
+            eprintln!("bf: {}:{}", name, err);
+            process::exit(1);
+        }
+    };
+    if options.bench {
+        // Each run gets the same input.
+        bench(&code, &read_all(&mut input, &options.input));
+        return;
+    }
+    if let Some(lang) = options.emit {
+        if program.has_procedures() {
+            eprintln!("bf: {}: pbrain procedures can't be emitted", name);
+            process::exit(1);
+        }
+        print!("{}", transpile(&program, &options, name, lang));
+        return;
+    }
+
+    let mut tape = Tape::new(options.tape_size, options.cell_bits, options.tape_mode);
+    let stop = if options.debug {
+        let (mut commands, mut out, mut log) = (BufReader::new(stdin()), stdout(), stderr());
+        debug(&program, &mut tape, options.eof, &mut input, &mut out, &mut commands, &mut log)
+            .err().map_or(Stop::Finished, Stop::Error)
+    } else if options.jit {
+        run_jit(&program, &mut tape, options.eof, &mut input, &mut stdout())
+            .err().map_or(Stop::Finished, Stop::Error)
+    } else if options.steps.is_none() && !options.profile && !options.dump {
+        // Without a budget, there's no need to count steps.
+        run(&program, &mut tape, options.eof, &mut input, &mut stdout())
+            .err().map_or(Stop::Finished, Stop::Error)
+    } else {
+        let mut interpreter = Interpreter::new(program, tape, options.eof, input, stdout());
+        if options.profile {
+            interpreter.count_ops();
+        }
+        let mut stop = interpreter.run(options.steps);
+        while let Stop::Breakpoint = stop {
+            // Only --dump parses breakpoints here, and the
+            // budget counts the steps before them too.
+            interpreter.out.flush().expect("Could not flush stdout.");
+            let pos = interpreter.program.positions[interpreter.state.code_ptr - 1];
+            eprint!("bf: {}:{}: ", name, pos);
+            show_tape(&interpreter.tape, &interpreter.state, 4, &mut stderr());
+            stop = interpreter.run(options.steps.map(|steps| steps - interpreter.steps));
+        }
+        if let Some(ref counts) = interpreter.counts {
+            interpreter.out.flush().expect("Could not flush stdout.");
+            // The program has parsed, so this can't fail.
+            let source = commands(&code, options.dialect, options.dump).unwrap();
+            print_profile(&interpreter.program, &source, counts, 10, &mut stderr())
+                .expect("Could not print profile.");
+        }
+        stop
+    };
+    let message = match stop {
+        Stop::Finished => return,
+        Stop::OutOfSteps => format!(": stopped after {} steps", options.steps.unwrap()),
+        Stop::Breakpoint => unreachable!(),
+        Stop::Error(err) => format!(":{}", err)
+    };
+    stdout().flush().expect("Could not flush stdout.");
+    eprintln!("\nbf: {}{}", name, message);
+    process::exit(1);
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut options = Options{
+        program: Source::File("program.bf".to_string()),
+        input: Source::Stdin,
+        tape_size: BUFFER_SIZE,
+        cell_bits: 8,
+        eof: Eof::Leave,
+        tape_mode: TapeMode::Fixed,
+        dialect: Dialect::Brainfuck,
+        emit: None,
+        jit: false,
+        debug: false,
+        dump: false,
+        steps: None,
+        profile: false,
+        bench: false,
+        generate: false
+    };
+    let mut has_program = false;
+    let mut dialect = None;
+    // The last option that changes the tape or EOF, which --bench
+    // can't follow.
+    let mut tape_option = None;
+    let mut i = 0;
+    while i < args.len() {
+        let arg = args[i].as_str();
+        i += 1;
+        match arg {
+            "--jit" => options.jit = true,
+            "--debug" => options.debug = true,
+            "--dump" => options.dump = true,
+            "--profile" => options.profile = true,
+            "--bench" => options.bench = true,
+            "--generate" => options.generate = true,
+            _ => ()
+        }
+        if ["--jit", "--debug", "--dump", "--profile", "--bench", "--generate"].contains(&arg) {
+            continue;
+        }
+        if !arg.starts_with('-') || arg == "-e" {
+            if has_program {
+                return Err("more than one program given".to_string());
+            }
+            has_program = true;
+        }
+        if !arg.starts_with('-') {
+            options.program = Source::File(arg.to_string());
+            continue;
+        }
+
+        // Every other option takes a value.
+        if !["-e", "-i", "--input", "--tape", "--cell", "--eof", "--tape-mode", "--dialect",
+            "--emit", "--steps"].contains(&arg) {
+            return Err(format!("unknown option: {}", arg));
+        }
+        let value = match args.get(i) {
+            Some(value) => value.clone(),
+            None => return Err(format!("{} needs a value", arg))
+        };
+        i += 1;
+        if ["--tape", "--cell", "--eof", "--tape-mode"].contains(&arg) {
+            tape_option = Some(arg);
+        }
+        match arg {
+            "-e" => options.program = Source::Text(value),
+            "-i" => options.input = Source::File(value),
+            "--input" => options.input = Source::Text(value),
+            "--tape" => options.tape_size = match value.parse::<usize>() {
+                Ok(size) if size > 0 => size,
+                _ => return Err(format!("invalid tape size: {}", value))
+            },
+            "--cell" => options.cell_bits = match value.as_str() {
+                "8" => 8,
+                "16" => 16,
+                "32" => 32,
+                _ => return Err(format!("invalid cell width: {}", value))
+            },
+            "--eof" => options.eof = match value.as_str() {
+                "leave" => Eof::Leave,
+                "zero" => Eof::Zero,
+                "-1" => Eof::MinusOne,
+                _ => return Err(format!("invalid EOF behavior: {}", value))
+            },
+            "--tape-mode" => options.tape_mode = match value.as_str() {
+                "fixed" => TapeMode::Fixed,
+                "grow" => TapeMode::Grow,
+                "wrap" => TapeMode::Wrap,
+                _ => return Err(format!("invalid tape mode: {}", value))
+            },
+            "--steps" => match value.parse::<u64>() {
+                Ok(x) => options.steps = Some(x),
+                _ => return Err(format!("invalid step count: {}", value))
+            },
+            "--dialect" => dialect = match value.as_str() {
+                "bf" => Some(Dialect::Brainfuck),
+                "ook" => Some(Dialect::Ook),
+                "pbrain" => Some(Dialect::Pbrain),
+                _ => return Err(format!("invalid dialect: {}", value))
+            },
+            "--emit" => options.emit = match value.as_str() {
+                "c" => Some(Lang::C),
+                "rust" => Some(Lang::Rust),
+                _ => return Err(format!("invalid language: {}", value))
+            },
+            _ => unreachable!()
+        }
+    }
+    if options.steps.is_some() && (options.jit || options.debug) {
+        return Err("--steps can't be used with --jit or --debug".to_string());
+    }
+    if options.profile && (options.jit || options.debug) {
+        return Err("--profile can't be used with --jit or --debug".to_string());
+    }
+    if options.dump && (options.jit || options.debug) {
+        return Err("--dump can't be used with --jit or --debug".to_string());
+    }
+    // The debugger reads its commands from stdin, so the program
+    // can't read from there too.
+    if options.debug && options.input == Source::Stdin {
+        return Err("--debug needs the program's input from -i or --input".to_string());
+    }
+    if let (true, Some(arg)) = (options.bench, tape_option) {
+        return Err(format!("{} can't be used with --bench", arg));
+    }
+    // Without --dialect, go by the file extension.
+    options.dialect = match (dialect, &options.program) {
+        (Some(dialect), _) => dialect,
+        (None, &Source::File(ref path)) if path.ends_with(".ook") => Dialect::Ook,
+        (None, &Source::File(ref path)) if path.ends_with(".pb") || path.ends_with(".pbrain") =>
+            Dialect::Pbrain,
+        (None, _) => Dialect::Brainfuck
+    };
+    if options.bench && options.dialect != Dialect::Brainfuck {
+        return Err("--bench only runs Brainfuck programs".to_string());
+    }
+    Ok(options)
+}
+
+fn read_program(path: &str) -> std::io::Result<Vec<char>> {
+    let mut res = String::new();
+    File::open(path)?.read_to_string(&mut res)?;
+    Ok(Vec::<char>::from_iter(res.chars()))
+}
+
+// Read the rest of the input. If it can't be read, like a
+// directory given to -i, report that and exit.
+fn read_all(input: &mut dyn Read, source: &Source) -> Vec<u8> {
+    let mut res = Vec::<u8>::new();
+    if let Err(err) = input.read_to_end(&mut res) {
+        let name = match *source {
+            Source::File(ref path) => path.as_str(),
+            _ => "stdin"
+        };
+        eprintln!("bf: {}: {}", name, err);
+        process::exit(1);
+    }
+    res
+}
+
+pub fn parse(code: &[char]) -> Result<Program, Error> {
+    parse_dialect(code, Dialect::Brainfuck, false)
+}
+
+// Parse a program in a dialect, optionally with an
+// Op::Breakpoint for each '#'. Otherwise '#' is a comment like
+// any other character.
+fn parse_dialect(code: &[char], dialect: Dialect, breakpoints: bool) -> Result<Program, Error> {
+    let mut program = Program{ops: Vec::new(), positions: Vec::new()};
+    // The JumpIfZeros and Defines that haven't been closed yet.
+    let mut opens = Vec::<usize>::new();
+    for (c, pos) in commands(code, dialect, breakpoints)? {
+        match c {
+            '+' => push_add(&mut program, 1, pos),
+            '-' => push_add(&mut program, -1, pos),
+            '>' => push_move(&mut program, 1, pos),
+            '<' => push_move(&mut program, -1, pos),
+            '.' => program.push(Op::Output, pos),
+            ',' => program.push(Op::Input, pos),
+            '#' => program.push(Op::Breakpoint, pos),
+            ':' => program.push(Op::Call, pos),
+            '(' => {
+                opens.push(program.ops.len());
+                program.push(Op::Define(0), pos);
+            }
+            ')' => {
+                let open = match opens.pop() {
+                    Some(open) if program.ops[open] == Op::Define(0) => open,
+                    _ => return Err(Error{pos: pos, message: "unmatched ')'".to_string()})
+                };
+                program.ops[open] = Op::Define(program.ops.len());
+                program.push(Op::Return, pos);
+            }
+            '[' => {
+                opens.push(program.ops.len());
+                program.push(Op::JumpIfZero(0), pos);
+            }
+            ']' => {
+                let open = match opens.pop() {
+                    Some(open) if program.ops[open] == Op::JumpIfZero(0) => open,
+                    _ => return Err(Error{pos: pos, message: "unmatched ']'".to_string()})
+                };
+                if let Some(body) = simplify_loop(&program.ops[open + 1..]) {
+                    let open_pos = program.positions[open];
+                    program.truncate(open);
+                    for op in body {
+                        program.push(op, open_pos);
+                    }
+                } else {
+                    program.ops[open] = Op::JumpIfZero(program.ops.len());
+                    program.push(Op::JumpIfNonZero(open), pos);
+                }
+            }
+            _ => ()
+        }
+    }
+    match opens.pop() {
+        Some(open) => {
+            let message = if program.ops[open] == Op::Define(0) {
+                "unmatched '('"
+            } else {
+                "unmatched '['"
+            };
+            Err(Error{pos: program.positions[open], message: message.to_string()})
+        }
+        None => Ok(program)
+    }
+}
+
+// The commands of a program in a dialect, with their
+// positions, as Brainfuck characters. Everything else is a
+// comment.
+fn commands(code: &[char], dialect: Dialect, breakpoints: bool) -> Result<Vec<(char, Pos)>, Error> {
+    let tokens = match dialect {
+        Dialect::Ook => ook_tokens(code)?,
+        Dialect::Brainfuck | Dialect::Pbrain => bf_tokens(code)
+    };
+    let procedures = dialect == Dialect::Pbrain;
+    Ok(Vec::from_iter(tokens.into_iter().filter(|&(c, _)| match c {
+        '+' | '-' | '>' | '<' | '.' | ',' | '[' | ']' => true,
+        '#' => breakpoints,
+        '(' | ')' | ':' => procedures,
+        _ => false
+    })))
+}
+
+// Each character of a program with its position.
+fn bf_tokens(code: &[char]) -> Vec<(char, Pos)> {
+    let mut res = Vec::<(char, Pos)>::new();
+    let mut pos = Pos{line: 1, col: 1};
+    for &c in code {
+        res.push((c, pos));
+        pos.advance(c);
+    }
+    res
+}
+
+// Translate Ook! to Brainfuck, keeping the position of the
+// first word of each command. Ook! has three words, "Ook.",
+// "Ook?" and "Ook!", and each pair of them is one command.
+// Everything else is a comment.
+fn ook_tokens(code: &[char]) -> Result<Vec<(char, Pos)>, Error> {
+    let mut words = Vec::<(char, Pos)>::new();
+    let mut pos = Pos{line: 1, col: 1};
+    let mut i = 0;
+    while i < code.len() {
+        let ook = code[i..].starts_with(&['O', 'o', 'k']) && i + 3 < code.len();
+        if ook && ".?!".contains(code[i + 3]) {
+            words.push((code[i + 3], pos));
+            for &c in &code[i..i + 4] {
+                pos.advance(c);
+            }
+            i += 4;
+        } else {
+            pos.advance(code[i]);
+            i += 1;
+        }
+    }
+    if words.len() % 2 == 1 {
+        let pos = words[words.len() - 1].1;
+        return Err(Error{pos: pos, message: "Ook without a second word".to_string()});
+    }
+    let mut res = Vec::<(char, Pos)>::new();
+    for pair in words.chunks(2) {
+        let c = match (pair[0].0, pair[1].0) {
+            ('.', '?') => '>',
+            ('?', '.') => '<',
+            ('.', '.') => '+',
+            ('!', '!') => '-',
+            ('!', '.') => '.',
+            ('.', '!') => ',',
+            ('!', '?') => '[',
+            ('?', '!') => ']',
+            // "Ook? Ook?" does nothing.
+            _ => continue
+        };
+        res.push((c, pair[0].1));
+    }
+    Ok(res)
+}
+
+// Merge runs of + and -, keeping the position of the first.
+fn push_add(program: &mut Program, amount: i32, pos: Pos) {
+    if let Some(&Op::Add(x)) = program.ops.last() {
+        let first = program.pop().unwrap();
+        if x + amount != 0 {
+            program.push(Op::Add(x + amount), first);
+        }
+    } else {
+        program.push(Op::Add(amount), pos);
+    }
+}
+
+// Merge runs of > and <. Only the net move is checked
+// against the ends of the tape.
+fn push_move(program: &mut Program, offset: isize, pos: Pos) {
+    if let Some(&Op::Move(x)) = program.ops.last() {
+        let first = program.pop().unwrap();
+        if x + offset != 0 {
+            program.push(Op::Move(x + offset), first);
+        }
+    } else {
+        program.push(Op::Move(offset), pos);
+    }
+}
+
+// Replace the body of a loop that only adds and moves, ends
+// up where it started, and decrements the current cell once
+// per iteration. It runs once per unit in the current cell,
+// so it's a Mul for every other cell it touches.
+// [-] and [+] are Clear, since cells wrap around.
+fn simplify_loop(body: &[Op]) -> Option<Vec<Op>> {
+    if body == [Op::Add(-1)] || body == [Op::Add(1)] {
+        return Some(vec![Op::Clear]);
+    }
+    let mut offset = 0isize;
+    let mut deltas = Vec::<(isize, i32)>::new();
+    for op in body {
+        match *op {
+            Op::Add(x) => match deltas.iter().position(|&(o, _)| o == offset) {
+                Some(i) => deltas[i].1 += x,
+                None => deltas.push((offset, x))
+            },
+            Op::Move(x) => offset += x,
+            _ => return None
+        }
+    }
+    if offset != 0 || deltas.iter().find(|&&(o, _)| o == 0) != Some(&(0, -1)) {
+        return None;
+    }
+    let mut res = Vec::<Op>::new();
+    for &(o, x) in &deltas {
+        if o != 0 && x != 0 {
+            res.push(Op::Mul(o, x));
+        }
+    }
+    res.push(Op::Clear);
+    Some(res)
+}
+
+// Run the Op at $code_ptr, then move $code_ptr to the next
+// one. If the Op fails, this returns the error from the
+// enclosing function before changing $code_ptr or $data_ptr.
+// run() and step() both expand this, so that run() can keep
+// the pointers in locals, which is about 15% faster on
+// bench.bf than a loop over step().
+macro_rules! run_op {
+    ($program:expr, $tape:expr, $procedures:expr, $eof:expr, $input:expr, $out:expr, $mask:expr,
+        $code_ptr:ident, $data_ptr:ident) => {{
+        let (program, tape, mask, pos) = ($program, &mut *$tape, $mask, $code_ptr);
+        let error = move |message| Error{pos: program.positions[pos], message: message};
+        let data = &mut tape.cells;
+        match program.ops[$code_ptr] {
+            Op::Add(x) => data[$data_ptr] = data[$data_ptr].wrapping_add(x as u32) & mask,
+            Op::Move(x) => {
+                let target = $data_ptr.wrapping_add(x as usize);
+                $data_ptr = if target < data.len() {
+                    target
+                } else {
+                    tape.offset($data_ptr, x).map_err(error)?
+                };
+            }
+            // Only the low byte of a wider cell is printed.
+            Op::Output => write_byte($out, data[$data_ptr] as u8).map_err(error)?,
+            Op::Input => read_cell($input, $out, $eof, mask, &mut data[$data_ptr]).map_err(error)?,
+            Op::JumpIfZero(target) => {
+                if data[$data_ptr] == 0 {
+                    $code_ptr = target;
+                }
+            }
+            Op::JumpIfNonZero(target) => {
+                if data[$data_ptr] != 0 {
+                    $code_ptr = target;
+                }
+            }
+            Op::Clear => data[$data_ptr] = 0,
+            // The loop that this came from doesn't move at all
+            // when the current cell is zero.
+            Op::Mul(offset, factor) => if data[$data_ptr] != 0 {
+                let x = data[$data_ptr];
+                let target = tape.offset($data_ptr, offset).map_err(error)?;
+                let data = &mut tape.cells;
+                data[target] = data[target].wrapping_add(x.wrapping_mul(factor as u32)) & mask;
+            }
+            Op::Breakpoint => (),
+            // Listing these, rather than matching any other Op,
+            // saves a range check before the jump table.
+            op @ Op::Define(_) | op @ Op::Call | op @ Op::Return => {
+                let cell = data[$data_ptr];
+                $code_ptr = procedure_step(op, $procedures, $code_ptr, cell).map_err(error)?
+            }
+        }
+        $code_ptr += 1;
+    }}
+}
+
+fn run<R: Read, W: Write>(program: &Program, tape: &mut Tape, eof: Eof, input: &mut R, out: &mut W)
+    -> Result<(), Error>
+{
+    let (mut procedures, mask) = (Procedures::default(), tape.mask);
+    let (mut code_ptr, mut data_ptr) = (0, 0);
+    while code_ptr < program.ops.len() {
+        run_op!(program, tape, &mut procedures, eof, input, out, mask, code_ptr, data_ptr);
+    }
+    Ok(())
+}
+
+// A program with its own tape and I/O, like a file and stdout
+// or in-memory buffers, which can be run for a limited number
+// of steps at a time.
+pub struct Interpreter<R: Read, W: Write> {
+    program: Program,
+    tape: Tape,
+    state: State,
+    procedures: Procedures,
+    eof: Eof,
+    input: R,
+    out: W,
+    // How many Ops have run so far.
+    steps: u64,
+    // How many times each Op has run, after count_ops().
+    counts: Option<Vec<u64>>
+}
+
+// Why Interpreter::run returned.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Stop {
+    Finished,
+    // The step budget ran out. Running again continues.
+    OutOfSteps,
+    // An Op::Breakpoint just ran. Running again continues.
+    Breakpoint,
+    Error(Error)
+}
+
+impl<R: Read, W: Write> Interpreter<R, W> {
+    pub fn new(program: Program, tape: Tape, eof: Eof, input: R, out: W) -> Interpreter<R, W> {
+        Interpreter{
+            program: program,
+            tape: tape,
+            state: State{code_ptr: 0, data_ptr: 0},
+            procedures: Procedures::default(),
+            eof: eof,
+            input: input,
+            out: out,
+            steps: 0,
+            counts: None
+        }
+    }
+
+    // Count how many times each Op runs from now on, for
+    // print_profile().
+    pub fn count_ops(&mut self) {
+        self.counts = Some(vec![0; self.program.ops.len()]);
+    }
+
+    // How many times each Op has run, if count_ops() was
+    // called.
+    pub fn counts(&self) -> Option<&[u64]> {
+        self.counts.as_ref().map(|counts| &counts[..])
+    }
+
+    // How many Ops have run so far.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    // The position of the next Op to run, or None once the
+    // program has finished.
+    pub fn pos(&self) -> Option<Pos> {
+        self.program.positions.get(self.state.code_ptr).cloned()
+    }
+
+    pub fn output(&self) -> &W {
+        &self.out
+    }
+
+    pub fn into_output(self) -> W {
+        self.out
+    }
+
+    // Run until the program ends or fails, passes a breakpoint,
+    // or until budget more Ops have run.
+    pub fn run(&mut self, budget: Option<u64>) -> Stop {
+        let end = budget.map_or(u64::max_value(), |budget| self.steps.saturating_add(budget));
+        // Counting is in a separate copy of the loop so that it
+        // doesn't slow down the usual case.
+        match self.counts.take() {
+            Some(mut counts) => {
+                let stop = self.run_until(end, |code_ptr| counts[code_ptr] += 1);
+                self.counts = Some(counts);
+                stop
+            }
+            None => self.run_until(end, |_| ())
+        }
+    }
+
+    // Run until steps reaches end, calling count with the index
+    // of each Op before running it. This works on copies of the
+    // state, which is much faster than going through self.
+    fn run_until<F: FnMut(usize)>(&mut self, end: u64, mut count: F) -> Stop {
+        let (mut state, mut steps) = (self.state, self.steps);
+        let stop = loop {
+            if state.code_ptr >= self.program.ops.len() {
+                break Stop::Finished;
+            }
+            if steps == end {
+                break Stop::OutOfSteps;
+            }
+            count(state.code_ptr);
+            let breakpoint = self.program.ops[state.code_ptr] == Op::Breakpoint;
+            let res = step(&self.program, &mut self.tape, &mut state, &mut self.procedures,
+                self.eof, &mut self.input, &mut self.out);
+            if let Err(err) = res {
+                break Stop::Error(err);
+            }
+            steps += 1;
+            if breakpoint {
+                break Stop::Breakpoint;
+            }
+        };
+        self.state = state;
+        self.steps = steps;
+        stop
+    }
+}
+
+// Print how many times the busiest loops ran and the busiest
+// Ops, at most top of each, from the counts of an Interpreter.
+fn print_profile<W: Write>(program: &Program, commands: &[(char, Pos)], counts: &[u64], top: usize,
+    out: &mut W) -> std::io::Result<()>
+{
+    // A loop starts once each time its '[' runs, and goes round
+    // once each time its ']' runs. Loops that became Muls
+    // aren't loops any more.
+    let mut loops = Vec::<(usize, usize)>::new();
+    for (i, op) in program.ops.iter().enumerate() {
+        if let Op::JumpIfNonZero(open) = *op {
+            loops.push((open, i));
+        }
+    }
+    loops.sort_by_key(|&(open, close)| (u64::max_value() - counts[close], open));
+    writeln!(out, "{} steps", counts.iter().sum::<u64>())?;
+    writeln!(out, "\nloops by iterations:")?;
+    writeln!(out, "  {:>12} {:>10}  {:<12} {}", "iterations", "entries", "span", "source")?;
+    for &(open, close) in loops.iter().take(top) {
+        let (start, end) = (program.positions[open], program.positions[close]);
+        let span = format!("{}-{}", start, end);
+        let source = source_between(commands, start, end, 40);
+        writeln!(out, "  {:>12} {:>10}  {:<12} {}", counts[close], counts[open], span, source)?;
+    }
+
+    let mut ops = Vec::<usize>::from_iter(0..program.ops.len());
+    ops.sort_by_key(|&i| (u64::max_value() - counts[i], i));
+    writeln!(out, "\nops by count:")?;
+    writeln!(out, "  {:>12}  {:<12} {}", "count", "position", "op")?;
+    for &i in ops.iter().take(top) {
+        let position = program.positions[i].to_string();
+        writeln!(out, "  {:>12}  {:<12} {:?}", counts[i], position, program.ops[i])?;
+    }
+    Ok(())
+}
+
+// The commands from start to end, from commands(), with at
+// most max characters.
+fn source_between(commands: &[(char, Pos)], start: Pos, end: Pos, max: usize) -> String {
+    let between = commands.iter().filter(|&&(_, pos)| pos >= start && pos <= end);
+    let mut res = Vec::from_iter(between.map(|&(c, _)| c));
+    if res.len() > max {
+        res.truncate(max - 3);
+        res.extend(&['.', '.', '.']);
+    }
+    String::from_iter(res)
+}
+
+// Run the Op at code_ptr. If it fails, state is left as it
+// was before.
+#[inline(always)]
+fn step<R: Read, W: Write>(program: &Program, tape: &mut Tape, state: &mut State,
+    procedures: &mut Procedures, eof: Eof, input: &mut R, out: &mut W) -> Result<(), Error>
+{
+    let (mut code_ptr, mut data_ptr, mask) = (state.code_ptr, state.data_ptr, tape.mask);
+    run_op!(program, tape, procedures, eof, input, out, mask, code_ptr, data_ptr);
+    *state = State{code_ptr: code_ptr, data_ptr: data_ptr};
+    Ok(())
+}
+
+// Run a pbrain Op at code_ptr, and return the index of the Op
+// before the next one. This is kept out of step() so that
+// Brainfuck programs don't pay for it.
+#[cold]
+#[inline(never)]
+fn procedure_step(op: Op, procedures: &mut Procedures, code_ptr: usize, cell: u32)
+    -> Result<usize, String>
+{
+    match op {
+        Op::Define(end) => {
+            procedures.defined.insert(cell, code_ptr);
+            Ok(end)
+        }
+        Op::Call => match procedures.defined.get(&cell) {
+            Some(_) if procedures.calls.len() == MAX_CALLS =>
+                Err(format!("more than {} nested calls", MAX_CALLS)),
+            Some(&define) => {
+                procedures.calls.push(code_ptr);
+                Ok(define)
+            }
+            None => Err(format!("procedure {} is not defined", cell))
+        },
+        // Define jumps over this, so it only runs at the end of
+        // a call.
+        Op::Return => Ok(procedures.calls.pop().unwrap()),
+        _ => unreachable!()
+    }
+}
+
+const DEBUG_HELP: &str = "Commands:
+  s, step [N]    run the next N Ops (default 1), also an empty line
+  c, continue    run until the next '#' or the end
+  t, tape [N]    show N cells on each side of the current one (default 8)
+  q, quit        stop the program
+  h, help        show this";
+
+// Run a program one Op at a time, reading commands from
+// commands and writing where it stopped to log. This starts
+// before the first Op. If an Op fails, the error is shown and
+// the debugger stays before that Op, so the tape can still be
+// looked at; quitting there returns the error.
+fn debug<R: Read, W: Write, C: BufRead, L: Write>(program: &Program, tape: &mut Tape, eof: Eof,
+    input: &mut R, out: &mut W, commands: &mut C, log: &mut L) -> Result<(), Error>
+{
+    let (mut state, mut procedures) = (State{code_ptr: 0, data_ptr: 0}, Procedures::default());
+    let done = |state: &State| state.code_ptr >= program.ops.len();
+    let at_breakpoint = |state: &State| program.ops[state.code_ptr] == Op::Breakpoint;
+    let (mut line, mut error) = (String::new(), None);
+    show_stop(program, tape, &state, log);
+    while !done(&state) {
+        out.flush().expect("Could not flush stdout.");
+        write!(log, "(bf) ").and_then(|_| log.flush()).expect("Could not write to the debugger.");
+        line.clear();
+        if commands.read_line(&mut line).expect("Could not read a command.") == 0 {
+            return error.map_or(Ok(()), Err);
+        }
+        let words = Vec::<&str>::from_iter(line.split_whitespace());
+        let count = match words.get(1).map(|x| x.parse::<usize>()) {
+            Some(Ok(x)) => Some(x),
+            Some(Err(_)) => {
+                writeln!(log, "invalid count: {}", words[1])
+                    .expect("Could not write to the debugger.");
+                continue;
+            }
+            None => None
+        };
+        let res = match words.first().map(|x| *x) {
+            None | Some("s") | Some("step") => {
+                let mut res = Ok(());
+                for _ in 0..count.unwrap_or(1) {
+                    if done(&state) || res.is_err() {
+                        break;
+                    }
+                    res = step(program, tape, &mut state, &mut procedures, eof, input, out);
+                }
+                res
+            }
+            Some("c") | Some("continue") => {
+                let mut res = step(program, tape, &mut state, &mut procedures, eof, input, out);
+                while res.is_ok() && !done(&state) && !at_breakpoint(&state) {
+                    res = step(program, tape, &mut state, &mut procedures, eof, input, out);
+                }
+                res
+            }
+            Some("t") | Some("tape") => {
+                show_tape(tape, &state, count.unwrap_or(8), log);
+                continue;
+            }
+            Some("q") | Some("quit") => return error.map_or(Ok(()), Err),
+            Some("h") | Some("help") => {
+                writeln!(log, "{}", DEBUG_HELP).expect("Could not write to the debugger.");
+                continue;
+            }
+            Some(command) => {
+                writeln!(log, "unknown command: {} (try help)", command)
+                    .expect("Could not write to the debugger.");
+                continue;
+            }
+        };
+        match res {
+            Ok(()) => show_stop(program, tape, &state, log),
+            Err(ref err) => {
+                writeln!(log, "{}", err).expect("Could not write to the debugger.");
+                show_tape(tape, &state, 4, log);
+            }
+        }
+        error = res.err();
+    }
+    Ok(())
+}
+
+// Show the next Op, or that the program finished, and the
+// cells around the current one.
+fn show_stop<L: Write>(program: &Program, tape: &Tape, state: &State, log: &mut L) {
+    let res = match program.ops.get(state.code_ptr) {
+        Some(&Op::Breakpoint) => writeln!(log, "{}: breakpoint", program.positions[state.code_ptr]),
+        Some(op) => writeln!(log, "{}: {:?}", program.positions[state.code_ptr], op),
+        None => writeln!(log, "finished")
+    };
+    res.expect("Could not write to the debugger.");
+    show_tape(tape, state, 4, log);
+}
+
+// Show width cells on each side of the current one, which is
+// in brackets.
+fn show_tape<L: Write>(tape: &Tape, state: &State, width: usize, log: &mut L) {
+    let start = state.data_ptr.saturating_sub(width);
+    let end = state.data_ptr.saturating_add(width).saturating_add(1).min(tape.cells.len());
+    let mut line = format!("cells {}-{}:", start, end - 1);
+    for i in start..end {
+        if i == state.data_ptr {
+            line.push_str(&format!(" [{}]", tape.cells[i]));
+        } else {
+            line.push_str(&format!(" {}", tape.cells[i]));
+        }
     }
+    writeln!(log, "{}", line).expect("Could not write to the debugger.");
+}
 
+// Run a program by walking its characters, like this
+// interpreter used to. This is only kept to measure the
+// speedup from parsing.
+fn run_chars<R: Read, W: Write>(code: &[char], data: &mut [u8], input: &mut R, out: &mut W) {
     let mut code_ptr: usize = 0;
     let mut data_ptr: usize = 0;
 
@@ -21,18 +1190,20 @@ fn main() {
         match code[code_ptr] {
             '>' => data_ptr += 1,
             '<' => data_ptr -= 1,
-            '+' => data[data_ptr] = increment(data[data_ptr]),
-            '-' => data[data_ptr] = decrement(data[data_ptr]),
-            '.' => write_byte(data[data_ptr]),
-            ',' => data[data_ptr] = read_byte(),
+            '+' => data[data_ptr] = data[data_ptr].wrapping_add(1),
+            '-' => data[data_ptr] = data[data_ptr].wrapping_sub(1),
+            '.' => write_byte(out, data[data_ptr]).expect("Could not write output."),
+            ',' => if let Some(x) = read_byte(input).expect("Could not read input.") {
+                data[data_ptr] = x;
+            },
             '[' => {
                 if data[data_ptr] == 0 {
-                    code_ptr = matching_close(&code, code_ptr);
+                    code_ptr = matching_close(code, code_ptr);
                 }
             }
             ']' => {
                 if data[data_ptr] != 0 {
-                    code_ptr = matching_open(&code, code_ptr);
+                    code_ptr = matching_open(code, code_ptr);
                 }
             }
             _ => ()
@@ -41,63 +1212,1150 @@ fn main() {
     }
 }
 
-fn read_program(path: &str) -> Vec<char> {
-    let mut res = String::new();
-    let mut f = File::open(path).expect("Unable to open file.");
-    f.read_to_string(&mut res).expect("Could not read program.");
-    Vec::<char>::from_iter(res.chars())
+// Time both interpreters and the JIT on a program, and check
+// that they print the same thing.
+fn bench(code: &[char], input: &[u8]) {
+    let (mut expected, mut actual) = (Vec::<u8>::new(), Vec::<u8>::new());
+    let mut jit_actual = Vec::<u8>::new();
+    let start = Instant::now();
+    run_chars(code, &mut vec![0; BUFFER_SIZE], &mut &input[..], &mut expected);
+    let chars_time = start.elapsed();
+
+    let start = Instant::now();
+    let program = parse(code).expect("Could not parse program.");
+    run(&program, &mut Tape::new(BUFFER_SIZE, 8, TapeMode::Fixed), Eof::Leave, &mut &input[..],
+        &mut actual)
+        .expect("Could not run program.");
+    let ops_time = start.elapsed();
+
+    let start = Instant::now();
+    run_jit(&program, &mut Tape::new(BUFFER_SIZE, 8, TapeMode::Fixed), Eof::Leave, &mut &input[..],
+        &mut jit_actual)
+        .expect("Could not run program.");
+    let jit_time = start.elapsed();
+
+    assert!(actual == expected, "The interpreters printed different output.");
+    assert!(jit_actual == expected, "The JIT printed different output.");
+    println!("{} chars parsed into {} ops", code.len(), program.ops.len());
+    println!("chars: {:?}", chars_time);
+    println!("ops:   {:?} ({:.1}x faster)", ops_time,
+        chars_time.as_secs_f64() / ops_time.as_secs_f64());
+    println!("jit:   {:?} ({:.1}x faster)", jit_time,
+        chars_time.as_secs_f64() / jit_time.as_secs_f64());
+}
+
+// Run a program by compiling it to x86-64 machine code, which
+// behaves exactly like run() but is faster.
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn run_jit<R: Read, W: Write>(program: &Program, tape: &mut Tape, eof: Eof, input: &mut R,
+    out: &mut W) -> Result<(), Error>
+{
+    if program.has_procedures() {
+        return run(program, tape, eof, input, out);
+    }
+    jit::run(program, tape, eof, input, out)
+}
+
+// There's no JIT for other platforms, or for pbrain procedures,
+// so interpret instead.
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+fn run_jit<R: Read, W: Write>(program: &Program, tape: &mut Tape, eof: Eof, input: &mut R,
+    out: &mut W) -> Result<(), Error>
+{
+    run(program, tape, eof, input, out)
+}
+
+// The compiled code keeps the cells in rbx, data_ptr in r12,
+// the number of cells in r13 and a Context in r14. It calls
+// back into Rust for I/O and for moves that leave the tape,
+// so those work the same as in run().
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+mod jit {
+    use std::io::{Read, Write};
+    use std::mem;
+    use std::ptr;
+    use super::{Eof, Error, Op, Program, Tape, read_cell, write_byte};
+
+    const PROT_READ: i32 = 1;
+    const PROT_WRITE: i32 = 2;
+    const PROT_EXEC: i32 = 4;
+    const MAP_PRIVATE: i32 = 2;
+    const MAP_ANONYMOUS: i32 = 0x20;
+
+    extern "C" {
+        fn mmap(addr: *mut u8, len: usize, prot: i32, flags: i32, fd: i32, offset: i64) -> *mut u8;
+        fn mprotect(addr: *mut u8, len: usize, prot: i32) -> i32;
+        fn munmap(addr: *mut u8, len: usize) -> i32;
+    }
+
+    // What the callbacks need. cells and len come first so the
+    // compiled code can reload them after the tape grows.
+    #[repr(C)]
+    struct Context<'a> {
+        cells: *mut u32,
+        len: usize,
+        tape: &'a mut Tape,
+        eof: Eof,
+        input: &'a mut dyn Read,
+        out: &'a mut dyn Write,
+        // The Op that moved off the tape or failed to do I/O,
+        // and the message.
+        error: Option<(usize, String)>
+    }
+
+    pub fn run<R: Read, W: Write>(program: &Program, tape: &mut Tape, eof: Eof, input: &mut R,
+        out: &mut W) -> Result<(), Error>
+    {
+        let code = compile(program, tape.mask);
+        let mut ctx = Context{
+            cells: tape.cells.as_mut_ptr(),
+            len: tape.cells.len(),
+            tape: tape,
+            eof: eof,
+            input: input,
+            out: out,
+            error: None
+        };
+        unsafe {
+            // Map the code writable, then executable, but never both.
+            let addr = mmap(ptr::null_mut(), code.len(), PROT_READ | PROT_WRITE,
+                MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+            assert!(addr as isize != -1, "Could not map memory for the JIT.");
+            ptr::copy_nonoverlapping(code.as_ptr(), addr, code.len());
+            assert!(mprotect(addr, code.len(), PROT_READ | PROT_EXEC) == 0,
+                "Could not make the JIT code executable.");
+            let f: extern "C" fn(*mut Context) = mem::transmute(addr);
+            f(&mut ctx);
+            munmap(addr, code.len());
+        }
+        match ctx.error {
+            Some((op, message)) => Err(Error{pos: program.positions[op], message: message}),
+            None => Ok(())
+        }
+    }
+
+    // Find the cell for a move that left the tape, or return
+    // usize::MAX to stop the program.
+    extern "C" fn offset(ctx: *mut Context, ptr: usize, offset: isize, op: usize) -> usize {
+        let ctx = unsafe { &mut *ctx };
+        match ctx.tape.offset(ptr, offset) {
+            Ok(target) => {
+                ctx.cells = ctx.tape.cells.as_mut_ptr();
+                ctx.len = ctx.tape.cells.len();
+                target
+            }
+            Err(message) => {
+                ctx.error = Some((op, message));
+                usize::max_value()
+            }
+        }
+    }
+
+    // Like offset(), these return usize::MAX if I/O fails.
+    extern "C" fn output(ctx: *mut Context, x: u32, op: usize) -> usize {
+        let ctx = unsafe { &mut *ctx };
+        match write_byte(&mut ctx.out, x as u8) {
+            Ok(()) => 0,
+            Err(message) => {
+                ctx.error = Some((op, message));
+                usize::max_value()
+            }
+        }
+    }
+
+    extern "C" fn input(ctx: *mut Context, cell: *mut u32, op: usize) -> usize {
+        let (ctx, cell) = unsafe { (&mut *ctx, &mut *cell) };
+        match read_cell(&mut ctx.input, &mut ctx.out, ctx.eof, ctx.tape.mask, cell) {
+            Ok(()) => 0,
+            Err(message) => {
+                ctx.error = Some((op, message));
+                usize::max_value()
+            }
+        }
+    }
+
+    // Compile to a function that takes a *mut Context. Most Ops
+    // work on the current cell, which is at [rbx + r12*4].
+    fn compile(program: &Program, mask: u32) -> Vec<u8> {
+        let mut code = Vec::<u8>::new();
+        let mut opens = Vec::<usize>::new();
+        let mut exits = Vec::<usize>::new();
+        // push rbx; push r12; push r13; push r14; push r15. The
+        // fifth push keeps the stack aligned for calls.
+        code.extend(&[0x53, 0x41, 0x54, 0x41, 0x55, 0x41, 0x56, 0x41, 0x57]);
+        // mov r14, rdi; mov rbx, [r14]; mov r13, [r14+8]; xor r12d, r12d
+        code.extend(&[0x49, 0x89, 0xfe, 0x49, 0x8b, 0x1e, 0x4d, 0x8b, 0x6e, 0x08,
+            0x45, 0x31, 0xe4]);
+        for (i, &op) in program.ops.iter().enumerate() {
+            match op {
+                Op::Add(x) => {
+                    // add dword [rbx+r12*4], x
+                    code.extend(&[0x42, 0x81, 0x04, 0xa3]);
+                    push_u32(&mut code, x as u32);
+                    // and dword [rbx+r12*4], mask
+                    and_mask(&mut code, &[0x42, 0x81, 0x24, 0xa3], mask);
+                }
+                Op::Move(x) => {
+                    move_rax(&mut code, &mut exits, x, i);
+                    // mov r12, rax
+                    code.extend(&[0x49, 0x89, 0xc4]);
+                }
+                Op::Output => {
+                    // mov rdi, r14; mov esi, [rbx+r12*4]; mov rdx, i
+                    code.extend(&[0x4c, 0x89, 0xf7, 0x42, 0x8b, 0x34, 0xa3, 0x48, 0xba]);
+                    push_u64(&mut code, i as u64);
+                    call(&mut code, output as *const ());
+                    exit_on_error(&mut code, &mut exits);
+                }
+                Op::Input => {
+                    // mov rdi, r14; lea rsi, [rbx+r12*4]; mov rdx, i
+                    code.extend(&[0x4c, 0x89, 0xf7, 0x4a, 0x8d, 0x34, 0xa3, 0x48, 0xba]);
+                    push_u64(&mut code, i as u64);
+                    call(&mut code, input as *const ());
+                    exit_on_error(&mut code, &mut exits);
+                }
+                Op::JumpIfZero(_) => {
+                    // cmp dword [rbx+r12*4], 0; je past the loop
+                    code.extend(&[0x42, 0x83, 0x3c, 0xa3, 0x00, 0x0f, 0x84]);
+                    opens.push(code.len());
+                    push_u32(&mut code, 0);
+                }
+                Op::JumpIfNonZero(_) => {
+                    let open = opens.pop().unwrap();
+                    // cmp dword [rbx+r12*4], 0; jne to the start of the loop
+                    code.extend(&[0x42, 0x83, 0x3c, 0xa3, 0x00, 0x0f, 0x85]);
+                    let at = code.len();
+                    push_u32(&mut code, 0);
+                    patch(&mut code, at, open + 4);
+                    let end = code.len();
+                    patch(&mut code, open, end);
+                }
+                Op::Clear => {
+                    // mov dword [rbx+r12*4], 0
+                    code.extend(&[0x42, 0xc7, 0x04, 0xa3, 0x00, 0x00, 0x00, 0x00]);
+                }
+                Op::Mul(offset, factor) => {
+                    // cmp dword [rbx+r12*4], 0; je past the Mul
+                    code.extend(&[0x42, 0x83, 0x3c, 0xa3, 0x00, 0x0f, 0x84]);
+                    let skip = code.len();
+                    push_u32(&mut code, 0);
+                    move_rax(&mut code, &mut exits, offset, i);
+                    // mov ecx, [rbx+r12*4]; imul ecx, ecx, factor
+                    code.extend(&[0x42, 0x8b, 0x0c, 0xa3, 0x69, 0xc9]);
+                    push_u32(&mut code, factor as u32);
+                    // add [rbx+rax*4], ecx; and dword [rbx+rax*4], mask
+                    code.extend(&[0x01, 0x0c, 0x83]);
+                    and_mask(&mut code, &[0x81, 0x24, 0x83], mask);
+                    let end = code.len();
+                    patch(&mut code, skip, end);
+                }
+                Op::Breakpoint => (),
+                Op::Define(_) | Op::Call | Op::Return =>
+                    unreachable!("procedures can't be compiled")
+            }
+        }
+        let end = code.len();
+        for at in exits {
+            patch(&mut code, at, end);
+        }
+        // pop r15; pop r14; pop r13; pop r12; pop rbx; ret
+        code.extend(&[0x41, 0x5f, 0x41, 0x5e, 0x41, 0x5d, 0x41, 0x5c, 0x5b, 0xc3]);
+        code
+    }
+
+    // Put the index of the cell at an offset from data_ptr in
+    // rax, calling offset() if it's off the tape. If that fails,
+    // jump to the end, which is added to exits.
+    fn move_rax(code: &mut Vec<u8>, exits: &mut Vec<usize>, x: isize, op: usize) {
+        // mov rax, x; add rax, r12; cmp rax, r13; jb past the call
+        code.extend(&[0x48, 0xb8]);
+        push_u64(code, x as u64);
+        code.extend(&[0x4c, 0x01, 0xe0, 0x4c, 0x39, 0xe8, 0x0f, 0x82]);
+        let skip = code.len();
+        push_u32(code, 0);
+        // mov rdi, r14; mov rsi, r12; mov rdx, x; mov rcx, op
+        code.extend(&[0x4c, 0x89, 0xf7, 0x4c, 0x89, 0xe6, 0x48, 0xba]);
+        push_u64(code, x as u64);
+        code.extend(&[0x48, 0xb9]);
+        push_u64(code, op as u64);
+        call(code, offset as *const ());
+        exit_on_error(code, exits);
+        // mov rbx, [r14]; mov r13, [r14+8], since the tape may
+        // have grown.
+        code.extend(&[0x49, 0x8b, 0x1e, 0x4d, 0x8b, 0x6e, 0x08]);
+        let end = code.len();
+        patch(code, skip, end);
+    }
+
+    // Jump to the end if a callback returned usize::MAX.
+    fn exit_on_error(code: &mut Vec<u8>, exits: &mut Vec<usize>) {
+        // cmp rax, -1; je to the end
+        code.extend(&[0x48, 0x83, 0xf8, 0xff, 0x0f, 0x84]);
+        exits.push(code.len());
+        push_u32(code, 0);
+    }
+
+    fn call(code: &mut Vec<u8>, f: *const ()) {
+        // mov rax, f; call rax
+        code.extend(&[0x48, 0xb8]);
+        push_u64(code, f as u64);
+        code.extend(&[0xff, 0xd0]);
+    }
+
+    // Add an and with the cell mask, unless cells are 32 bits
+    // and wrap around by themselves.
+    fn and_mask(code: &mut Vec<u8>, and: &[u8], mask: u32) {
+        if mask != u32::max_value() {
+            code.extend(and);
+            push_u32(code, mask);
+        }
+    }
+
+    // Point the rel32 at `at` to target.
+    fn patch(code: &mut Vec<u8>, at: usize, target: usize) {
+        let rel = (target as isize - (at + 4) as isize) as i32;
+        code[at..at + 4].copy_from_slice(&rel.to_le_bytes());
+    }
+
+    fn push_u32(code: &mut Vec<u8>, x: u32) {
+        code.extend(&x.to_le_bytes());
+    }
+
+    fn push_u64(code: &mut Vec<u8>, x: u64) {
+        code.extend(&x.to_le_bytes());
+    }
 }
 
-fn increment(x: u8) -> u8 {
-    if x == 0xff {
-        0
+// The start of a C program, up to the first Op. Cells wrap
+// around by themselves as unsigned integers of the right
+// width, offset() moves off the tape like Tape::offset and
+// input() reads like Op::Input.
+const C_HEADER: &str = "#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+typedef uint{bits}_t cell;
+static cell *tape;
+static long size = {size};
+
+static inline long offset(long ptr, long offset, const char *pos) {
+    long target = ptr + offset;
+    if (target >= 0 && target < size) {
+        return target;
+    }
+{off_end}}
+
+static inline void input(cell *x) {
+    int c;
+    fflush(stdout);
+    if ((c = getchar()) != EOF) {
+        *x = c;
+    }{at_end}
+}
+
+int main(void) {
+    long p = 0;
+    tape = calloc(size, sizeof(cell));
+";
+
+const C_FOOTER: &str = "    return 0;
+}
+";
+
+const C_WRAP: &str = "    return (target % size + size) % size;
+";
+
+const C_GROW: &str = "    if (target >= size) {
+        long new_size = target + 1 > 2 * size ? target + 1 : 2 * size;
+        tape = realloc(tape, new_size * sizeof(cell));
+        memset(tape + size, 0, (new_size - size) * sizeof(cell));
+        size = new_size;
+        return target;
+    }
+";
+
+const C_ERROR: &str = "    fflush(stdout);
+    if (target < 0) {
+        fprintf(stderr, \"\\nbf: %s: moved to cell %ld, left of the first cell\\n\", pos, target);
     } else {
-        x + 1
+        fprintf(stderr, \"\\nbf: %s: moved to cell %ld, past the last cell (%ld)\\n\",
+            pos, target, size - 1);
     }
+    exit(1);
+";
+
+// The same for a Rust program.
+const RUST_HEADER: &str = "#![allow(unused)]
+use std::io::{Read, Write, stdin, stdout};
+
+type Cell = u{bits};
+
+fn offset(tape: &mut Vec<Cell>, ptr: usize, offset: isize, pos: &str) -> usize {
+    let (target, len) = (ptr as isize + offset, tape.len() as isize);
+    if target >= 0 && target < len {
+        return target as usize;
+    }
+{off_end}}
+
+fn input(x: &mut Cell) {
+    let mut byte = [0u8];
+    stdout().flush().unwrap();
+    if stdin().read(&mut byte).unwrap() == 1 {
+        *x = byte[0] as Cell;
+    }{at_end}
 }
 
-fn decrement(x: u8) -> u8 {
-    if x == 0 {
-        0xff
+fn main() {
+    let mut tape: Vec<Cell> = vec![0; {size}];
+    let mut p: usize = 0;
+    let mut out = stdout();
+";
+
+const RUST_FOOTER: &str = "    out.flush().unwrap();
+}
+";
+
+const RUST_WRAP: &str = "    target.rem_euclid(len) as usize
+";
+
+const RUST_GROW: &str = "    if target >= len {
+        let size = (target as usize + 1).max(2 * tape.len());
+        tape.resize(size, 0);
+        return target as usize;
+    }
+";
+
+const RUST_ERROR: &str = "    stdout().flush().unwrap();
+    if target < 0 {
+        eprintln!(\"\\nbf: {}: moved to cell {}, left of the first cell\", pos, target);
     } else {
-        x - 1
+        eprintln!(\"\\nbf: {}: moved to cell {}, past the last cell ({})\", pos, target, len - 1);
+    }
+    std::process::exit(1);
+";
+
+// Write a parsed program as a standalone C or Rust program
+// that behaves like run() with the same options. name is
+// used in error messages, like in main.
+fn transpile(program: &Program, options: &Options, name: &str, lang: Lang) -> String {
+    let (header, footer, off_end) = match (lang, options.tape_mode) {
+        (Lang::C, TapeMode::Wrap) => (C_HEADER, C_FOOTER, C_WRAP.to_string()),
+        (Lang::C, TapeMode::Grow) => (C_HEADER, C_FOOTER, C_GROW.to_string() + C_ERROR),
+        (Lang::C, TapeMode::Fixed) => (C_HEADER, C_FOOTER, C_ERROR.to_string()),
+        (Lang::Rust, TapeMode::Wrap) => (RUST_HEADER, RUST_FOOTER, RUST_WRAP.to_string()),
+        (Lang::Rust, TapeMode::Grow) =>
+            (RUST_HEADER, RUST_FOOTER, RUST_GROW.to_string() + RUST_ERROR),
+        (Lang::Rust, TapeMode::Fixed) => (RUST_HEADER, RUST_FOOTER, RUST_ERROR.to_string())
+    };
+    let at_end = match (options.eof, lang) {
+        (Eof::Leave, _) => "",
+        (Eof::Zero, _) => " else {\n        *x = 0;\n    }",
+        (Eof::MinusOne, Lang::C) => " else {\n        *x = -1;\n    }",
+        (Eof::MinusOne, Lang::Rust) => " else {\n        *x = Cell::max_value();\n    }"
+    };
+    let mut res = header
+        .replace("{bits}", &options.cell_bits.to_string())
+        .replace("{size}", &options.tape_size.to_string())
+        .replace("{off_end}", &off_end)
+        .replace("{at_end}", at_end);
+    let mut depth = 1;
+    for (op, pos) in program.ops.iter().zip(&program.positions) {
+        if let Op::JumpIfNonZero(_) = *op {
+            depth -= 1;
+        }
+        // Rust's escapes for a plain string are also valid in C.
+        let pos = format!("{:?}", format!("{}:{}", name, pos));
+        let code = match lang {
+            Lang::C => c_op(*op, &pos),
+            Lang::Rust => rust_op(*op, &pos)
+        };
+        for line in code.lines() {
+            res.push_str(&format!("{}{}\n", "    ".repeat(depth), line));
+        }
+        if let Op::JumpIfZero(_) = *op {
+            depth += 1;
+        }
+    }
+    res + footer
+}
+
+fn c_op(op: Op, pos: &str) -> String {
+    match op {
+        Op::Add(x) => format!("tape[p] += {};", x),
+        Op::Move(x) => format!("p = offset(p, {}, {});", x, pos),
+        Op::Output => "putchar(tape[p]);".to_string(),
+        Op::Input => "input(&tape[p]);".to_string(),
+        Op::JumpIfZero(_) => "while (tape[p]) {".to_string(),
+        Op::JumpIfNonZero(_) => "}".to_string(),
+        Op::Clear => "tape[p] = 0;".to_string(),
+        Op::Breakpoint => "".to_string(),
+        Op::Define(_) | Op::Call | Op::Return => unreachable!("procedures can't be transpiled"),
+        // Multiply as uint32_t, since a narrower cell would be
+        // promoted to a signed int that could overflow.
+        Op::Mul(offset, factor) => format!(
+            "if (tape[p]) {{\n    long t = offset(p, {}, {});\n    \
+             tape[t] += (uint32_t)tape[p] * (uint32_t){};\n}}",
+            offset, pos, factor)
+    }
+}
+
+fn rust_op(op: Op, pos: &str) -> String {
+    match op {
+        Op::Add(x) => format!("tape[p] = tape[p].wrapping_add({}i32 as Cell);", x),
+        Op::Move(x) => format!("p = offset(&mut tape, p, {}, {});", x, pos),
+        Op::Output => "out.write_all(&[tape[p] as u8]).unwrap();".to_string(),
+        Op::Input => "input(&mut tape[p]);".to_string(),
+        Op::JumpIfZero(_) => "while tape[p] != 0 {".to_string(),
+        Op::JumpIfNonZero(_) => "}".to_string(),
+        Op::Clear => "tape[p] = 0;".to_string(),
+        Op::Breakpoint => "".to_string(),
+        Op::Define(_) | Op::Call | Op::Return => unreachable!("procedures can't be transpiled"),
+        Op::Mul(offset, factor) => format!(
+            "if tape[p] != 0 {{\n    let t = offset(&mut tape, p, {}, {});\n    \
+             tape[t] = tape[t].wrapping_add(tape[p].wrapping_mul({}i32 as Cell));\n}}",
+            offset, pos, factor)
+    }
+}
+
+// Write a Brainfuck program that prints text. Each byte is
+// reached from the one before it in the first cell, with a
+// multiply loop counting down in the second cell when that's
+// shorter than a run of + or -. The program never relies on
+// cells wrapping around, so it runs with any cell width.
+fn generate(text: &[u8]) -> String {
+    let mut res = String::new();
+    let mut current = 0;
+    for &x in text {
+        let delta = x as i32 - current;
+        let (up, down) = if delta < 0 { ('-', '+') } else { ('+', '-') };
+        let n = delta.abs() as usize;
+        // A loop adding times * factor also needs ">", "[<",
+        // ">-]" and "<", and + or - for whatever is left.
+        let mut best = (n, 0, 0);
+        for times in 2..n {
+            let factor = (n + times / 2) / times;
+            let cost = times + factor + (n as isize - (times * factor) as isize).abs() as usize + 7;
+            if factor > 1 && cost < best.0 {
+                best = (cost, times, factor);
+            }
+        }
+        let (_, times, factor) = best;
+        let mut rest = n as isize;
+        if times > 0 {
+            res.push('>');
+            res.extend((0..times).map(|_| '+'));
+            res.push_str("[<");
+            res.extend((0..factor).map(|_| up));
+            res.push_str(">-]<");
+            rest -= (times * factor) as isize;
+        }
+        res.extend((0..rest.abs()).map(|_| if rest < 0 { down } else { up }));
+        res.push_str(".\n");
+        current = x as i32;
     }
+    res
 }
 
-fn write_byte(x: u8) {
-    stdout().write(&[x]).expect("Could not write byte to stdout.");
+fn write_byte<W: Write>(out: &mut W, x: u8) -> Result<(), String> {
+    out.write_all(&[x]).map_err(|err| format!("could not write output: {}", err))
 }
 
-fn read_byte() -> u8 {
+// Read the next byte of input, or None at the end.
+fn read_byte<R: Read>(input: &mut R) -> Result<Option<u8>, String> {
     let mut res: [u8; 1] = [0];
-    stdin().read_exact(&mut res).expect("Could not read byte from stdin.");
-    res[0]
+    match input.read(&mut res) {
+        Ok(0) => Ok(None),
+        Ok(_) => Ok(Some(res[0])),
+        Err(err) => Err(format!("could not read input: {}", err))
+    }
 }
 
-fn matching_close(code: &Vec<char>, code_ptr: usize) -> usize {
+// Do what Op::Input does to a cell.
+fn read_cell<R: Read, W: Write>(input: &mut R, out: &mut W, eof: Eof, mask: u32, cell: &mut u32)
+    -> Result<(), String>
+{
+    // Make sure any prompt is visible before blocking.
+    out.flush().map_err(|err| format!("could not write output: {}", err))?;
+    match (read_byte(input)?, eof) {
+        (Some(x), _) => *cell = x as u32,
+        (None, Eof::Leave) => (),
+        (None, Eof::Zero) => *cell = 0,
+        (None, Eof::MinusOne) => *cell = mask
+    }
+    Ok(())
+}
+
+// Find the ']' that matches the '[' at code_ptr.
+fn matching_close(code: &[char], code_ptr: usize) -> usize {
     let mut count = 1;
-    let mut cur = code_ptr + 1;
+    let mut cur = code_ptr;
     while count != 0 {
+        cur += 1;
         if code[cur] == '[' {
             count += 1;
         } else if code[cur] == ']' {
             count -= 1;
         }
-        cur += 1;
     }
     cur
 }
 
-fn matching_open(code: &Vec<char>, code_ptr: usize) -> usize {
+// Find the '[' that matches the ']' at code_ptr.
+fn matching_open(code: &[char], code_ptr: usize) -> usize {
     let mut count = 1;
-    let mut cur = code_ptr - 1;
+    let mut cur = code_ptr;
     while count != 0 {
+        cur -= 1;
         if code[cur] == '[' {
             count -= 1;
         } else if code[cur] == ']' {
             count += 1;
         }
-        cur -= 1;
     }
     cur
 }
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io::{self, Write};
+    use std::process::{self, Command, Output, Stdio};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use super::{BUFFER_SIZE, Dialect, Eof, Error, Interpreter, Lang, Op, Options, Pos, Program};
+    use super::{Source, Stop, Tape, TapeMode};
+    use super::{commands, debug, generate, parse, parse_args, parse_dialect, print_profile, run};
+    use super::{read_program, run_chars, run_jit, source_between};
+    use super::transpile;
+
+    fn chars(code: &str) -> Vec<char> {
+        code.chars().collect()
+    }
+
+    fn ops(code: &str) -> Vec<Op> {
+        parse(&chars(code)).unwrap().ops
+    }
+
+    fn run_on(code: &str, tape: &mut Tape) -> Result<Vec<u8>, Error> {
+        let mut out = Vec::<u8>::new();
+        run(&parse(&chars(code))?, tape, Eof::Leave, &mut "".as_bytes(), &mut out)?;
+        Ok(out)
+    }
+
+    fn run_with(code: &str, input: &str, bits: u32, eof: Eof) -> Vec<u8> {
+        let mut out = Vec::<u8>::new();
+        let mut tape = Tape::new(BUFFER_SIZE, bits, TapeMode::Fixed);
+        let program = parse(&chars(code)).unwrap();
+        run(&program, &mut tape, eof, &mut input.as_bytes(), &mut out).unwrap();
+        out
+    }
+
+    fn error(code: &str, tape: &mut Tape) -> String {
+        run_on(code, tape).err().unwrap().to_string()
+    }
+
+    fn output(code: &str) -> Vec<u8> {
+        run_with(code, "", 8, Eof::Leave)
+    }
+
+    fn args(line: &str) -> Result<Options, String> {
+        parse_args(&line.split_whitespace().map(|s| s.to_string()).collect::<Vec<String>>())
+    }
+
+    // Programs that compiled code should run exactly like run(),
+    // with options and input.
+    const CORPUS: [(&str, &str, &str); 13] = [
+        ("", include_str!("program.bf"), ""),
+        ("--eof zero", ",[.,]", "echo"),
+        ("--eof -1 --cell 16", ",+.", ""),
+        ("--cell 32", "-.>-[>+<-]>.", ""),
+        ("--cell 16", "++++++++++++++++[>++++++++++++++++<-]>\
+                       [[-]+++++++++++++++++++++++++++++++++.[-]]", ""),
+        ("", "++++++++++++++++[>++++++++++++++++<-]>\
+              [[-]+++++++++++++++++++++++++++++++++.[-]]", ""),
+        ("", "++[>+++[>+++++<-]<-]>>+++.<<+[>++>+<<-]>>.", ""),
+        ("", ",[>>+++[<+++++>-]<[<+>-]<.[-],]", "ABC"),
+        ("--tape 4", "+.[-<+>]>>>>.", ""),
+        ("--tape 100", "++++++++[>++++++++<-]>[[>>>>+<<<<-]>>>>-].", ""),
+        ("--tape 4 --tape-mode wrap", "<++++++.>>>>>>+[-<<<<<+>>>>>]<<<<<.", ""),
+        ("--tape 2 --tape-mode grow", ">>>>>+++[-<<<<<+>>>>>]<<<<<.<", ""),
+        ("--tape 2 --tape-mode grow --cell 16",
+            "++++++++[>++++++++<-]>[[>>>>+<<<<-]>>>>-]+++++++++++++++++++++++++++++++++.", ""),
+    ];
+
+    // Run a program with parsed options, using run() or run_jit(),
+    // and return its output, result and final tape.
+    fn run_options(program: &Program, options: &Options, input: &str, jit: bool)
+        -> (Vec<u8>, Result<(), Error>, Vec<u32>)
+    {
+        let mut out = Vec::<u8>::new();
+        let mut tape = Tape::new(options.tape_size, options.cell_bits, options.tape_mode);
+        let res = if jit {
+            run_jit(program, &mut tape, options.eof, &mut input.as_bytes(), &mut out)
+        } else {
+            run(program, &mut tape, options.eof, &mut input.as_bytes(), &mut out)
+        };
+        (out, res, tape.cells)
+    }
+
+    // Compile source with cc or rustc and run it on input, or
+    // return None if the compiler isn't installed.
+    fn run_native(source: &str, lang: Lang, input: &str) -> Option<Output> {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let n = COUNT.fetch_add(1, Ordering::SeqCst);
+        let exe = env::temp_dir().join(format!("bf_test_{}_{}", process::id(), n));
+        let (src, compiler) = match lang {
+            Lang::C => (exe.with_extension("c"), "cc"),
+            Lang::Rust => (exe.with_extension("rs"), "rustc")
+        };
+        fs::write(&src, source).unwrap();
+        let status = match Command::new(compiler).arg(&src).arg("-o").arg(&exe).status() {
+            Ok(status) => status,
+            Err(_) => return None
+        };
+        assert!(status.success(), "{} failed on:\n{}", compiler, source);
+        let mut child = Command::new(&exe)
+            .stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped())
+            .spawn().unwrap();
+        child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+        let output = child.wait_with_output().unwrap();
+        fs::remove_file(&src).unwrap();
+        fs::remove_file(&exe).unwrap();
+        Some(output)
+    }
+
+    #[test]
+    fn parse_ops() {
+        assert_eq!(ops("++-+>><<<."), vec![Op::Add(2), Op::Move(-1), Op::Output]);
+        assert_eq!(ops("+-<>"), vec![]);
+        assert_eq!(ops(">[-]<"), vec![Op::Move(1), Op::Clear, Op::Move(-1)]);
+        assert_eq!(ops("[->+++>-<<]"), vec![Op::Mul(1, 3), Op::Mul(2, -1), Op::Clear]);
+        assert_eq!(ops("[->+<.]"), vec![
+            Op::JumpIfZero(6), Op::Add(-1), Op::Move(1), Op::Add(1), Op::Move(-1), Op::Output,
+            Op::JumpIfNonZero(0)
+        ]);
+        // Loops that don't decrement by one can't be simplified.
+        assert_eq!(ops("[-->+<]").len(), 6);
+    }
+
+    #[test]
+    fn same_output() {
+        let programs = [
+            include_str!("program.bf"),
+            "++++++[>++++++++<-]>[>+>+<<-]>>[<<+>>-]<<.>.",
+            "+++[>+++++<-]>[>++[>+++<-]<-]>>.[-]-.",
+            "++[>+[>++<-]<-]>>++++++++++++++++++++++++++++++++.\
+             <<+[>]++++++++++++++++++++++++++++++++++++.",
+        ];
+        for program in &programs {
+            let mut expected = Vec::<u8>::new();
+            run_chars(&chars(program), &mut vec![0; BUFFER_SIZE], &mut "".as_bytes(),
+                &mut expected);
+            assert_eq!(output(program), expected, "{}", program);
+        }
+        assert_eq!(output(include_str!("program.bf")), b"Hello World!\n");
+    }
+
+    #[test]
+    fn cells_and_eof() {
+        // 256 wraps around to 0 in 8-bit cells only.
+        let code = "++++++++++++++++[>++++++++++++++++<-]>\
+                    [[-]+++++++++++++++++++++++++++++++++.[-]]";
+        assert_eq!(output(code), b"");
+        assert_eq!(run_with(code, "", 16, Eof::Leave), b"!");
+        assert_eq!(run_with("-[>+<-]>.", "", 16, Eof::Leave), vec![0xff]);
+        assert_eq!(run_with("-.", "", 32, Eof::Leave), vec![0xff]);
+
+        // Echo the input, then read past its end.
+        let code = ",.,.>+++++,.";
+        assert_eq!(run_with(code, "hi", 8, Eof::Leave), b"hi\x05");
+        assert_eq!(run_with(code, "hi", 8, Eof::Zero), b"hi\x00");
+        assert_eq!(run_with(code, "hi", 8, Eof::MinusOne), b"hi\xff");
+        assert_eq!(run_with(",+.", "", 16, Eof::MinusOne), b"\x00");
+    }
+
+    #[test]
+    fn command_line() {
+        let defaults = args("").unwrap();
+        assert_eq!(defaults.program, Source::File("program.bf".to_string()));
+        assert_eq!(defaults.input, Source::Stdin);
+        assert_eq!((defaults.tape_size, defaults.cell_bits, defaults.eof),
+            (BUFFER_SIZE, 8, Eof::Leave));
+        // A missing program is an error to report, not a panic.
+        assert_eq!(read_program("/nonexistent/program.bf").err().unwrap().kind(),
+            io::ErrorKind::NotFound);
+
+        let options = args("--tape 100 -e +. --cell 16 --input abc --eof -1").unwrap();
+        assert_eq!(options.program, Source::Text("+.".to_string()));
+        assert_eq!(options.input, Source::Text("abc".to_string()));
+        assert_eq!((options.tape_size, options.cell_bits, options.eof), (100, 16, Eof::MinusOne));
+        assert_eq!(args("-i in.txt hello.bf").unwrap().input, Source::File("in.txt".to_string()));
+
+        assert_eq!(args("a.bf b.bf").err().unwrap(), "more than one program given");
+        assert_eq!(args("-e + a.bf").err().unwrap(), "more than one program given");
+        assert_eq!(args("--tape 0").err().unwrap(), "invalid tape size: 0");
+        assert_eq!(args("--cell 12").err().unwrap(), "invalid cell width: 12");
+        assert_eq!(args("--eof 1").err().unwrap(), "invalid EOF behavior: 1");
+        assert_eq!(args("--eof").err().unwrap(), "--eof needs a value");
+        assert_eq!(args("-x").err().unwrap(), "unknown option: -x");
+
+        assert_eq!(defaults.tape_mode, TapeMode::Fixed);
+        assert_eq!(args("--tape-mode wrap").unwrap().tape_mode, TapeMode::Wrap);
+        assert_eq!(args("--tape-mode big").err().unwrap(), "invalid tape mode: big");
+
+        assert_eq!(defaults.emit, None);
+        assert_eq!(args("--emit rust").unwrap().emit, Some(Lang::Rust));
+        assert_eq!(args("--emit go").err().unwrap(), "invalid language: go");
+        assert_eq!((defaults.jit, defaults.debug), (false, false));
+        let options = args("--jit --debug -e + --input x").unwrap();
+        assert_eq!((options.jit, options.debug), (true, true));
+        assert_eq!(options.program, Source::Text("+".to_string()));
+        assert_eq!(args("--steps 1000").unwrap().steps, Some(1000));
+        assert_eq!(args("--steps -1").err().unwrap(), "invalid step count: -1");
+        assert_eq!(args("--jit --steps 10").err().unwrap(),
+            "--steps can't be used with --jit or --debug");
+        assert_eq!(args("--profile --steps 10").unwrap().profile, true);
+        assert_eq!(args("--profile --debug").err().unwrap(),
+            "--profile can't be used with --jit or --debug");
+
+        assert_eq!(defaults.dialect, Dialect::Brainfuck);
+        assert_eq!(args("hello.ook").unwrap().dialect, Dialect::Ook);
+        assert_eq!(args("lib.pb").unwrap().dialect, Dialect::Pbrain);
+        assert_eq!(args("lib.pbrain").unwrap().dialect, Dialect::Pbrain);
+        assert_eq!(args("--dialect bf hello.ook").unwrap().dialect, Dialect::Brainfuck);
+        assert_eq!(args("-e + --dialect ook").unwrap().dialect, Dialect::Ook);
+        assert_eq!(args("--dialect cow").err().unwrap(), "invalid dialect: cow");
+        assert_eq!(args("--dump").unwrap().dump, true);
+        assert_eq!(args("--dump --jit").err().unwrap(),
+            "--dump can't be used with --jit or --debug");
+        assert_eq!(args("--debug").err().unwrap(),
+            "--debug needs the program's input from -i or --input");
+        assert_eq!(args("--debug -i input.txt").unwrap().debug, true);
+        assert_eq!(defaults.generate, false);
+        assert_eq!(args("--generate --input hi").unwrap().generate, true);
+        assert_eq!(args("--bench --input hi").unwrap().bench, true);
+        assert_eq!(args("--bench --eof zero").err().unwrap(), "--eof can't be used with --bench");
+        assert_eq!(args("--bench --tape 100").err().unwrap(), "--tape can't be used with --bench");
+        assert_eq!(args("--cell 16 --bench").err().unwrap(), "--cell can't be used with --bench");
+        assert_eq!(args("--bench --tape-mode wrap").err().unwrap(),
+            "--tape-mode can't be used with --bench");
+        assert_eq!(args("--bench hello.ook").err().unwrap(),
+            "--bench only runs Brainfuck programs");
+        assert_eq!(args("--bench --dialect bf hello.ook").unwrap().dialect, Dialect::Brainfuck);
+    }
+
+    #[test]
+    fn transpiled_output() {
+        for &(flags, code, input) in &CORPUS {
+            let options = args(flags).unwrap();
+            let program = parse(&chars(code)).unwrap();
+            let (expected, res, _) = run_options(&program, &options, input, false);
+            let stderr = match res {
+                Ok(()) => "".to_string(),
+                Err(err) => format!("\nbf: -e:{}\n", err)
+            };
+            for &lang in &[Lang::C, Lang::Rust] {
+                let source = transpile(&program, &options, "-e", lang);
+                let output = match run_native(&source, lang, input) {
+                    Some(output) => output,
+                    None => continue
+                };
+                assert_eq!(output.stdout, expected, "{:?} {} {}", lang, flags, code);
+                assert_eq!(String::from_utf8_lossy(&output.stderr), stderr,
+                    "{:?} {} {}", lang, flags, code);
+                assert_eq!(output.status.success(), stderr.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn jit_output() {
+        for &(flags, code, input) in &CORPUS {
+            let options = args(flags).unwrap();
+            let program = parse(&chars(code)).unwrap();
+            assert_eq!(run_options(&program, &options, input, true),
+                run_options(&program, &options, input, false), "{} {}", flags, code);
+        }
+        // bench.bf is too slow to interpret in a debug build.
+        let mut out = Vec::<u8>::new();
+        let mut tape = Tape::new(BUFFER_SIZE, 8, TapeMode::Fixed);
+        let program = parse(&chars(include_str!("bench.bf"))).unwrap();
+        run_jit(&program, &mut tape, Eof::Leave, &mut "".as_bytes(), &mut out).unwrap();
+        assert_eq!(out, b"ZYXWVUTSRQPONMLKJIHGFEDCBA\n");
+    }
+
+    #[test]
+    fn debugger() {
+        let session = |code: &str, commands: &str| {
+            let program = parse_dialect(&chars(code), Dialect::Brainfuck, true).unwrap();
+            let (mut out, mut log) = (Vec::<u8>::new(), Vec::<u8>::new());
+            let mut tape = Tape::new(BUFFER_SIZE, 8, TapeMode::Fixed);
+            let res = debug(&program, &mut tape, Eof::Leave, &mut "".as_bytes(), &mut out,
+                &mut commands.as_bytes(), &mut log);
+            (out, String::from_utf8(log).unwrap(), res)
+        };
+        let code = "++[>+++<-]#>.\n#<<";
+        let (out, log, res) = session(code, "s\n\nc\nt 2\nstep 2\nfoo\nc\nq\n");
+        assert_eq!(out, b"\x06");
+        assert_eq!(res, Ok(()));
+        assert_eq!(log, "1:1: Add(2)\ncells 0-4: [0] 0 0 0 0\n\
+            (bf) 1:3: Mul(1, 3)\ncells 0-4: [2] 0 0 0 0\n\
+            (bf) 1:3: Clear\ncells 0-4: [2] 6 0 0 0\n\
+            (bf) 1:11: breakpoint\ncells 0-4: [0] 6 0 0 0\n\
+            (bf) cells 0-2: [0] 6 0\n\
+            (bf) 1:13: Output\ncells 0-5: 0 [6] 0 0 0 0\n\
+            (bf) unknown command: foo (try help)\n\
+            (bf) 2:1: breakpoint\ncells 0-5: 0 [6] 0 0 0 0\n\
+            (bf) ");
+
+        // Continuing runs into the error, which leaves the tape
+        // to look at, and stepping stops at the end.
+        let (_, log, res) = session(code, "c\nc\nc\nt 1\ns\nq\n");
+        assert_eq!(res.err().unwrap().to_string(), "2:2: moved to cell -1, left of the first cell");
+        let error = "2:2: moved to cell -1, left of the first cell\ncells 0-5: 0 [6] 0 0 0 0\n";
+        let expected = format!("(bf) {}(bf) cells 0-2: 0 [6] 0\n(bf) {}(bf) ", error, error);
+        assert!(log.ends_with(&expected), "{}", log);
+        let (out, log, res) = session("+.", "step 5\n");
+        assert_eq!((out, res), (b"\x01".to_vec(), Ok(())));
+        assert!(log.ends_with("(bf) finished\ncells 0-4: [1] 0 0 0 0\n"), "{}", log);
+
+        // A wide tape view stops at the ends of the tape.
+        let (_, log, _) = session("+", "t 18446744073709551615\nq\n");
+        assert!(log.contains("(bf) cells 0-8191: [0] 0 0 "));
+
+        // '#' is only a breakpoint when debugging.
+        assert_eq!(ops("+#+"), vec![Op::Add(2)]);
+        let ops = |code: &str| parse_dialect(&chars(code), Dialect::Brainfuck, true).unwrap().ops;
+        assert_eq!(ops("+#+"), vec![Op::Add(1), Op::Breakpoint, Op::Add(1)]);
+        assert_eq!(ops("[-#]").len(), 4);
+    }
+
+    // Output that can't be written.
+    struct Closed;
+
+    impl Write for Closed {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::BrokenPipe, "closed"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn dialects() {
+        // Ook! runs like the Brainfuck it stands for.
+        let words = |code: &str| code.chars().map(|c| match c {
+            '>' => "Ook. Ook?",
+            '<' => "Ook? Ook.",
+            '+' => "Ook. Ook.",
+            '-' => "Ook! Ook!",
+            '.' => "Ook! Ook.",
+            ',' => "Ook. Ook!",
+            '[' => "Ook! Ook?",
+            ']' => "Ook? Ook!",
+            _ => unreachable!()
+        }).collect::<Vec<&str>>().join(" ");
+        let bf = "++++++++[>++++++++<-]>+.+.,.";
+        let ook = chars(&words(bf));
+        assert_eq!(parse_dialect(&ook, Dialect::Ook, false).unwrap().ops, ops(bf));
+        let ook = chars("Ook. Ook.
+Ook? Ook? comment Ook! Ook.
+Ook!");
+        let err = parse_dialect(&ook, Dialect::Ook, false).err().unwrap();
+        assert_eq!(err.to_string(), "3:1: Ook without a second word");
+        let program = parse_dialect(&ook[..ook.len() - 5], Dialect::Ook, false).unwrap();
+        assert_eq!(program.ops, vec![Op::Add(1), Op::Output]);
+        assert_eq!(program.positions, vec![Pos{line: 1, col: 1}, Pos{line: 2, col: 19}]);
+        let err = parse_dialect(&chars("Ook! Ook?"), Dialect::Ook, false).err().unwrap();
+        assert_eq!(err.to_string(), "1:1: unmatched '['");
+
+        // pbrain procedures are numbered by the cell they're
+        // defined or called from.
+        let pbrain = |code: &str| -> Result<Vec<u8>, Error> {
+            let mut out = Vec::<u8>::new();
+            let mut tape = Tape::new(BUFFER_SIZE, 8, TapeMode::Fixed);
+            let program = parse_dialect(&chars(code), Dialect::Pbrain, false)?;
+            run_jit(&program, &mut tape, Eof::Leave, &mut "".as_bytes(), &mut out)?;
+            Ok(out)
+        };
+        assert_eq!(pbrain("+++++[>+++++++++++++<-]>(.-):+:"), Ok(b"AA".to_vec()));
+        assert_eq!(pbrain("+(>+++<)>++(<::>):."), Ok(b"\x08".to_vec()));
+        assert_eq!(ops("(:)"), vec![]);
+        assert_eq!(pbrain("+:").err().unwrap().to_string(), "1:2: procedure 1 is not defined");
+        assert_eq!(pbrain("(:):").err().unwrap().to_string(),
+            "1:2: more than 1048576 nested calls");
+        assert_eq!(pbrain("(+").err().unwrap().to_string(), "1:1: unmatched '('");
+        assert_eq!(pbrain("[)]").err().unwrap().to_string(), "1:2: unmatched ')'");
+        assert_eq!(pbrain("(])").err().unwrap().to_string(), "1:2: unmatched ']'");
+        assert!(!parse(&chars("+.")).unwrap().has_procedures());
+
+        // With breakpoints, Interpreter stops after each '#'.
+        let program = parse_dialect(&chars("+#>++#."), Dialect::Brainfuck, true).unwrap();
+        let tape = Tape::new(BUFFER_SIZE, 8, TapeMode::Fixed);
+        let mut interpreter = Interpreter::new(program, tape, Eof::Leave, "".as_bytes(),
+            Vec::<u8>::new());
+        assert_eq!(interpreter.run(None), Stop::Breakpoint);
+        assert_eq!((interpreter.pos(), interpreter.steps()), (Some(Pos{line: 1, col: 3}), 2));
+        assert_eq!(interpreter.run(Some(2)), Stop::OutOfSteps);
+        assert_eq!(interpreter.run(None), Stop::Breakpoint);
+        assert_eq!(interpreter.run(None), Stop::Finished);
+        assert_eq!(interpreter.into_output(), b"\x02");
+    }
+
+    #[test]
+    fn generated() {
+        let texts = [
+            "".to_string().into_bytes(),
+            b"Hello World!\n".to_vec(),
+            b"aaaa zzzz AAAA".to_vec(),
+            (0..=255).collect::<Vec<u8>>(),
+            (0..=255).rev().collect::<Vec<u8>>()
+        ];
+        for text in &texts {
+            let program = generate(text);
+            assert_eq!(output(&program), *text, "{}", program);
+            assert_eq!(run_with(&program, "", 16, Eof::Leave), *text, "{}", program);
+        }
+        // Multiply loops make it about half as long as it would
+        // be with only + and -, which takes 402 characters.
+        let program = generate(b"Hello World!\n");
+        assert!(program.contains("[<"));
+        assert_eq!(program.len(), 205);
+        assert_eq!(generate(b"ab"), ">++++++++[<++++++++++++>-]<+.\n+.\n");
+    }
+
+    #[test]
+    fn interpreter() {
+        let new = |code: &str, input: &'static str| {
+            let tape = Tape::new(BUFFER_SIZE, 8, TapeMode::Fixed);
+            let program = parse(&chars(code)).unwrap();
+            Interpreter::new(program, tape, Eof::Zero, input.as_bytes(), Vec::<u8>::new())
+        };
+        let mut echo = new(",[.,]", "abc");
+        assert_eq!(echo.run(None), Stop::Finished);
+        assert_eq!((echo.steps(), echo.pos()), (11, None));
+        assert_eq!(echo.into_output(), b"abc");
+
+        // Running out of steps can be continued.
+        let mut count = new("+[.+]", "");
+        assert_eq!(count.run(Some(4)), Stop::OutOfSteps);
+        assert_eq!((&count.output()[..], count.steps()), (&[1][..], 4));
+        assert_eq!(count.pos(), Some(Pos{line: 1, col: 5}));
+        assert_eq!(count.run(Some(0)), Stop::OutOfSteps);
+        assert_eq!(count.run(None), Stop::Finished);
+        assert_eq!(count.steps(), 2 + 255 * 3);
+        assert_eq!(count.into_output(), (1..=255).collect::<Vec<u8>>());
+
+        let mut fail = new(">+.<<", "");
+        assert_eq!(fail.run(Some(3)), Stop::OutOfSteps);
+        let message = "moved to cell -1, left of the first cell";
+        let err = Error{pos: Pos{line: 1, col: 4}, message: message.to_string()};
+        assert_eq!(fail.run(None), Stop::Error(err.clone()));
+        assert_eq!((err.pos(), err.message()), (Pos{line: 1, col: 4}, message));
+        assert_eq!((fail.steps(), fail.pos()), (3, Some(Pos{line: 1, col: 4})));
+
+        // I/O errors stop the program instead of panicking.
+        let program = parse(&chars("+++.")).unwrap();
+        let tape = Tape::new(BUFFER_SIZE, 8, TapeMode::Fixed);
+        let mut closed = Interpreter::new(parse(&chars("+++.")).unwrap(), tape, Eof::Leave,
+            "".as_bytes(), Closed);
+        let message = "could not write output: closed";
+        let err = Error{pos: Pos{line: 1, col: 4}, message: message.to_string()};
+        assert_eq!(closed.run(None), Stop::Error(err.clone()));
+        let mut tape = Tape::new(BUFFER_SIZE, 8, TapeMode::Fixed);
+        let res = run_jit(&program, &mut tape, Eof::Leave, &mut "".as_bytes(), &mut Closed);
+        assert_eq!(res, Err(err));
+    }
+
+    #[test]
+    fn profile() {
+        let code = chars("+++[>++[>+.<-]<-]\n[-]");
+        let tape = Tape::new(BUFFER_SIZE, 8, TapeMode::Fixed);
+        let program = parse(&code).unwrap();
+        let mut interpreter = Interpreter::new(program, tape, Eof::Leave, "".as_bytes(),
+            Vec::<u8>::new());
+        interpreter.count_ops();
+        assert_eq!(interpreter.run(None), Stop::Finished);
+        let mut report = Vec::<u8>::new();
+        let source = commands(&code, Dialect::Brainfuck, false).unwrap();
+        let counts = interpreter.counts().unwrap();
+        print_profile(&parse(&code).unwrap(), &source, counts, 3, &mut report).unwrap();
+        let expected = [
+            "57 steps",
+            "",
+            "loops by iterations:",
+            "    iterations    entries  span         source",
+            "             6          3  1:8-1:14     [>+.<-]",
+            "             3          1  1:4-1:17     [>++[>+.<-]<-]",
+            "",
+            "ops by count:",
+            "         count  position     op",
+            "             6  1:9          Move(1)",
+            "             6  1:10         Add(1)",
+            "             6  1:11         Output",
+            ""
+        ];
+        assert_eq!(String::from_utf8(report).unwrap(), expected.join("\n"));
+
+        // Sources are shown as the commands of their dialect.
+        let span = |code: &str, dialect: Dialect, end: usize| {
+            let source = commands(&chars(code), dialect, false).unwrap();
+            source_between(&source, Pos{line: 1, col: 1}, Pos{line: 1, col: end}, 40)
+        };
+        assert_eq!(span("(a[-:]b)", Dialect::Pbrain, 8), "([-:])");
+        assert_eq!(span("(a[-:]b)", Dialect::Brainfuck, 8), "[-]");
+        assert_eq!(span("Ook! Ook? Ook! Ook! Ook? Ook! Ook. Ook.", Dialect::Ook, 21), "[-]");
+        assert_eq!(span(&"+".repeat(50), Dialect::Brainfuck, 50), format!("{}...", "+".repeat(37)));
+
+        // Counting continues after running out of steps.
+        let tape = Tape::new(BUFFER_SIZE, 8, TapeMode::Fixed);
+        let program = parse(&chars("+[]")).unwrap();
+        let mut interpreter = Interpreter::new(program, tape, Eof::Leave, "".as_bytes(),
+            io::sink());
+        interpreter.count_ops();
+        assert_eq!(interpreter.run(Some(30)), Stop::OutOfSteps);
+        assert_eq!(interpreter.run(Some(20)), Stop::OutOfSteps);
+        assert_eq!(interpreter.counts(), Some(&[1, 1, 48][..]));
+    }
+
+    #[test]
+    fn syntax_errors() {
+        let pos = |code: &str| parse(&chars(code)).err().unwrap().pos;
+        assert_eq!(pos("+[\n  [-]\n  ]]"), Pos{line: 3, col: 4});
+        assert_eq!(pos("[\n++[->+<]\n[.-]"), Pos{line: 1, col: 1});
+        assert_eq!(pos("[[[]"), Pos{line: 1, col: 2});
+        assert_eq!(parse(&chars("]")).err().unwrap().to_string(), "1:1: unmatched ']'");
+        assert_eq!(parse(&chars("\n [")).err().unwrap().to_string(), "2:2: unmatched '['");
+    }
+
+    #[test]
+    fn tape_modes() {
+        let fixed = || Tape::new(4, 8, TapeMode::Fixed);
+        assert_eq!(error("+.\n<", &mut fixed()), "2:1: moved to cell -1, left of the first cell");
+        assert_eq!(error("+++[>+<-]>>>>", &mut fixed()),
+            "1:10: moved to cell 4, past the last cell (3)");
+        assert_eq!(error("+[->>>>+<<<<]", &mut fixed()),
+            "1:2: moved to cell 4, past the last cell (3)");
+        // Output before the error is kept, and loops that copy to
+        // cells off the tape are fine as long as they never run.
+        let mut out = Vec::<u8>::new();
+        let program = parse(&chars("+.<")).unwrap();
+        assert!(run(&program, &mut fixed(), Eof::Leave, &mut "".as_bytes(), &mut out).is_err());
+        assert_eq!(out, b"\x01");
+        assert_eq!(run_on("[-<+>]>>>.", &mut fixed()).unwrap(), b"\x00");
+
+        let mut grow = Tape::new(4, 8, TapeMode::Grow);
+        let code = ">>>>>>>>>+++.[-<<<<<<<<<+>>>>>>>>>]<<<<<<<<<.";
+        assert_eq!(run_on(code, &mut grow).unwrap(), b"\x03\x03");
+        assert_eq!(grow.cells.len(), 10);
+        assert_eq!(error("<", &mut grow), "1:1: moved to cell -1, left of the first cell");
+
+        let mut wrap = Tape::new(4, 8, TapeMode::Wrap);
+        let code = "<+++.>>>>.<<<<.>>>>>>>>>>>>+[-<<+>>]<<.";
+        assert_eq!(run_on(code, &mut wrap).unwrap(), b"\x03\x03\x03\x04");
+        assert_eq!(wrap.cells, vec![0, 4, 0, 0]);
+    }
+}